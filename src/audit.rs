@@ -0,0 +1,313 @@
+use crate::error::Result;
+use crate::models::{AuditEntry, AuditPage, AuditQuery};
+use bb8::Pool;
+use bb8_redis::RedisConnectionManager;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::{Context, Layer};
+
+/// 审计日志所在的Redis stream
+pub const AUDIT_STREAM: &str = "audit";
+
+/// 安全事件使用的tracing target，普通日志中会过滤掉该target
+pub const AUDIT_TARGET: &str = "audit";
+
+/// 单次查询默认返回的条目数
+const DEFAULT_LIMIT: usize = 50;
+
+/// 单次查询允许返回的最大条目数
+const MAX_LIMIT: usize = 500;
+
+/// 每次从stream中读取的批大小
+const SCAN_BATCH: usize = 200;
+
+/// stream保留的大约条目数，超出后裁剪最早的条目
+const STREAM_MAX_LEN: usize = 100_000;
+
+/**
+ * 写入一条审计日志
+ */
+pub async fn record(
+    redis_pool: &Pool<RedisConnectionManager>,
+    action: &str,
+    actor: &str,
+    target: Option<&str>,
+    outcome: &str,
+    details: &serde_json::Value,
+) -> Result<()> {
+    let mut conn = redis_pool.get().await?;
+
+    let _: String = redis::cmd("XADD")
+        .arg(AUDIT_STREAM)
+        .arg("MAXLEN")
+        .arg("~")
+        .arg(STREAM_MAX_LEN)
+        .arg("*")
+        .arg("action")
+        .arg(action)
+        .arg("actor")
+        .arg(actor)
+        .arg("target")
+        .arg(target.unwrap_or(""))
+        .arg("outcome")
+        .arg(outcome)
+        .arg("details")
+        .arg(details.to_string())
+        .query_async(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+/**
+ * 记录一条安全或管理事件：输出JSON记录由AuditLayer写入独立的审计日志，
+ * 同时写入audit stream供GET /api/admin/audit查询
+ * outcome为success、failure或denied；details中的target字段作为条目的target，可按目标查询
+ */
+pub async fn log_event(
+    redis_pool: &Pool<RedisConnectionManager>,
+    action: &str,
+    actor: &str,
+    outcome: &str,
+    details: serde_json::Value,
+) {
+    let target = details.get("target").and_then(|target| target.as_str());
+    if let Err(e) = record(redis_pool, action, actor, target, outcome, &details).await {
+        tracing::warn!("Failed to write audit event {} to Redis: {}", action, e);
+    }
+
+    let record = serde_json::json!({
+        "timestamp": Utc::now(),
+        "action": action,
+        "actor": actor,
+        "outcome": outcome,
+        "details": details,
+    })
+    .to_string();
+
+    tracing::info!(target: AUDIT_TARGET, record = record.as_str());
+}
+
+/**
+ * 将审计target的事件按行写出JSON记录，其他事件忽略
+ */
+pub struct AuditLayer<W> {
+    writer: W,
+}
+
+impl<W> AuditLayer<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+impl<S, W> Layer<S> for AuditLayer<W>
+where
+    S: Subscriber,
+    W: for<'a> MakeWriter<'a> + 'static,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if event.metadata().target() != AUDIT_TARGET {
+            return;
+        }
+
+        let mut visitor = RecordVisitor(None);
+        event.record(&mut visitor);
+        if let Some(record) = visitor.0 {
+            let _ = writeln!(self.writer.make_writer(), "{}", record);
+        }
+    }
+}
+
+/**
+ * 从事件中取出record字段
+ */
+struct RecordVisitor(Option<String>);
+
+impl Visit for RecordVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "record" {
+            self.0 = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {}
+}
+
+/**
+ * 查询审计日志
+ * 按时间倒序扫描stream，应用过滤条件，并返回下一页的游标
+ */
+pub async fn query_audit_log(
+    redis_pool: &Pool<RedisConnectionManager>,
+    query: &AuditQuery,
+) -> Result<AuditPage> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    // 游标为上一页最后一条的stream id，使用排他区间避免重复返回
+    let mut max = match &query.cursor {
+        Some(cursor) => format!("({}", cursor),
+        None => "+".to_string(),
+    };
+    let min = match &query.since {
+        Some(since) => since.timestamp_millis().to_string(),
+        None => "-".to_string(),
+    };
+
+    let mut conn = redis_pool.get().await?;
+
+    let mut entries = Vec::new();
+    let mut next_cursor = None;
+
+    'scan: loop {
+        let batch: Vec<(String, HashMap<String, String>)> = redis::cmd("XREVRANGE")
+            .arg(AUDIT_STREAM)
+            .arg(&max)
+            .arg(&min)
+            .arg("COUNT")
+            .arg(SCAN_BATCH)
+            .query_async(&mut *conn)
+            .await?;
+
+        let exhausted = batch.len() < SCAN_BATCH;
+
+        for (id, fields) in batch {
+            max = format!("({}", id);
+
+            let entry = parse_entry(id, fields);
+            if !matches_query(&entry, query) {
+                continue;
+            }
+
+            entries.push(entry);
+            if entries.len() >= limit {
+                next_cursor = entries.last().map(|e| e.id.clone());
+                break 'scan;
+            }
+        }
+
+        if exhausted {
+            break;
+        }
+    }
+
+    Ok(AuditPage {
+        entries,
+        next_cursor,
+    })
+}
+
+/**
+ * 将stream条目解析为审计日志结构
+ */
+fn parse_entry(id: String, mut fields: HashMap<String, String>) -> AuditEntry {
+    // stream id的前半部分为毫秒时间戳
+    let timestamp = id
+        .split('-')
+        .next()
+        .and_then(|ms| ms.parse::<i64>().ok())
+        .and_then(DateTime::<Utc>::from_timestamp_millis);
+
+    let details = fields
+        .remove("details")
+        .and_then(|raw| serde_json::from_str(&raw).ok())
+        .unwrap_or(serde_json::Value::Null);
+
+    AuditEntry {
+        id,
+        action: fields.remove("action").unwrap_or_default(),
+        actor: fields.remove("actor").unwrap_or_default(),
+        target: fields.remove("target").filter(|t| !t.is_empty()),
+        outcome: fields.remove("outcome").filter(|o| !o.is_empty()),
+        timestamp,
+        details,
+    }
+}
+
+/**
+ * 判断审计日志条目是否满足查询条件
+ */
+fn matches_query(entry: &AuditEntry, query: &AuditQuery) -> bool {
+    if let Some(action) = &query.action {
+        if !entry.action.eq_ignore_ascii_case(action) {
+            return false;
+        }
+    }
+
+    if let Some(actor) = &query.actor {
+        if !entry.actor.eq_ignore_ascii_case(actor) {
+            return false;
+        }
+    }
+
+    if let Some(target) = &query.target {
+        match &entry.target {
+            Some(entry_target) if entry_target.eq_ignore_ascii_case(target) => {}
+            _ => return false,
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestHarness;
+
+    fn target(i: usize) -> String {
+        format!("0x{:040x}", i)
+    }
+
+    #[tokio::test]
+    async fn moderation_events_are_filtered_and_paginated() {
+        let harness = TestHarness::start().await;
+        let pool = &harness.redis_pool;
+        let moderator = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+
+        for i in 0..5 {
+            let details = serde_json::json!({ "room": "general", "target": target(i) });
+            log_event(pool, "kick", moderator, "success", details).await;
+        }
+        log_event(pool, "auth", moderator, "failure", serde_json::json!({ "method": "siwe" })).await;
+
+        // 按游标翻页，最新的条目在前，最后一页没有游标
+        let mut query = AuditQuery {
+            action: Some("kick".to_string()),
+            limit: Some(2),
+            ..Default::default()
+        };
+        let mut targets = Vec::new();
+        loop {
+            let page = query_audit_log(pool, &query).await.unwrap();
+            assert!(page.entries.len() <= 2);
+            assert!(page.entries.iter().all(|entry| entry.action == "kick"));
+            targets.extend(page.entries.into_iter().filter_map(|entry| entry.target));
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        assert_eq!(targets, (0..5).rev().map(target).collect::<Vec<_>>());
+
+        let by_target = query_audit_log(pool, &AuditQuery { target: Some(target(3)), ..Default::default() })
+            .await
+            .unwrap();
+        assert_eq!(by_target.entries.len(), 1);
+        assert_eq!(by_target.entries[0].outcome.as_deref(), Some("success"));
+
+        let failures = AuditQuery {
+            action: Some("auth".to_string()),
+            actor: Some(moderator.to_lowercase()),
+            ..Default::default()
+        };
+        let failures = query_audit_log(pool, &failures).await.unwrap();
+        assert_eq!(failures.entries.len(), 1);
+        assert_eq!(failures.entries[0].outcome.as_deref(), Some("failure"));
+    }
+}
@@ -1,74 +1,239 @@
+use crate::audit;
+use crate::config::Config;
 use crate::error::{AppError, Result};
-use crate::models::{Claims, UserAuth, UserInfo};
+use crate::models::{avatar_seed, Claims, TokenGate, TokenGateType, UserAuth, UserInfo, MAINNET_CHAIN_ID};
+use crate::retry::RetryPolicy;
+use crate::telemetry;
+use axum::http::uri::Authority;
 use bb8::Pool;
 use bb8_redis::RedisConnectionManager;
 use chrono::{Duration, Utc};
 use ethers::{
-    providers::{Http, Provider},
-    types::{Address, U256},
-    utils::to_checksum,
+    contract::ContractError,
+    providers::{Http, JsonRpcClient, Middleware, Provider, ProviderError},
+    types::{Address, Bytes, H256, U256},
+    utils::{hash_message, to_checksum},
 };
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use redis::AsyncCommands;
-
+use serde::{Deserialize, Serialize};
 use siwe::{Message, VerificationOpts};
 use std::collections::HashMap;
 use std::str::FromStr;
+use std::sync::Arc;
 use uuid::Uuid;
 
+// 生成Token门禁所需的合约ABI绑定
+mod erc20 {
+    ethers::contract::abigen!(
+        Erc20Token,
+        r#"[
+            function balanceOf(address account) external view returns (uint256)
+        ]"#
+    );
+}
+
+mod erc721 {
+    ethers::contract::abigen!(
+        Erc721Token,
+        r#"[
+            function balanceOf(address owner) external view returns (uint256)
+            function ownerOf(uint256 tokenId) external view returns (address)
+        ]"#
+    );
+}
+
+mod erc1155 {
+    ethers::contract::abigen!(
+        Erc1155Token,
+        r#"[
+            function balanceOf(address account, uint256 id) external view returns (uint256)
+        ]"#
+    );
+}
+
+// 智能合约钱包签名校验接口（EIP-1271）
+mod erc1271 {
+    ethers::contract::abigen!(
+        Erc1271Wallet,
+        r#"[
+            function isValidSignature(bytes32 hash, bytes signature) external view returns (bytes4)
+        ]"#
+    );
+}
+
+/// ipfs头像使用的公共网关
+const IPFS_GATEWAY: &str = "https://ipfs.io/ipfs/";
+
+/// EIP-1271 isValidSignature校验通过时返回的魔术值
+const EIP1271_MAGIC_VALUE: [u8; 4] = [0x16, 0x26, 0xba, 0x7e];
+
 /**
  * 认证服务
+ * 对RPC传输层泛型，生产环境使用HTTP，测试中可以注入MockProvider
  */
-pub struct AuthService {
+pub struct AuthService<P: JsonRpcClient = Http> {
     jwt_secret: String,
+    jwt_expiry: Duration,
+    refresh_token_ttl: Duration,
     redis_pool: Pool<RedisConnectionManager>,
-    eth_provider: Provider<Http>,
+    eth_provider: Arc<Provider<P>>, // 主网RPC，用于ENS解析
+    chain_providers: HashMap<u64, Arc<Provider<P>>>, // 各链RPC，按链id选择，同时是允许登录的链
+    siwe_domain: Authority, // SIWE消息必须针对的域名，防止其他站点的签名被重放
+    eip1271_enabled: bool,  // 是否接受智能合约钱包的EIP-1271签名
+    ens_cache_ttl_secs: u64,
+    nonce_ttl_secs: u64,
+    rpc_retry: RetryPolicy, // 链上读取遇到临时错误时的重试策略
 }
 
-impl AuthService {
+impl AuthService<Http> {
     /**
-     * 创建新的认证服务实例
+     * 创建新的认证服务实例，按配置中的URL连接各链RPC
      */
-    pub fn new(
-        jwt_secret: String,
+    pub fn new(config: &Config, redis_pool: Pool<RedisConnectionManager>) -> Result<Self> {
+        let eth_provider = Provider::<Http>::try_from(config.ethereum_http_url.as_str())
+            .map_err(|e| AppError::BlockchainError(e.to_string()))?;
+        
+        let mut chain_providers = HashMap::new();
+        for (chain_id, url) in &config.rpc_urls {
+            let provider = Provider::<Http>::try_from(url.as_str())
+                .map_err(|e| AppError::BlockchainError(format!("Invalid RPC URL for chain {}: {}", chain_id, e)))?;
+            chain_providers.insert(*chain_id, provider);
+        }
+        
+        Self::with_providers(config, redis_pool, eth_provider, chain_providers)
+    }
+}
+
+impl<P: JsonRpcClient + 'static> AuthService<P> {
+    /**
+     * 使用给定的RPC创建认证服务实例
+     * chain_providers为主网以外的链，未单独配置主网RPC时使用eth_provider
+     */
+    pub fn with_providers(
+        config: &Config,
         redis_pool: Pool<RedisConnectionManager>,
-        eth_rpc_url: &str,
+        eth_provider: Provider<P>,
+        chain_providers: HashMap<u64, Provider<P>>,
     ) -> Result<Self> {
-        let eth_provider = Provider::<Http>::try_from(eth_rpc_url)
-            .map_err(|e| AppError::BlockchainError(e.to_string()))?;
+        let eth_provider = Arc::new(eth_provider);
+        let mut chain_providers: HashMap<u64, Arc<Provider<P>>> = chain_providers
+            .into_iter()
+            .map(|(chain_id, provider)| (chain_id, Arc::new(provider)))
+            .collect();
+        chain_providers.entry(MAINNET_CHAIN_ID).or_insert_with(|| eth_provider.clone());
+        
+        let siwe_domain = Authority::from_str(&config.siwe_domain)
+            .map_err(|e| {
+                AppError::InternalError(format!("Invalid SIWE domain {}: {}", config.siwe_domain, e))
+            })?;
         
         Ok(Self {
-            jwt_secret,
+            jwt_secret: config.jwt_secret.clone(),
+            jwt_expiry: Duration::hours(config.jwt_expiry_hours),
+            refresh_token_ttl: Duration::days(config.refresh_token_ttl_days),
             redis_pool,
             eth_provider,
+            chain_providers,
+            siwe_domain,
+            eip1271_enabled: config.eip1271_enabled,
+            ens_cache_ttl_secs: config.ens_cache_ttl_secs,
+            nonce_ttl_secs: config.nonce_ttl_secs,
+            rpc_retry: RetryPolicy::from_config(config),
         })
     }
     
+    /**
+     * 是否配置了指定链的RPC
+     */
+    pub fn supports_chain(&self, chain_id: u64) -> bool {
+        self.chain_providers.contains_key(&chain_id)
+    }
+    
+    /**
+     * 获取指定链的RPC，未配置的链返回错误
+     */
+    fn provider(&self, chain_id: u64) -> Result<Arc<Provider<P>>> {
+        self.chain_providers
+            .get(&chain_id)
+            .cloned()
+            .ok_or_else(|| AppError::InvalidRequest(format!("Unsupported chain id: {}", chain_id)))
+    }
+    
+    /**
+     * 检查以太坊RPC连通性，返回最新区块号
+     */
+    pub async fn check_rpc(&self) -> Result<u64> {
+        let block_number = self.eth_provider.get_block_number().await?;
+        Ok(block_number.as_u64())
+    }
+    
     /**
      * 生成认证nonce
      */
     pub async fn generate_nonce(&self) -> Result<String> {
         let nonce = Uuid::new_v4().to_string();
-        let mut conn = self.redis_pool.get().await
-            .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+        let mut conn = self.redis_pool.get().await?;
         
-        // 存储nonce，5分钟过期
-        let _: () = conn.set_ex(&format!("nonce:{}", nonce), "1", 300).await?;
+        // 存储nonce，超过NONCE_TTL_SECS未使用则过期；值为nonce本身，验证时与消息中的nonce比对
+        let _: () = conn.set_ex(&format!("nonce:{}", nonce), &nonce, self.nonce_ttl_secs).await?;
+        
+        telemetry::record_nonce_generated();
         
         Ok(nonce)
     }
     
     /**
-     * 验证SIWE消息和签名
+     * 验证SIWE消息和签名，并记录认证结果指标
      */
     pub async fn verify_siwe_message(
         &self,
         message_str: &str,
         signature: &str,
     ) -> Result<UserAuth> {
+        let result = self.verify_siwe_message_inner(message_str, signature).await;
+        telemetry::record_auth("siwe", result.is_ok());
+        
+        match &result {
+            Ok(user_auth) => {
+                audit::log_event(
+                    &self.redis_pool,
+                    "auth",
+                    &user_auth.address,
+                    "success",
+                    serde_json::json!({ "method": "siwe" }),
+                ).await;
+            }
+            Err(e) => {
+                // 消息无法解析时没有可信的地址
+                let actor = message_str
+                    .parse::<Message>()
+                    .map(|message| to_checksum(&Address::from(message.address), None))
+                    .unwrap_or_else(|_| "unknown".to_string());
+                audit::log_event(
+                    &self.redis_pool,
+                    "auth",
+                    &actor,
+                    "failure",
+                    serde_json::json!({ "method": "siwe", "error": e.to_string() }),
+                ).await;
+            }
+        }
+        
+        result
+    }
+    
+    /**
+     * 验证SIWE消息和签名
+     */
+    async fn verify_siwe_message_inner(
+        &self,
+        message_str: &str,
+        signature: &str,
+    ) -> Result<UserAuth> {
+        // 消息和签名属于用户凭证，只记录长度
         tracing::info!("Starting SIWE verification");
-        tracing::info!("Message: {}", message_str);
-        tracing::info!("Signature: {}", signature);
+        tracing::debug!("SIWE message length: {} chars, signature length: {} chars", message_str.len(), signature.len());
         
         // 首先尝试解析原始消息
         let message = match message_str.parse::<Message>() {
@@ -81,8 +246,7 @@ impl AuthService {
                 
                 // 如果原始消息解析失败，尝试使用normalize后的消息
                 let processed_message = self.normalize_siwe_message(message_str)?;
-                tracing::info!("📝 Message normalized (addresses converted to checksum format)");
-                tracing::info!("Processed message: {}", processed_message);
+                tracing::debug!("📝 Message normalized (addresses converted to checksum format)");
                 
                 processed_message.parse::<Message>()
                     .map_err(|e| {
@@ -93,30 +257,49 @@ impl AuthService {
             }
         };
         
-        tracing::info!("Parsed message successfully. Nonce: {}, Address: {:?}", message.nonce, message.address);
+        tracing::info!("Parsed message successfully for address: {}", to_checksum(&Address::from(message.address), None));
+        
+        // 只接受配置了RPC的链
+        if !self.supports_chain(message.chain_id) {
+            tracing::error!("Unsupported chain id in SIWE message: {}", message.chain_id);
+            return Err(AppError::AuthenticationFailed(format!(
+                "Unsupported chain id: {}",
+                message.chain_id
+            )));
+        }
         
-        // 验证nonce是否存在且有效
-        let mut conn = self.redis_pool.get().await
-            .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+        // 原子地取出并删除服务端存储的nonce，同一nonce并发提交时只有一个请求能取到
+        let mut conn = self.redis_pool.get().await?;
         
         let nonce_key = format!("nonce:{}", message.nonce);
-        let nonce_exists: bool = conn.exists(&nonce_key).await?;
+        let (stored_nonce, _): (Option<String>, i64) = redis::pipe()
+            .atomic()
+            .get(&nonce_key)
+            .del(&nonce_key)
+            .query_async(&mut *conn)
+            .await?;
         
-        if !nonce_exists {
-            tracing::error!("Nonce not found or expired: {}", message.nonce);
-            return Err(AppError::InvalidNonce);
-        }
+        let stored_nonce = stored_nonce.ok_or_else(|| {
+            tracing::error!("Nonce not found or expired");
+            AppError::InvalidNonce
+        })?;
         
         tracing::info!("Nonce validation passed");
         
-        // 删除已使用的nonce
-        let _: () = conn.del(&nonce_key).await?;
+        audit::log_event(
+            &self.redis_pool,
+            "nonce_consumed",
+            &to_checksum(&Address::from(message.address), None),
+            "success",
+            serde_json::json!({ "nonce": message.nonce }),
+        ).await;
         
-        // 验证签名 - 使用默认选项让SIWE自动处理
+        // 校验域名、nonce以及expirationTime/notBefore，拒绝为其他站点签发或已过期的消息
+        let now = time::OffsetDateTime::now_utc();
         let verification_opts = VerificationOpts {
-            domain: None,
-            nonce: None,
-            timestamp: None,
+            domain: Some(self.siwe_domain.clone()),
+            nonce: Some(stored_nonce),
+            timestamp: Some(now),
         };
         
         // 将十六进制签名字符串转换为字节数组
@@ -126,40 +309,34 @@ impl AuthService {
                 AppError::InvalidSignature
             })?;
         
-        tracing::info!("🔢 Converting signature hex: {} (length: {})", signature.trim_start_matches("0x"), signature.len());
-        tracing::info!("✅ Signature decoded successfully, length: {} bytes", signature_bytes.len());
-        
-        // 打印签名的前几个字节用于调试
-        if signature_bytes.len() >= 8 {
-            let hex_bytes: Vec<String> = signature_bytes.iter().take(8).map(|b| format!("{:02x}", b)).collect();
-            tracing::info!("🔍 First 8 bytes of signature: [{}]", hex_bytes.join(", "));
-        }
-        
-        // 打印完整的消息用于调试
-        tracing::info!("🔍 Complete message for verification:");
-        for (i, line) in message_str.lines().enumerate() {
-            tracing::info!("   Line {}: '{}'", i + 1, line);
-        }
-        
+        tracing::debug!("✅ Signature decoded successfully, length: {} bytes", signature_bytes.len());
         tracing::info!("🔐 Starting SIWE signature verification...");
         
-        message.verify(&signature_bytes, &verification_opts)
-            .await
-            .map_err(|e| {
-                tracing::error!("❌ SIWE signature verification failed: {}", e);
-                tracing::error!("❌ Verification details:");
-                tracing::error!("   - Message address: {:?}", message.address);
-                tracing::error!("   - Message domain: {}", message.domain);
-                tracing::error!("   - Message nonce: {}", message.nonce);
-                tracing::error!("   - Signature bytes length: {}", signature_bytes.len());
-                tracing::error!("🔍 Additional debugging information:");
-                tracing::error!("   - Raw message length: {} chars", message_str.len());
-                tracing::error!("   - Message starts with: '{}'", &message_str[..std::cmp::min(50, message_str.len())]);
-                tracing::error!("   - Message ends with: '{}'", &message_str[std::cmp::max(0, message_str.len().saturating_sub(50))..]);
-                tracing::error!("   - Signature format: 0x{}", signature.trim_start_matches("0x"));
-                tracing::error!("   - This suggests the SIWE library cannot verify the signature with the given message format");
-                AppError::InvalidSignature
-            })?;
+        if let Err(e) = message.verify(&signature_bytes, &verification_opts).await {
+            tracing::warn!("❌ SIWE signature verification failed: {}", e);
+            tracing::debug!(
+                "Verification details: domain {}, message length {} chars, signature length {} bytes",
+                message.domain,
+                message_str.len(),
+                signature_bytes.len()
+            );
+            
+            // 智能合约钱包无法通过ECDSA恢复地址，域名和有效期校验通过后尝试EIP-1271
+            let contract_verified = message.domain == self.siwe_domain
+                && message.valid_at(&now)
+                && self.verify_contract_signature(
+                    message.chain_id,
+                    Address::from(message.address),
+                    hash_message(message_str),
+                    signature_bytes.clone(),
+                ).await?;
+            
+            if !contract_verified {
+                return Err(AppError::InvalidSignature);
+            }
+            
+            tracing::info!("EIP-1271 contract signature verification passed");
+        }
         
         tracing::info!("SIWE signature verification passed");
        // 转换地址类型
@@ -171,7 +348,10 @@ impl AuthService {
 
         
         // 获取用户的ENS名称
-        let ens_name = self.resolve_ens(&address).await.ok();
+        let ens_name = self.resolve_ens(&address).await.unwrap_or_else(|e| {
+            tracing::warn!("Failed to resolve ENS name for {}: {}", user_address, e);
+            None
+        });
         
         // 获取用户的token持有情况
         let token_holdings = self.get_token_holdings(&address).await?;
@@ -185,18 +365,46 @@ impl AuthService {
         })
     }
     
+    /**
+     * 通过EIP-1271校验智能合约钱包的签名
+     * 未启用、合约不支持该接口或返回值不是魔术值时返回false
+     */
+    pub async fn verify_contract_signature(
+        &self,
+        chain_id: u64,
+        address: Address,
+        hash: H256,
+        signature: Vec<u8>,
+    ) -> Result<bool> {
+        if !self.eip1271_enabled {
+            return Ok(false);
+        }
+        
+        let wallet = erc1271::Erc1271Wallet::new(address, self.provider(chain_id)?);
+        let call = wallet.is_valid_signature(hash.into(), Bytes::from(signature));
+        match self.rpc_retry.run("isValidSignature", || call.call()).await {
+            Ok(magic_value) => Ok(magic_value == EIP1271_MAGIC_VALUE),
+            Err(e) => {
+                // 普通账户或未实现EIP-1271的合约会调用失败
+                tracing::warn!("EIP-1271 check failed for {:?}: {}", address, e);
+                Ok(false)
+            }
+        }
+    }
+    
     /**
      * 生成JWT token
      */
     pub fn generate_jwt(&self, user_auth: &UserAuth) -> Result<String> {
         let now = Utc::now();
-        let exp = now + Duration::hours(24); // 24小时过期
+        let exp = now + self.jwt_expiry;
         
         let claims = Claims {
             sub: user_auth.address.clone(),
             exp: exp.timestamp() as usize,
             iat: now.timestamp() as usize,
             ens: user_auth.ens_name.clone(),
+            jti: Uuid::new_v4().to_string(),
         };
         
         let token = encode(
@@ -209,9 +417,151 @@ impl AuthService {
     }
     
     /**
-     * 验证JWT token
+     * 验证JWT token，已吊销的token视为无效
+     */
+    pub async fn verify_jwt(&self, token: &str) -> Result<Claims> {
+        let claims = self.decode_jwt(token)?;
+        
+        if !claims.jti.is_empty() {
+            let mut conn = self.redis_pool.get().await?;
+            let revoked: bool = conn.exists(revoked_jwt_key(&claims.jti)).await?;
+            if revoked {
+                return Err(AppError::AuthenticationFailed("Token has been revoked".to_string()));
+            }
+        }
+        
+        Ok(claims)
+    }
+    
+    /**
+     * 生成刷新token
+     * 不透明的随机字符串，在Redis中映射到用户地址，有效期比访问token长
+     */
+    pub async fn generate_refresh_token(&self, user_auth: &UserAuth) -> Result<String> {
+        let refresh_token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        let session = RefreshSession {
+            address: user_auth.address.clone(),
+            ens_name: user_auth.ens_name.clone(),
+        };
+        
+        let mut conn = self.redis_pool.get().await?;
+        let _: () = redis::cmd("SETEX")
+            .arg(refresh_token_key(&refresh_token))
+            .arg(self.refresh_token_ttl.num_seconds())
+            .arg(serde_json::to_string(&session)?)
+            .query_async(&mut *conn)
+            .await?;
+        
+        Ok(refresh_token)
+    }
+    
+    /**
+     * 使用刷新token换取新的访问token
+     * 刷新token使用后立即作废并签发新的刷新token，重复使用旧token会被拒绝
+     * 返回(用户信息, 访问token, 新的刷新token)
+     */
+    pub async fn refresh_session(&self, refresh_token: &str) -> Result<(UserAuth, String, String)> {
+        let raw: Option<String> = {
+            let mut conn = self.redis_pool.get().await?;
+            // 在同一个事务中读取并删除，保证同一个刷新token只能被使用一次（GETDEL需要Redis 6.2）
+            let key = refresh_token_key(refresh_token);
+            let (raw, _): (Option<String>, i64) = redis::pipe()
+                .atomic()
+                .get(&key)
+                .del(&key)
+                .query_async(&mut *conn)
+                .await?;
+            raw
+        };
+        
+        let session: RefreshSession = match raw {
+            Some(raw) => serde_json::from_str(&raw)?,
+            None => {
+                return Err(AppError::AuthenticationFailed(
+                    "Invalid or expired refresh token".to_string(),
+                ))
+            }
+        };
+        
+        let user_auth = UserAuth {
+            address: session.address,
+            ens_name: session.ens_name,
+            token_holdings: HashMap::new(),
+            nft_holdings: Vec::new(),
+        };
+        
+        let (access_token, refresh_token) = self.issue_tokens(&user_auth).await?;
+        
+        Ok((user_auth, access_token, refresh_token))
+    }
+    
+    /**
+     * 签发一对访问token和刷新token
+     * 记录访问token（jti）与刷新token的关联，吊销访问token时一并作废刷新token
      */
-    pub fn verify_jwt(&self, token: &str) -> Result<Claims> {
+    pub async fn issue_tokens(&self, user_auth: &UserAuth) -> Result<(String, String)> {
+        let access_token = self.generate_jwt(user_auth)?;
+        let refresh_token = self.generate_refresh_token(user_auth).await?;
+        let claims = self.decode_jwt(&access_token)?;
+        
+        // 关联只需保留到访问token过期，之后访问token已无法用于登出
+        let mut conn = self.redis_pool.get().await?;
+        let _: () = redis::cmd("SETEX")
+            .arg(session_refresh_key(&claims.jti))
+            .arg(self.jwt_expiry.num_seconds().max(1))
+            .arg(&refresh_token)
+            .query_async(&mut *conn)
+            .await?;
+        
+        Ok((access_token, refresh_token))
+    }
+    
+    /**
+     * 吊销JWT token，黑名单记录在token过期后自动清除
+     */
+    pub async fn revoke_jwt(&self, token: &str) -> Result<()> {
+        let claims = self.decode_jwt(token)?;
+        self.revoke_claims(&claims).await
+    }
+    
+    /**
+     * 按已校验的声明吊销JWT，用于只保留了声明的连接
+     * 与该JWT一同签发的刷新token同时作废，登出后不能再换取新的访问token
+     */
+    pub async fn revoke_claims(&self, claims: &Claims) -> Result<()> {
+        if claims.jti.is_empty() {
+            return Err(AppError::InvalidRequest("Token cannot be revoked".to_string()));
+        }
+        
+        let remaining_secs = (claims.exp as i64 - Utc::now().timestamp()).max(1);
+        
+        let mut conn = self.redis_pool.get().await?;
+        let _: () = redis::cmd("SETEX")
+            .arg(revoked_jwt_key(&claims.jti))
+            .arg(remaining_secs)
+            .arg("1")
+            .query_async(&mut *conn)
+            .await?;
+        
+        let link = session_refresh_key(&claims.jti);
+        let (refresh_token, _): (Option<String>, i64) = redis::pipe()
+            .atomic()
+            .get(&link)
+            .del(&link)
+            .query_async(&mut *conn)
+            .await?;
+        if let Some(refresh_token) = refresh_token {
+            let _: i64 = conn.del(refresh_token_key(&refresh_token)).await?;
+        }
+        
+        tracing::info!("Revoked token {} for {}", claims.jti, claims.sub);
+        Ok(())
+    }
+    
+    /**
+     * 解码并校验JWT签名与过期时间
+     */
+    fn decode_jwt(&self, token: &str) -> Result<Claims> {
         let token_data = decode::<Claims>(
             token,
             &DecodingKey::from_secret(self.jwt_secret.as_ref()),
@@ -223,35 +573,157 @@ impl AuthService {
     
     /**
      * 检查用户是否满足token门禁要求
+     * 根据门禁类型（ERC20/ERC721/ERC1155）调用门禁所在链上对应的合约方法
      */
     pub async fn check_token_gate(
         &self,
         user_address: &Address,
-        contract_address: &str,
-        minimum_balance: Option<&str>,
+        gate: &TokenGate,
     ) -> Result<bool> {
-        let contract_addr = Address::from_str(contract_address)
+        let contract_addr = Address::from_str(&gate.contract_address)
             .map_err(|e| AppError::InvalidRequest(e.to_string()))?;
         
-        // 这里简化实现，实际应该根据合约类型（ERC20/ERC721/ERC1155）调用不同的方法
-        let balance = self.get_erc20_balance(user_address, &contract_addr).await?;
+        let minimum_balance = parse_minimum_balance(gate.minimum_balance.as_deref())?;
+        let token_ids = parse_token_ids(gate.token_ids.as_deref())?;
+        let provider = self.provider(gate.chain_id)?;
+        
+        match gate.gate_type {
+            TokenGateType::ERC20 => {
+                let balance = self.get_erc20_balance(&provider, user_address, &contract_addr).await?;
+                Ok(meets_minimum(balance, minimum_balance))
+            }
+            TokenGateType::ERC721 => {
+                if token_ids.is_empty() {
+                    // 持有该集合中任意NFT即可
+                    let balance = self.get_erc721_balance(&provider, user_address, &contract_addr).await?;
+                    return Ok(meets_minimum(balance, minimum_balance));
+                }
+                
+                // 必须持有指定的token id之一
+                for token_id in token_ids {
+                    let owner = self.get_erc721_owner(&provider, &contract_addr, token_id).await?;
+                    if owner == Some(*user_address) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            TokenGateType::ERC1155 => {
+                if token_ids.is_empty() {
+                    return Err(AppError::InvalidRequest(
+                        "ERC1155 token gate requires token_ids".to_string(),
+                    ));
+                }
+                
+                for token_id in token_ids {
+                    let balance = self.get_erc1155_balance(&provider, user_address, &contract_addr, token_id).await?;
+                    if meets_minimum(balance, minimum_balance) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+    
+    /**
+     * 解析地址的ENS主名称
+     * 结果（包括没有名称的情况）缓存在Redis中，过期前不再请求RPC
+     */
+    pub async fn resolve_ens(&self, address: &Address) -> Result<Option<String>> {
+        let cache_key = ens_cache_key(address);
+        
+        {
+            let mut conn = self.redis_pool.get().await?;
+            let cached: Option<String> = conn.get(&cache_key).await?;
+            if let Some(name) = cached {
+                // 空字符串表示该地址没有ENS名称
+                return Ok(Some(name).filter(|name| !name.is_empty()));
+            }
+        }
+        
+        let name = match self.rpc_retry.run("lookupAddress", || self.eth_provider.lookup_address(*address)).await {
+            Ok(name) => Some(name),
+            // 没有反向解析记录或记录与地址不匹配
+            Err(ProviderError::EnsError(_)) | Err(ProviderError::EnsNotOwned(_)) => None,
+            Err(e) => return Err(e.into()),
+        };
+        
+        let mut conn = self.redis_pool.get().await?;
+        let _: () = redis::cmd("SETEX")
+            .arg(&cache_key)
+            .arg(self.ens_cache_ttl_secs)
+            .arg(name.as_deref().unwrap_or(""))
+            .query_async(&mut *conn)
+            .await?;
         
-        if let Some(min_balance) = minimum_balance {
-            let min_balance_u256 = U256::from_dec_str(min_balance)
-                .map_err(|e| AppError::InvalidRequest(e.to_string()))?;
-            Ok(balance >= min_balance_u256)
-        } else {
-            Ok(balance > U256::zero())
+        Ok(name)
+    }
+    
+    /**
+     * 解析地址ENS主名称的avatar文本记录，返回可直接显示的图片地址
+     * 支持https、ipfs以及eip155 NFT头像，结果与ENS名称一样缓存在Redis中
+     */
+    pub async fn resolve_avatar(&self, address: &Address) -> Result<Option<String>> {
+        let ens_name = match self.resolve_ens(address).await? {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        
+        let cache_key = ens_avatar_cache_key(address);
+        
+        {
+            let mut conn = self.redis_pool.get().await?;
+            let cached: Option<String> = conn.get(&cache_key).await?;
+            if let Some(avatar) = cached {
+                // 空字符串表示没有头像
+                return Ok(Some(avatar).filter(|avatar| !avatar.is_empty()));
+            }
         }
+        
+        let avatar = match self.rpc_retry.run("resolveField", || self.eth_provider.resolve_field(&ens_name, "avatar")).await {
+            Ok(record) => self.avatar_record_to_url(&ens_name, record.trim()).await,
+            // 没有解析器或记录
+            Err(ProviderError::EnsError(_)) | Err(ProviderError::EnsNotOwned(_)) => None,
+            Err(e) => return Err(e.into()),
+        };
+        
+        let mut conn = self.redis_pool.get().await?;
+        let _: () = redis::cmd("SETEX")
+            .arg(&cache_key)
+            .arg(self.ens_cache_ttl_secs)
+            .arg(avatar.as_deref().unwrap_or(""))
+            .query_async(&mut *conn)
+            .await?;
+        
+        Ok(avatar)
     }
     
     /**
-     * 解析ENS名称
+     * 将avatar记录转换为图片地址
+     * eip155 NFT头像需要读取token元数据中的图片，交给ethers处理，得到的图片地址同样只接受https和ipfs
      */
-    async fn resolve_ens(&self, _address: &Address) -> Result<String> {
-        // 这里应该调用ENS合约来解析地址对应的ENS名称
-        // 简化实现，返回None
-        Err(AppError::BlockchainError("ENS resolution not implemented".to_string()))
+    async fn avatar_record_to_url(&self, ens_name: &str, record: &str) -> Option<String> {
+        if record.starts_with("eip155:") {
+            return match self.rpc_retry.run("resolveAvatar", || self.eth_provider.resolve_avatar(ens_name)).await {
+                Ok(url) => avatar_uri_to_url(url.as_str()),
+                Err(e) => {
+                    tracing::warn!("Failed to resolve NFT avatar for {}: {}", ens_name, e);
+                    None
+                }
+            };
+        }
+        
+        avatar_uri_to_url(record)
+    }
+    
+    /**
+     * 清除地址的ENS缓存（包括头像），用户更换主名称后调用
+     */
+    pub async fn invalidate_ens(&self, address: &Address) -> Result<()> {
+        let mut conn = self.redis_pool.get().await?;
+        let _: () = conn.del(vec![ens_cache_key(address), ens_avatar_cache_key(address)]).await?;
+        Ok(())
     }
     
     /**
@@ -275,10 +747,65 @@ impl AuthService {
     /**
      * 获取ERC20 token余额
      */
-    async fn get_erc20_balance(&self, _user_address: &Address, _token_address: &Address) -> Result<U256> {
-        // 这里应该调用ERC20合约的balanceOf方法
-        // 简化实现，返回0
-        Ok(U256::zero())
+    async fn get_erc20_balance(
+        &self,
+        provider: &Arc<Provider<P>>,
+        user_address: &Address,
+        token_address: &Address,
+    ) -> Result<U256> {
+        let contract = erc20::Erc20Token::new(*token_address, provider.clone());
+        let call = contract.balance_of(*user_address);
+        self.rpc_retry.run("balanceOf", || call.call()).await
+            .map_err(|e| AppError::BlockchainError(e.to_string()))
+    }
+    
+    /**
+     * 获取ERC721 NFT持有数量
+     */
+    async fn get_erc721_balance(
+        &self,
+        provider: &Arc<Provider<P>>,
+        user_address: &Address,
+        token_address: &Address,
+    ) -> Result<U256> {
+        let contract = erc721::Erc721Token::new(*token_address, provider.clone());
+        let call = contract.balance_of(*user_address);
+        self.rpc_retry.run("balanceOf", || call.call()).await
+            .map_err(|e| AppError::BlockchainError(e.to_string()))
+    }
+    
+    /**
+     * 获取ERC721 NFT的持有者，token不存在或已销毁（ownerOf回滚）时返回None
+     */
+    async fn get_erc721_owner(
+        &self,
+        provider: &Arc<Provider<P>>,
+        token_address: &Address,
+        token_id: U256,
+    ) -> Result<Option<Address>> {
+        let contract = erc721::Erc721Token::new(*token_address, provider.clone());
+        let call = contract.owner_of(token_id);
+        match self.rpc_retry.run("ownerOf", || call.call()).await {
+            Ok(owner) => Ok(Some(owner)),
+            Err(ContractError::Revert(_)) => Ok(None),
+            Err(e) => Err(AppError::BlockchainError(e.to_string())),
+        }
+    }
+    
+    /**
+     * 获取ERC1155指定token id的余额
+     */
+    async fn get_erc1155_balance(
+        &self,
+        provider: &Arc<Provider<P>>,
+        user_address: &Address,
+        token_address: &Address,
+        token_id: U256,
+    ) -> Result<U256> {
+        let contract = erc1155::Erc1155Token::new(*token_address, provider.clone());
+        let call = contract.balance_of(*user_address, token_id);
+        self.rpc_retry.run("balanceOf", || call.call()).await
+            .map_err(|e| AppError::BlockchainError(e.to_string()))
     }
 
     /**
@@ -305,6 +832,112 @@ impl AuthService {
     }
 }
 
+/**
+ * 已吊销token在Redis中的键
+ */
+fn revoked_jwt_key(jti: &str) -> String {
+    format!("jwt:revoked:{}", jti)
+}
+
+/**
+ * 解析门禁的最低余额要求
+ */
+fn parse_minimum_balance(minimum_balance: Option<&str>) -> Result<Option<U256>> {
+    minimum_balance
+        .map(|min| U256::from_dec_str(min).map_err(|e| AppError::InvalidRequest(e.to_string())))
+        .transpose()
+}
+
+/**
+ * 解析门禁指定的token id列表
+ */
+fn parse_token_ids(token_ids: Option<&[String]>) -> Result<Vec<U256>> {
+    token_ids
+        .unwrap_or_default()
+        .iter()
+        .map(|id| U256::from_dec_str(id).map_err(|e| AppError::InvalidRequest(e.to_string())))
+        .collect()
+}
+
+/**
+ * 校验门禁配置的合约地址、最低余额和token id格式
+ */
+pub fn validate_token_gate(gate: &TokenGate) -> Result<()> {
+    Address::from_str(&gate.contract_address)
+        .map_err(|e| AppError::InvalidRequest(format!("Invalid contract_address: {}", e)))?;
+    parse_minimum_balance(gate.minimum_balance.as_deref())?;
+    parse_token_ids(gate.token_ids.as_deref())?;
+    Ok(())
+}
+
+/**
+ * 判断余额是否满足最低要求，未指定时要求大于0
+ */
+fn meets_minimum(balance: U256, minimum_balance: Option<U256>) -> bool {
+    match minimum_balance {
+        Some(min) => balance >= min,
+        None => balance > U256::zero(),
+    }
+}
+
+/**
+ * 刷新token在Redis中保存的会话信息
+ */
+#[derive(Debug, Serialize, Deserialize)]
+struct RefreshSession {
+    address: String,
+    ens_name: Option<String>,
+}
+
+/**
+ * 地址的ENS缓存键
+ */
+fn ens_cache_key(address: &Address) -> String {
+    format!("ens:{:?}", address)
+}
+
+/**
+ * ENS头像缓存在Redis中的键
+ */
+fn ens_avatar_cache_key(address: &Address) -> String {
+    format!("ens:avatar:{:?}", address)
+}
+
+/**
+ * 将https或ipfs形式的avatar记录转换为图片地址，不支持的格式返回None
+ * 不接受data:和http地址，避免客户端直接渲染不可信内容
+ */
+fn avatar_uri_to_url(record: &str) -> Option<String> {
+    if let Some(path) = record.strip_prefix("ipfs://") {
+        // 兼容 ipfs://ipfs/<cid> 的写法
+        let path = path.trim_start_matches("ipfs/");
+        if path.is_empty() {
+            return None;
+        }
+        return Some(format!("{}{}", IPFS_GATEWAY, path));
+    }
+    
+    if record.starts_with("https://") {
+        return Some(record.to_string());
+    }
+    
+    None
+}
+
+/**
+ * 刷新token在Redis中的键
+ */
+fn refresh_token_key(refresh_token: &str) -> String {
+    format!("refresh:{}", refresh_token)
+}
+
+/**
+ * 访问token（jti）关联的刷新token在Redis中的键
+ */
+fn session_refresh_key(jti: &str) -> String {
+    format!("session_refresh:{}", jti)
+}
+
 /**
  * 从JWT token中提取用户信息
  */
@@ -316,6 +949,7 @@ pub fn extract_user_from_token(token: &str, jwt_secret: &str) -> Result<UserInfo
     )?;
     
     Ok(UserInfo {
+        avatar_seed: avatar_seed(&token_data.claims.sub),
         address: token_data.claims.sub,
         ens_name: token_data.claims.ens,
         avatar: None, // 可以从ENS或其他来源获取头像
@@ -324,20 +958,467 @@ pub fn extract_user_from_token(token: &str, jwt_secret: &str) -> Result<UserInfo
 
 /**
  * 创建SIWE消息模板
+ * 域名、URI协议、声明和资源列表来自配置，域名与verify_siwe_message校验的域名一致
  */
 pub fn create_siwe_message(
+    config: &Config,
     address: &str,
-    domain: &str,
     nonce: &str,
     chain_id: u64,
 ) -> String {
-    format!(
-        "{} wants you to sign in with your Ethereum account:\n{}\n\nChainTalk Authentication\n\nURI: https://{}\nVersion: 1\nChain ID: {}\nNonce: {}\nIssued At: {}",
-        domain,
+    // EIP-4361中声明是可选的，为空时省略声明行
+    let statement = if config.siwe_statement.is_empty() {
+        String::new()
+    } else {
+        format!("{}\n", config.siwe_statement)
+    };
+    
+    let mut message = format!(
+        "{} wants you to sign in with your Ethereum account:\n{}\n\n{}\nURI: {}://{}\nVersion: 1\nChain ID: {}\nNonce: {}\nIssued At: {}",
+        config.siwe_domain,
         address,
-        domain,
+        statement,
+        config.siwe_scheme,
+        config.siwe_domain,
         chain_id,
         nonce,
         Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ")
-    )
-}
\ No newline at end of file
+    );
+    
+    if !config.siwe_resources.is_empty() {
+        message.push_str("\nResources:");
+        for resource in &config.siwe_resources {
+            message.push_str("\n- ");
+            message.push_str(resource);
+        }
+    }
+    
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{capture_audit_log, TestHarness, TEST_PRIVATE_KEY};
+    use ethers::providers::{JsonRpcError, MockResponse};
+    use ethers::signers::{LocalWallet, Signer};
+    
+    #[tokio::test]
+    async fn siwe_login_round_trip_consumes_nonce() {
+        let harness = TestHarness::start().await;
+        let auth = harness.auth_service();
+        
+        let wallet: LocalWallet = TEST_PRIVATE_KEY.parse().unwrap();
+        let address = to_checksum(&wallet.address(), None);
+        
+        let nonce = auth.generate_nonce().await.unwrap();
+        let message = create_siwe_message(&harness.config, &address, &nonce, MAINNET_CHAIN_ID);
+        let signature = wallet.sign_message(&message).await.unwrap();
+        let signature = format!("0x{}", hex::encode(signature.to_vec()));
+        
+        let user = auth.verify_siwe_message(&message, &signature).await.unwrap();
+        assert_eq!(user.address, address);
+        assert_eq!(user.ens_name, None);
+        
+        // nonce只能使用一次，重放同一消息会被拒绝
+        let replay = auth.verify_siwe_message(&message, &signature).await;
+        assert!(matches!(replay, Err(AppError::InvalidNonce)));
+    }
+    
+    #[tokio::test]
+    async fn siwe_message_for_another_domain_is_rejected() {
+        let harness = TestHarness::start().await;
+        let auth = harness.auth_service();
+        
+        let wallet: LocalWallet = TEST_PRIVATE_KEY.parse().unwrap();
+        let address = to_checksum(&wallet.address(), None);
+        
+        // 其他站点签发的消息，签名本身有效
+        let mut other_site = harness.config.clone();
+        other_site.siwe_domain = "evil.example".to_string();
+        let nonce = auth.generate_nonce().await.unwrap();
+        let message = create_siwe_message(&other_site, &address, &nonce, MAINNET_CHAIN_ID);
+        let signature = wallet.sign_message(&message).await.unwrap();
+        let signature = format!("0x{}", hex::encode(signature.to_vec()));
+        
+        let result = auth.verify_siwe_message(&message, &signature).await;
+        assert!(matches!(result, Err(AppError::InvalidSignature)));
+    }
+    
+    #[tokio::test]
+    async fn expired_siwe_message_is_rejected() {
+        let harness = TestHarness::start().await;
+        let auth = harness.auth_service();
+        
+        let wallet: LocalWallet = TEST_PRIVATE_KEY.parse().unwrap();
+        let address = to_checksum(&wallet.address(), None);
+        
+        let nonce = auth.generate_nonce().await.unwrap();
+        let expired_at = Utc::now() - Duration::minutes(5);
+        let message = format!(
+            "{}\nExpiration Time: {}",
+            create_siwe_message(&harness.config, &address, &nonce, MAINNET_CHAIN_ID),
+            expired_at.format("%Y-%m-%dT%H:%M:%S%.3fZ")
+        );
+        let signature = wallet.sign_message(&message).await.unwrap();
+        let signature = format!("0x{}", hex::encode(signature.to_vec()));
+        
+        let result = auth.verify_siwe_message(&message, &signature).await;
+        assert!(matches!(result, Err(AppError::InvalidSignature)));
+    }
+    
+    #[tokio::test]
+    async fn erc20_token_gate_uses_injected_provider() {
+        let harness = TestHarness::start().await;
+        let (auth, mock) = harness.mocked_auth_service();
+        
+        let user: Address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1".parse().unwrap();
+        let gate = TokenGate {
+            gate_type: TokenGateType::ERC20,
+            contract_address: "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48".to_string(),
+            minimum_balance: Some("100".to_string()),
+            token_ids: None,
+            chain_id: MAINNET_CHAIN_ID,
+        };
+        
+        // eth_call返回ABI编码的balanceOf结果
+        let balance = |amount: u64| Bytes::from(ethers::abi::encode(&[ethers::abi::Token::Uint(U256::from(amount))]));
+        
+        mock.push::<Bytes, _>(balance(150)).unwrap();
+        assert!(auth.check_token_gate(&user, &gate).await.unwrap());
+        
+        mock.push::<Bytes, _>(balance(99)).unwrap();
+        assert!(!auth.check_token_gate(&user, &gate).await.unwrap());
+    }
+    
+    /**
+     * 构造指定类型的测试门禁
+     */
+    fn nft_gate(gate_type: TokenGateType, token_ids: Option<&[&str]>) -> TokenGate {
+        TokenGate {
+            gate_type,
+            contract_address: "0xBC4CA0EdA7647A8aB7C2061c2E118A18a936f13D".to_string(),
+            minimum_balance: None,
+            token_ids: token_ids.map(|ids| ids.iter().map(|id| id.to_string()).collect()),
+            chain_id: MAINNET_CHAIN_ID,
+        }
+    }
+    
+    /**
+     * ABI编码的uint256返回值
+     */
+    fn encoded_uint(amount: u64) -> Bytes {
+        Bytes::from(ethers::abi::encode(&[ethers::abi::Token::Uint(U256::from(amount))]))
+    }
+    
+    /**
+     * ABI编码的address返回值
+     */
+    fn encoded_address(address: Address) -> Bytes {
+        Bytes::from(ethers::abi::encode(&[ethers::abi::Token::Address(address)]))
+    }
+    
+    /**
+     * 合约调用回滚时节点返回的错误
+     */
+    fn reverted() -> MockResponse {
+        MockResponse::Error(JsonRpcError {
+            code: 3,
+            message: "execution reverted".to_string(),
+            data: Some(serde_json::json!("0x")),
+        })
+    }
+    
+    #[tokio::test]
+    async fn erc721_gate_accepts_any_token_from_collection() {
+        let harness = TestHarness::start().await;
+        let (auth, mock) = harness.mocked_auth_service();
+        let user: Address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1".parse().unwrap();
+        let gate = nft_gate(TokenGateType::ERC721, None);
+        
+        mock.push::<Bytes, _>(encoded_uint(1)).unwrap();
+        assert!(auth.check_token_gate(&user, &gate).await.unwrap());
+        
+        mock.push::<Bytes, _>(encoded_uint(0)).unwrap();
+        assert!(!auth.check_token_gate(&user, &gate).await.unwrap());
+    }
+    
+    #[tokio::test]
+    async fn erc721_gate_checks_specific_token_ids() {
+        let harness = TestHarness::start().await;
+        let (auth, mock) = harness.mocked_auth_service();
+        let user: Address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1".parse().unwrap();
+        let other: Address = "0xFFcf8FDEE72ac11b5c542428B35EEF5769C409f0".parse().unwrap();
+        let gate = nft_gate(TokenGateType::ERC721, Some(&["1", "2"]));
+        
+        // 按后进先出的顺序：token 1已销毁（ownerOf回滚），token 2属于用户
+        mock.push::<Bytes, _>(encoded_address(user)).unwrap();
+        mock.push_response(reverted());
+        assert!(auth.check_token_gate(&user, &gate).await.unwrap());
+        
+        // token 1属于其他地址，token 2已销毁
+        mock.push_response(reverted());
+        mock.push::<Bytes, _>(encoded_address(other)).unwrap();
+        assert!(!auth.check_token_gate(&user, &gate).await.unwrap());
+    }
+    
+    #[tokio::test]
+    async fn erc1155_gate_checks_balance_of_listed_ids() {
+        let harness = TestHarness::start().await;
+        let (auth, mock) = harness.mocked_auth_service();
+        let user: Address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1".parse().unwrap();
+        let gate = nft_gate(TokenGateType::ERC1155, Some(&["7", "8"]));
+        
+        // token 7余额为0，token 8余额为2
+        mock.push::<Bytes, _>(encoded_uint(2)).unwrap();
+        mock.push::<Bytes, _>(encoded_uint(0)).unwrap();
+        assert!(auth.check_token_gate(&user, &gate).await.unwrap());
+        
+        mock.push::<Bytes, _>(encoded_uint(0)).unwrap();
+        mock.push::<Bytes, _>(encoded_uint(0)).unwrap();
+        assert!(!auth.check_token_gate(&user, &gate).await.unwrap());
+        
+        // ERC1155没有“任意token id”的余额查询，必须指定token_ids
+        let any_id = nft_gate(TokenGateType::ERC1155, None);
+        assert!(matches!(
+            auth.check_token_gate(&user, &any_id).await,
+            Err(AppError::InvalidRequest(_))
+        ));
+    }
+    
+    #[tokio::test]
+    async fn refresh_token_rotates_and_rejects_reuse() {
+        let harness = TestHarness::start().await;
+        let auth = harness.auth_service();
+        let user = UserAuth {
+            address: "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1".to_string(),
+            ens_name: Some("alice.eth".to_string()),
+            token_holdings: HashMap::new(),
+            nft_holdings: Vec::new(),
+        };
+        
+        let first = auth.generate_refresh_token(&user).await.unwrap();
+        let (session, access_token, second) = auth.refresh_session(&first).await.unwrap();
+        assert_eq!(session.address, user.address);
+        assert_eq!(session.ens_name, user.ens_name);
+        assert_ne!(second, first);
+        assert_eq!(auth.verify_jwt(&access_token).await.unwrap().sub, user.address);
+        
+        // 已轮换的刷新token不能再次使用，新的刷新token仍然有效
+        assert!(matches!(
+            auth.refresh_session(&first).await,
+            Err(AppError::AuthenticationFailed(_))
+        ));
+        assert!(auth.refresh_session(&second).await.is_ok());
+    }
+    
+    #[tokio::test]
+    async fn revoked_token_fails_verification_and_ends_refresh() {
+        let harness = TestHarness::start().await;
+        let auth = harness.auth_service();
+        let user = UserAuth {
+            address: "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1".to_string(),
+            ens_name: None,
+            token_holdings: HashMap::new(),
+            nft_holdings: Vec::new(),
+        };
+        
+        let (revoked, refresh_token) = auth.issue_tokens(&user).await.unwrap();
+        let (unrelated, unrelated_refresh) = auth.issue_tokens(&user).await.unwrap();
+        auth.revoke_jwt(&revoked).await.unwrap();
+        
+        assert!(matches!(
+            auth.verify_jwt(&revoked).await,
+            Err(AppError::AuthenticationFailed(_))
+        ));
+        assert_eq!(auth.verify_jwt(&unrelated).await.unwrap().sub, user.address);
+        
+        // 登出后不能再用同一会话的刷新token换取新的访问token
+        assert!(matches!(
+            auth.refresh_session(&refresh_token).await,
+            Err(AppError::AuthenticationFailed(_))
+        ));
+        assert!(auth.refresh_session(&unrelated_refresh).await.is_ok());
+    }
+    
+    #[tokio::test]
+    async fn failed_siwe_login_is_audited() {
+        let harness = TestHarness::start().await;
+        let auth = harness.auth_service();
+        let (audit_log, _guard) = capture_audit_log();
+        
+        let wallet: LocalWallet = TEST_PRIVATE_KEY.parse().unwrap();
+        let address = to_checksum(&wallet.address(), None);
+        
+        let nonce = auth.generate_nonce().await.unwrap();
+        let message = create_siwe_message(&harness.config, &address, &nonce, MAINNET_CHAIN_ID);
+        // 签名的内容与提交的消息不一致
+        let signature = wallet.sign_message("something else").await.unwrap();
+        let signature = format!("0x{}", hex::encode(signature.to_vec()));
+        
+        assert!(auth.verify_siwe_message(&message, &signature).await.is_err());
+        
+        let records = audit_log.records();
+        let record = records.iter().find(|record| record["action"] == "auth").expect("no auth audit record");
+        assert_eq!(record["actor"], address.as_str());
+        assert_eq!(record["outcome"], "failure");
+        assert_eq!(record["details"]["method"], "siwe");
+        assert!(record["details"]["error"].is_string());
+        assert!(record["timestamp"].is_string());
+    }
+    
+    #[test]
+    fn avatar_records_map_to_https_urls() {
+        assert_eq!(
+            avatar_uri_to_url("https://example.com/alice.png").as_deref(),
+            Some("https://example.com/alice.png")
+        );
+        assert_eq!(
+            avatar_uri_to_url("ipfs://QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG").unwrap(),
+            format!("{}QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG", IPFS_GATEWAY)
+        );
+        // 兼容 ipfs://ipfs/<cid> 的写法
+        assert_eq!(
+            avatar_uri_to_url("ipfs://ipfs/QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG").unwrap(),
+            format!("{}QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG", IPFS_GATEWAY)
+        );
+        
+        assert!(avatar_uri_to_url("ipfs://").is_none());
+        assert!(avatar_uri_to_url("http://example.com/alice.png").is_none());
+        assert!(avatar_uri_to_url("data:image/svg+xml;base64,PHN2Zz48L3N2Zz4=").is_none());
+        assert!(avatar_uri_to_url("javascript:alert(1)").is_none());
+    }
+    
+    /**
+     * ABI编码的bytes4返回值
+     */
+    fn encoded_bytes4(value: [u8; 4]) -> Bytes {
+        Bytes::from(ethers::abi::encode(&[ethers::abi::Token::FixedBytes(value.to_vec())]))
+    }
+    
+    #[tokio::test]
+    async fn contract_signature_requires_eip1271_magic_value() {
+        let mut harness = TestHarness::start().await;
+        harness.config.eip1271_enabled = true;
+        let (auth, mock) = harness.mocked_auth_service();
+        let wallet: Address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1".parse().unwrap();
+        let hash = hash_message("hello");
+        
+        mock.push::<Bytes, _>(encoded_bytes4([0x16, 0x26, 0xba, 0x7e])).unwrap();
+        assert!(auth.verify_contract_signature(MAINNET_CHAIN_ID, wallet, hash, vec![1; 65]).await.unwrap());
+        
+        mock.push::<Bytes, _>(encoded_bytes4([0xff, 0xff, 0xff, 0xff])).unwrap();
+        assert!(!auth.verify_contract_signature(MAINNET_CHAIN_ID, wallet, hash, vec![1; 65]).await.unwrap());
+        
+        // 未实现EIP-1271的地址调用回滚，按校验失败处理
+        mock.push_response(reverted());
+        assert!(!auth.verify_contract_signature(MAINNET_CHAIN_ID, wallet, hash, vec![1; 65]).await.unwrap());
+    }
+    
+    #[tokio::test]
+    async fn contract_signatures_are_ignored_when_eip1271_is_disabled() {
+        let mut harness = TestHarness::start().await;
+        harness.config.eip1271_enabled = false;
+        let (auth, mock) = harness.mocked_auth_service();
+        let wallet: Address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1".parse().unwrap();
+        
+        // 关闭时不发起RPC调用，即使合约会返回魔术值
+        mock.push::<Bytes, _>(encoded_bytes4([0x16, 0x26, 0xba, 0x7e])).unwrap();
+        assert!(!auth.verify_contract_signature(MAINNET_CHAIN_ID, wallet, hash_message("hello"), vec![1; 65]).await.unwrap());
+    }
+    
+    #[tokio::test]
+    async fn ens_lookups_are_cached_including_missing_names() {
+        let harness = TestHarness::start().await;
+        let (auth, mock) = harness.mocked_auth_service();
+        let address: Address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1".parse().unwrap();
+        
+        // 反向解析器为零地址，说明该地址没有ENS名称
+        mock.push::<Bytes, _>(encoded_address(Address::zero())).unwrap();
+        assert_eq!(auth.resolve_ens(&address).await.unwrap(), None);
+        
+        // 第二次查询命中“无名称”缓存，不再发起RPC调用（模拟节点已无响应可用）
+        assert_eq!(auth.resolve_ens(&address).await.unwrap(), None);
+        let mut conn = harness.redis_pool.get().await.unwrap();
+        let cached: Option<String> = conn.get(ens_cache_key(&address)).await.unwrap();
+        assert_eq!(cached.as_deref(), Some(""));
+        
+        // 已缓存的名称直接返回
+        let _: () = conn.set(ens_cache_key(&address), "alice.eth").await.unwrap();
+        assert_eq!(auth.resolve_ens(&address).await.unwrap().as_deref(), Some("alice.eth"));
+    }
+    
+    #[tokio::test]
+    async fn invalidate_ens_forces_a_fresh_lookup() {
+        let harness = TestHarness::start().await;
+        let (auth, mock) = harness.mocked_auth_service();
+        let address: Address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1".parse().unwrap();
+        let mut conn = harness.redis_pool.get().await.unwrap();
+        let _: () = conn.set(ens_cache_key(&address), "alice.eth").await.unwrap();
+        assert_eq!(auth.resolve_ens(&address).await.unwrap().as_deref(), Some("alice.eth"));
+        
+        auth.invalidate_ens(&address).await.unwrap();
+        
+        // 缓存失效后重新查询链上记录，名称已被移除
+        mock.push::<Bytes, _>(encoded_address(Address::zero())).unwrap();
+        assert_eq!(auth.resolve_ens(&address).await.unwrap(), None);
+    }
+    
+    #[tokio::test]
+    async fn jwt_lifetime_matches_configured_expiry() {
+        let mut harness = TestHarness::start().await;
+        harness.config.jwt_expiry_hours = 6;
+        let auth = harness.auth_service();
+        let user = UserAuth {
+            address: "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1".to_string(),
+            ens_name: None,
+            token_holdings: HashMap::new(),
+            nft_holdings: Vec::new(),
+        };
+        
+        let claims = auth.verify_jwt(&auth.generate_jwt(&user).unwrap()).await.unwrap();
+        assert_eq!(claims.exp - claims.iat, 6 * 3600);
+    }
+    
+    #[tokio::test]
+    async fn polygon_gates_use_the_polygon_provider() {
+        let harness = TestHarness::start().await;
+        let (mainnet, mainnet_mock) = Provider::mocked();
+        let (polygon, polygon_mock) = Provider::mocked();
+        let auth = AuthService::with_providers(&harness.config, harness.redis_pool.clone(), mainnet, HashMap::from([(137, polygon)]))
+            .unwrap();
+        let wallet: Address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1".parse().unwrap();
+        let gate = |chain_id: u64| TokenGate {
+            gate_type: TokenGateType::ERC20,
+            contract_address: "0x2791Bca1f2de4661ED88A30C99A7a9449Aa84174".to_string(),
+            minimum_balance: Some("100".to_string()),
+            token_ids: None,
+            chain_id,
+        };
+        assert!(auth.supports_chain(137));
+        assert!(!auth.supports_chain(10));
+        
+        // 只有Polygon节点上持有足够余额
+        polygon_mock.push::<Bytes, _>(encoded_uint(500)).unwrap();
+        mainnet_mock.push::<Bytes, _>(encoded_uint(0)).unwrap();
+        assert!(auth.check_token_gate(&wallet, &gate(137)).await.unwrap());
+        assert!(!auth.check_token_gate(&wallet, &gate(MAINNET_CHAIN_ID)).await.unwrap());
+        
+        assert!(matches!(
+            auth.check_token_gate(&wallet, &gate(10)).await,
+            Err(AppError::InvalidRequest(_))
+        ));
+    }
+    
+    #[tokio::test]
+    async fn nonces_expire_after_configured_ttl() {
+        let mut harness = TestHarness::start().await;
+        harness.config.nonce_ttl_secs = 120;
+        let auth = harness.auth_service();
+        
+        let nonce = auth.generate_nonce().await.unwrap();
+        let mut conn = harness.redis_pool.get().await.unwrap();
+        let ttl: i64 = conn.ttl(format!("nonce:{}", nonce)).await.unwrap();
+        assert!(ttl > 0 && ttl <= 120, "unexpected TTL {}", ttl);
+    }
+}
@@ -1,17 +1,21 @@
 use crate::error::{AppError, Result};
-use crate::models::{OnChainEvent, ServerMessage, UniswapV3SwapDetails};
+use crate::models::{Erc20TransferDetails, OnChainEvent, ServerMessage, UniswapV3SwapDetails};
+use crate::retry::RetryPolicy;
 use crate::state::AppState;
+use crate::telemetry;
 use ethers::{
     contract::{abigen, EthEvent},
     providers::{Provider, Ws, Middleware},
-    types::{Address, Filter, Log, U256},
-    utils::format_units,
+    types::{Address, Filter, Log, H256, U256},
 };
 use serde_json::json;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, RwLock};
+use tokio::task::JoinHandle;
 use tokio_stream::StreamExt;
 use tracing::{error, info, warn};
 
@@ -32,18 +36,63 @@ abigen!(
             ],
             "name": "Swap",
             "type": "event"
+        },
+        {
+            "inputs": [],
+            "name": "token0",
+            "outputs": [{"internalType": "address", "name": "", "type": "address"}],
+            "stateMutability": "view",
+            "type": "function"
+        },
+        {
+            "inputs": [],
+            "name": "token1",
+            "outputs": [{"internalType": "address", "name": "", "type": "address"}],
+            "stateMutability": "view",
+            "type": "function"
         }
     ]"#
 );
 
+// 生成ERC20元数据及Transfer事件的ABI绑定
+mod erc20 {
+    ethers::contract::abigen!(
+        Erc20Metadata,
+        r#"[
+            function symbol() external view returns (string)
+            function decimals() external view returns (uint8)
+            event Transfer(address indexed from, address indexed to, uint256 value)
+        ]"#
+    );
+}
+
+/// 视为1美元计价的稳定币
+const STABLECOINS: [&str; 3] = ["USDC", "USDT", "DAI"];
+
+/// 重连的初始等待时间
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// 重连的最长等待时间
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// 去重时记住的最近日志数量
+const SEEN_LOGS_CAPACITY: usize = 10_000;
+
 /**
  * 区块链事件监听器
  * 监听指定的链上事件并广播到聊天室
  */
 pub struct BlockchainListener {
-    provider: Provider<Ws>,
+    ws_url: String,
+    provider: Arc<Provider<Ws>>,
     app_state: Arc<AppState>,
     monitored_pools: Vec<Address>,
+    watched_tokens: Vec<Address>,
+    detector: LargeTransactionDetector,
+    pool_cache: RwLock<HashMap<Address, PoolInfo>>, // 池子的token信息不会变化，缓存后复用
+    token_cache: RwLock<HashMap<Address, (String, u8)>>,
+    seen_logs: RwLock<SeenLogs>, // 回填、实时订阅与重连可能重复收到同一日志
+    rpc_retry: RetryPolicy,
 }
 
 impl BlockchainListener {
@@ -70,53 +119,224 @@ impl BlockchainListener {
                 .map_err(|e| AppError::BlockchainError(e.to_string()))?,
         ];
         
+        // 监听大额转账的ERC20 token，忽略无法解析的地址
+        let watched_tokens = app_state.config.watched_tokens
+            .iter()
+            .filter_map(|token| match Address::from_str(token) {
+                Ok(address) => Some(address),
+                Err(e) => {
+                    warn!("Ignoring invalid watched token {}: {}", token, e);
+                    None
+                }
+            })
+            .collect();
+        
+        let detector = LargeTransactionDetector::new(
+            app_state.config.swap_alert_threshold_usd,
+            app_state.config.pool_swap_thresholds_usd.clone(),
+        );
+        let rpc_retry = RetryPolicy::from_config(&app_state.config);
+        
         Ok(Self {
-            provider,
+            ws_url: ws_url.to_string(),
+            provider: Arc::new(provider),
             app_state,
             monitored_pools,
+            watched_tokens,
+            detector,
+            pool_cache: RwLock::new(HashMap::new()),
+            token_cache: RwLock::new(HashMap::new()),
+            seen_logs: RwLock::new(SeenLogs::new(SEEN_LOGS_CAPACITY)),
+            rpc_retry,
         })
     }
     
     /**
      * 开始监听区块链事件
      */
-    pub async fn start(self) -> Result<()> {
+    pub async fn start(mut self) -> Result<()> {
         info!("Starting blockchain listener...");
         
-        // 创建事件过滤器
-        let filter = Filter::new()
-            .address(self.monitored_pools.clone())
-            .event(&SwapFilter::abi_signature());
+        let filter = self.event_filter();
+        
+        let mut shutdown = self.app_state.subscribe_shutdown();
+        let mut backoff = INITIAL_RECONNECT_DELAY;
+        // 只在首次订阅时回填历史事件
+        let mut backfill_blocks = self.app_state.config.backfill_blocks;
+        
+        loop {
+            let result = self.run_subscription(&filter, &mut shutdown, backfill_blocks).await;
+            backfill_blocks = 0;
+            
+            match result {
+                Ok(true) => {
+                    info!("Blockchain listener shutting down");
+                    return Ok(());
+                }
+                Ok(false) => {
+                    warn!("Blockchain event stream ended");
+                    backoff = INITIAL_RECONNECT_DELAY;
+                }
+                Err(e) => {
+                    error!("Blockchain subscription failed: {}", e);
+                }
+            }
+            
+            // 指数退避重连，直到成功或收到关闭信号
+            loop {
+                warn!("Reconnecting blockchain provider in {:?}", backoff);
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => {}
+                    _ = shutdown.changed() => {
+                        info!("Blockchain listener shutting down");
+                        return Ok(());
+                    }
+                }
+                backoff = (backoff * 2).min(MAX_RECONNECT_DELAY);
+                
+                match Provider::<Ws>::connect(&self.ws_url).await {
+                    Ok(provider) => {
+                        info!("Blockchain provider reconnected");
+                        self.provider = Arc::new(provider);
+                        break;
+                    }
+                    Err(e) => error!("Failed to reconnect blockchain provider: {}", e),
+                }
+            }
+        }
+    }
+    
+    /**
+     * 创建事件过滤器：池子的Swap事件及token的Transfer事件
+     */
+    fn event_filter(&self) -> Filter {
+        let addresses: Vec<Address> = self.monitored_pools
+            .iter()
+            .chain(self.watched_tokens.iter())
+            .copied()
+            .collect();
+        
+        Filter::new()
+            .address(addresses)
+            .events(vec![
+                SwapFilter::abi_signature().to_string(),
+                erc20::TransferFilter::abi_signature().to_string(),
+            ])
+    }
+    
+    /**
+     * 回填最近若干区块内的链上事件
+     * 事件只写入房间历史而不推送给在线用户，返回回填覆盖到的最新区块号
+     */
+    pub async fn backfill_recent(&self, blocks: u64) -> Result<u64> {
+        let latest = self.rpc_retry.run("eth_blockNumber", || self.provider.get_block_number()).await
+            .map_err(|e| AppError::BlockchainError(e.to_string()))?
+            .as_u64();
+        let from = latest.saturating_sub(blocks.saturating_sub(1));
+        
+        let filter = self.event_filter().from_block(from).to_block(latest);
+        let logs = self.rpc_retry.run("eth_getLogs", || self.provider.get_logs(&filter)).await
+            .map_err(|e| AppError::BlockchainError(e.to_string()))?;
+        
+        info!("Backfilling {} logs from blocks {}..={}", logs.len(), from, latest);
+        
+        for log in logs {
+            if let Err(e) = self.handle_log(log, false).await {
+                warn!("Error handling backfilled log: {}", e);
+            }
+        }
         
+        Ok(latest)
+    }
+    
+    /**
+     * 订阅并处理事件流，流结束时返回false，收到关闭信号时返回true
+     * 回填在订阅建立之后进行，已回填区块内的实时事件会被跳过，避免遗漏或重复
+     */
+    async fn run_subscription(
+        &self,
+        filter: &Filter,
+        shutdown: &mut watch::Receiver<bool>,
+        backfill_blocks: u64,
+    ) -> Result<bool> {
         // 订阅事件流
-        let mut stream = self.provider.subscribe_logs(&filter).await
+        let mut stream = self.provider.subscribe_logs(filter).await
             .map_err(|e| AppError::BlockchainError(e.to_string()))?;
         
-        info!("Blockchain listener started, monitoring {} pools", self.monitored_pools.len());
+        let backfilled_to = if backfill_blocks > 0 {
+            match self.backfill_recent(backfill_blocks).await {
+                Ok(latest) => Some(latest),
+                Err(e) => {
+                    warn!("Failed to backfill recent blockchain events: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        
+        info!(
+            "Blockchain listener started, monitoring {} pools and {} tokens",
+            self.monitored_pools.len(),
+            self.watched_tokens.len()
+        );
         
         // 处理事件流
-        while let Some(log) = stream.next().await {
-            if let Err(e) = self.handle_log(log).await {
-                error!("Error handling blockchain log: {}", e);
+        loop {
+            tokio::select! {
+                log = stream.next() => {
+                    match log {
+                        Some(log) => {
+                            let already_backfilled = match (backfilled_to, log.block_number) {
+                                (Some(latest), Some(block)) => block.as_u64() <= latest,
+                                _ => false,
+                            };
+                            if already_backfilled {
+                                continue;
+                            }
+                            
+                            if let Err(e) = self.handle_log(log, true).await {
+                                error!("Error handling blockchain log: {}", e);
+                            }
+                        }
+                        None => return Ok(false),
+                    }
+                }
+                _ = shutdown.changed() => return Ok(true),
             }
         }
-        
-        warn!("Blockchain event stream ended");
-        Ok(())
     }
     
     /**
      * 处理单个区块链日志事件
+     * live为false时事件只写入房间历史
      */
-    async fn handle_log(&self, log: Log) -> Result<()> {
-        // 尝试解析为Swap事件
+    async fn handle_log(&self, log: Log, live: bool) -> Result<()> {
+        // 按(交易哈希, 日志序号)去重，已处理过的日志不再广播
+        if let (Some(tx_hash), Some(log_index)) = (log.transaction_hash, log.log_index) {
+            if !self.seen_logs.write().await.insert((tx_hash, log_index)) {
+                return Ok(());
+            }
+        }
+        
         let raw_log = ethers::abi::RawLog {
             topics: log.topics.clone(),
             data: log.data.to_vec(),
         };
         
-        if let Ok(swap_event) = SwapFilter::decode_log(&raw_log) {
-            self.handle_swap_event(swap_event, &log).await?;
+        // 按合约地址区分池子的Swap事件与token的Transfer事件
+        if self.monitored_pools.contains(&log.address) {
+            if let Ok(swap_event) = SwapFilter::decode_log(&raw_log) {
+                telemetry::record_blockchain_event("swap", live);
+                self.app_state.record_blockchain_event();
+                self.handle_swap_event(swap_event, &log, live).await?;
+            }
+        } else if self.watched_tokens.contains(&log.address) {
+            if let Ok(transfer_event) = erc20::TransferFilter::decode_log(&raw_log) {
+                telemetry::record_blockchain_event("erc20_transfer", live);
+                self.app_state.record_blockchain_event();
+                self.handle_transfer_event(transfer_event, &log, live).await?;
+            }
         }
         
         Ok(())
@@ -125,29 +345,41 @@ impl BlockchainListener {
     /**
      * 处理Uniswap V3 Swap事件
      */
-    async fn handle_swap_event(&self, event: SwapFilter, log: &Log) -> Result<()> {
+    async fn handle_swap_event(&self, event: SwapFilter, log: &Log, live: bool) -> Result<()> {
         // 获取交易金额的绝对值
-        let amount0_abs = if event.amount_0.is_negative() {
-            U256::from(event.amount_0.abs().as_u128())
-        } else {
-            U256::from(event.amount_0.as_u128())
-        };
+        let amount0_abs = event.amount_0.unsigned_abs();
+        let amount1_abs = event.amount_1.unsigned_abs();
         
-        let amount1_abs = if event.amount_1.is_negative() {
-            U256::from(event.amount_1.abs().as_u128())
-        } else {
-            U256::from(event.amount_1.as_u128())
-        };
+        // 获取池子的token信息
+        let pool_info = self.get_pool_info(&log.address).await?;
         
-        // 只广播大额交易（这里设置一个阈值）
-        let threshold = U256::from(10).pow(U256::from(18)); // 1 ETH equivalent
+        // 按美元价值或各token自身精度下的数量判断是否为大额交易
+        let usd_value = estimate_usd_value(&event.sqrt_price_x96, &amount0_abs, &amount1_abs, &pool_info);
+        let is_large = self.detector.is_large_swap(
+            &format!("{:?}", log.address),
+            usd_value,
+            [
+                (pool_info.token0_symbol.as_str(), &amount0_abs, pool_info.token0_decimals),
+                (pool_info.token1_symbol.as_str(), &amount1_abs, pool_info.token1_decimals),
+            ],
+        );
         
-        if amount0_abs < threshold && amount1_abs < threshold {
+        if !is_large {
             return Ok(()); // 忽略小额交易
         }
         
-        // 获取池子信息（简化实现）
-        let pool_info = self.get_pool_info(&log.address).await?;
+        let amount0_formatted = format_amount(
+            &amount0_abs,
+            pool_info.token0_decimals,
+            &pool_info.token0_symbol,
+        );
+        let amount1_formatted = format_amount(
+            &amount1_abs,
+            pool_info.token1_decimals,
+            &pool_info.token1_symbol,
+        );
+        
+        let usd_value = usd_value.map(|usd| format!("{:.2}", usd));
         
         // 创建交易详情
         let swap_details = UniswapV3SwapDetails {
@@ -155,12 +387,17 @@ impl BlockchainListener {
             recipient: format!("{:?}", event.recipient),
             amount0: event.amount_0.to_string(),
             amount1: event.amount_1.to_string(),
+            amount0_formatted: amount0_formatted.clone(),
+            amount1_formatted: amount1_formatted.clone(),
             sqrt_price_x96: event.sqrt_price_x96.to_string(),
             liquidity: event.liquidity.to_string(),
             tick: event.tick,
             pool_address: format!("{:?}", log.address),
-            token0: pool_info.token0,
-            token1: pool_info.token1,
+            token0: pool_info.token0_symbol,
+            token1: pool_info.token1_symbol,
+            token0_address: format!("{:?}", pool_info.token0_address),
+            token1_address: format!("{:?}", pool_info.token1_address),
+            usd_value,
         };
         
         // 创建链上事件
@@ -174,13 +411,14 @@ impl BlockchainListener {
         // 创建服务器消息
         let server_message = ServerMessage::ChainEvent(chain_event);
         
-        // 广播到所有房间
-        self.app_state.broadcast_global(server_message).await;
+        // 有映射的池子/token只广播到对应房间，以交易token为门禁的房间同样收到，否则广播到所有房间
+        let tokens = [pool_info.token0_address, pool_info.token1_address];
+        self.deliver(self.target_room(&log.address, &pool_info), &tokens, server_message, live).await;
         
         info!(
-            "Broadcasted large swap: {} -> {} in pool {}",
-            event.amount_0,
-            event.amount_1,
+            "Processed large swap: {} / {} in pool {}",
+            amount0_formatted,
+            amount1_formatted,
             log.address
         );
         
@@ -188,39 +426,248 @@ impl BlockchainListener {
     }
     
     /**
-     * 获取池子信息（简化实现）
+     * 处理ERC20 Transfer事件，仅广播超过阈值的大额转账
+     */
+    async fn handle_transfer_event(&self, event: erc20::TransferFilter, log: &Log, live: bool) -> Result<()> {
+        let (symbol, decimals) = self.get_token_metadata(log.address).await;
+        
+        if !self.detector.is_large_transaction(&symbol, &event.value, decimals) {
+            return Ok(()); // 忽略小额转账
+        }
+        
+        let amount_formatted = format_amount(&event.value, decimals, &symbol);
+        
+        let transfer_details = Erc20TransferDetails {
+            from: format!("{:?}", event.from),
+            to: format!("{:?}", event.to),
+            amount: event.value.to_string(),
+            amount_formatted: amount_formatted.clone(),
+            symbol,
+            token_address: format!("{:?}", log.address),
+        };
+        
+        let chain_event = OnChainEvent::new(
+            "ERC20Transfer".to_string(),
+            format!("{:?}", log.transaction_hash.unwrap_or_default()),
+            log.block_number.unwrap_or_default().as_u64(),
+            json!(transfer_details),
+        );
+        
+        let server_message = ServerMessage::ChainEvent(chain_event);
+        
+        // 有映射的token只广播到对应房间，以该token为门禁的房间同样收到，否则广播到所有房间
+        let room = self.app_state.config.pool_room_map
+            .get(&format!("{:?}", log.address))
+            .map(|room| room.as_str());
+        self.deliver(room, &[log.address], server_message, live).await;
+        
+        info!(
+            "Processed large transfer: {} from {:?} to {:?}",
+            amount_formatted,
+            event.from,
+            event.to
+        );
+        
+        Ok(())
+    }
+    
+    /**
+     * 投递链上事件
+     * 目标房间为映射的房间加上以相关token为门禁的房间，没有目标房间时：
+     * 实时事件推送给所有客户端，回填事件写入默认房间的历史
+     * 每个实例都运行自己的监听器，因此链上事件只投递给本实例的客户端
+     */
+    async fn deliver(&self, room: Option<&str>, tokens: &[Address], message: ServerMessage, live: bool) {
+        let tokens: Vec<String> = tokens.iter().map(|token| format!("{:?}", token)).collect();
+        let mut rooms: Vec<String> = room.map(|room| vec![room.to_string()]).unwrap_or_default();
+        for gated in self.app_state.gated_rooms_for(&tokens).await {
+            if !rooms.contains(&gated) {
+                rooms.push(gated);
+            }
+        }
+        
+        if rooms.is_empty() {
+            if live {
+                self.app_state.deliver_global(message).await;
+            } else {
+                let room = self.app_state.config.default_room.as_str();
+                self.app_state.record_room_history(room, message).await;
+            }
+            return;
+        }
+        
+        for room in &rooms {
+            if live {
+                self.app_state.deliver_to_room(room, message.clone()).await;
+            } else {
+                self.app_state.record_room_history(room, message.clone()).await;
+            }
+        }
+    }
+    
+    /**
+     * 根据配置查找链上事件的目标房间，池子映射优先于token映射
+     */
+    fn target_room(&self, pool_address: &Address, pool_info: &PoolInfo) -> Option<&str> {
+        let pool_room_map = &self.app_state.config.pool_room_map;
+        
+        [pool_address, &pool_info.token0_address, &pool_info.token1_address]
+            .iter()
+            .find_map(|address| pool_room_map.get(&format!("{:?}", address)))
+            .map(|room| room.as_str())
+    }
+    
+    /**
+     * 获取池子信息，首次查询时从池子合约及token合约读取
      */
     async fn get_pool_info(&self, pool_address: &Address) -> Result<PoolInfo> {
-        // 这里应该调用池子合约获取token0和token1地址
-        // 简化实现，返回预定义的信息
-        match format!("{:?}", pool_address).as_str() {
-            "0x88e6a0c2ddd26feeb64f039a2c41296fcb3f5640" => Ok(PoolInfo {
-                token0: "USDC".to_string(),
-                token1: "WETH".to_string(),
-            }),
-            "0x8ad599c3a0ff1de082011efddc58f1908eb6e6d8" => Ok(PoolInfo {
-                token0: "USDC".to_string(),
-                token1: "WETH".to_string(),
-            }),
-            "0xcbcdf9626bc03e24f779434178a73a0b4bad62ed" => Ok(PoolInfo {
-                token0: "WBTC".to_string(),
-                token1: "WETH".to_string(),
-            }),
-            _ => Ok(PoolInfo {
-                token0: "Unknown".to_string(),
-                token1: "Unknown".to_string(),
-            }),
+        if let Some(info) = self.pool_cache.read().await.get(pool_address) {
+            return Ok(info.clone());
+        }
+        
+        let pool = UniswapV3Pool::new(*pool_address, self.provider.clone());
+        let (token0_call, token1_call) = (pool.token_0(), pool.token_1());
+        let token0_address = self.rpc_retry.run("token0", || token0_call.call()).await
+            .map_err(|e| AppError::BlockchainError(e.to_string()))?;
+        let token1_address = self.rpc_retry.run("token1", || token1_call.call()).await
+            .map_err(|e| AppError::BlockchainError(e.to_string()))?;
+        
+        let (token0_symbol, token0_decimals) = self.get_token_metadata(token0_address).await;
+        let (token1_symbol, token1_decimals) = self.get_token_metadata(token1_address).await;
+        
+        let info = PoolInfo {
+            token0_address,
+            token0_symbol,
+            token0_decimals,
+            token1_address,
+            token1_symbol,
+            token1_decimals,
+        };
+        
+        self.pool_cache.write().await.insert(*pool_address, info.clone());
+        Ok(info)
+    }
+    
+    /**
+     * 读取ERC20 token的符号和精度
+     * 部分token的symbol不符合标准（如返回bytes32），此时退回使用地址
+     */
+    async fn get_token_metadata(&self, token_address: Address) -> (String, u8) {
+        if let Some(metadata) = self.token_cache.read().await.get(&token_address) {
+            return metadata.clone();
+        }
+        
+        let token = erc20::Erc20Metadata::new(token_address, self.provider.clone());
+        
+        let (symbol_call, decimals_call) = (token.symbol(), token.decimals());
+        let symbol = self.rpc_retry.run("symbol", || symbol_call.call()).await.unwrap_or_else(|e| {
+            warn!("Failed to read symbol of token {:?}: {}", token_address, e);
+            format!("{:?}", token_address)
+        });
+        
+        let decimals = self.rpc_retry.run("decimals", || decimals_call.call()).await.unwrap_or_else(|e| {
+            warn!("Failed to read decimals of token {:?}: {}", token_address, e);
+            18
+        });
+        
+        self.token_cache.write().await.insert(token_address, (symbol.clone(), decimals));
+        (symbol, decimals)
+    }
+}
+
+/**
+ * 按配置启动唯一的区块链监听器任务，BLOCKCHAIN_ENABLED关闭时不启动并返回None
+ */
+pub fn start_listener(app_state: Arc<AppState>) -> Option<JoinHandle<()>> {
+    if !app_state.config.blockchain_enabled {
+        return None;
+    }
+    
+    let ws_url = app_state.config.ethereum_ws_url.clone();
+    Some(spawn_listener(ws_url, app_state))
+}
+
+/**
+ * 在后台启动区块链监听器
+ * 链上事件是可选功能：创建监听器失败（WS地址错误、节点不可用）时只记录警告，
+ * 按指数退避在后台重试，不影响聊天服务启动
+ */
+pub fn spawn_listener(ws_url: String, app_state: Arc<AppState>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut shutdown = app_state.subscribe_shutdown();
+        let mut backoff = INITIAL_RECONNECT_DELAY;
+        
+        loop {
+            if *shutdown.borrow() {
+                return;
+            }
+            
+            match BlockchainListener::new(&ws_url, app_state.clone()).await {
+                Ok(listener) => {
+                    if let Err(e) = listener.start().await {
+                        error!("Blockchain listener error: {}", e);
+                    }
+                    return;
+                }
+                Err(e) => warn!("Blockchain listener unavailable, retrying in {:?}: {}", backoff, e),
+            }
+            
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = shutdown.changed() => return,
+            }
+            backoff = (backoff * 2).min(MAX_RECONNECT_DELAY);
+        }
+    })
+}
+
+/**
+ * 最近处理过的日志，超过容量时淘汰最早的记录
+ */
+struct SeenLogs {
+    capacity: usize,
+    seen: HashSet<(H256, U256)>,
+    order: VecDeque<(H256, U256)>,
+}
+
+impl SeenLogs {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+    
+    /**
+     * 记录日志，已记录过时返回false
+     */
+    fn insert(&mut self, key: (H256, U256)) -> bool {
+        if !self.seen.insert(key) {
+            return false;
+        }
+        
+        self.order.push_back(key);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
         }
+        true
     }
 }
 
 /**
  * 池子信息结构体
  */
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct PoolInfo {
-    token0: String,
-    token1: String,
+    token0_address: Address,
+    token0_symbol: String,
+    token0_decimals: u8,
+    token1_address: Address,
+    token1_symbol: String,
+    token1_decimals: u8,
 }
 
 /**
@@ -229,13 +676,15 @@ struct PoolInfo {
  */
 pub struct LargeTransactionDetector {
     thresholds: HashMap<String, U256>,
+    swap_threshold_usd: Option<f64>, // Swap的全局美元阈值
+    pool_thresholds_usd: HashMap<String, f64>, // 池子地址（小写） -> 该池子的美元阈值
 }
 
 impl LargeTransactionDetector {
     /**
      * 创建新的大额交易检测器
      */
-    pub fn new() -> Self {
+    pub fn new(swap_threshold_usd: Option<f64>, pool_thresholds_usd: HashMap<String, f64>) -> Self {
         let mut thresholds = HashMap::new();
         
         // 设置不同token的阈值
@@ -243,23 +692,88 @@ impl LargeTransactionDetector {
         thresholds.insert("USDC".to_string(), U256::from(10000) * U256::from(10).pow(U256::from(6))); // 10,000 USDC
         thresholds.insert("WBTC".to_string(), U256::from(1) * U256::from(10).pow(U256::from(7))); // 0.1 BTC
         
-        Self { thresholds }
+        Self {
+            thresholds,
+            swap_threshold_usd,
+            pool_thresholds_usd,
+        }
+    }
+    
+    /**
+     * 检查Swap是否值得广播
+     * 池子单独配置的美元阈值优先于全局阈值，阈值为0时广播该池子的所有Swap
+     * 未配置美元阈值或无法估算美元价值时，按各token自身的精度判断数量
+     */
+    pub fn is_large_swap(&self, pool_address: &str, usd_value: Option<f64>, amounts: [(&str, &U256, u8); 2]) -> bool {
+        let threshold = self.pool_thresholds_usd
+            .get(&pool_address.to_lowercase())
+            .copied()
+            .or(self.swap_threshold_usd);
+        
+        match (threshold, usd_value) {
+            (Some(threshold), _) if threshold <= 0.0 => true,
+            (Some(threshold), Some(usd)) => usd >= threshold,
+            _ => amounts
+                .iter()
+                .any(|(symbol, amount, decimals)| self.is_large_transaction(symbol, amount, *decimals)),
+        }
     }
     
     /**
      * 检查交易是否为大额交易
+     * 已知token的阈值以最小单位表示，未知token按其精度使用默认阈值
      */
-    pub fn is_large_transaction(&self, token_symbol: &str, amount: &U256) -> bool {
+    pub fn is_large_transaction(&self, token_symbol: &str, amount: &U256, decimals: u8) -> bool {
         if let Some(threshold) = self.thresholds.get(token_symbol) {
             amount >= threshold
         } else {
             // 对于未知token，使用默认阈值
-            let default_threshold = U256::from(1000) * U256::from(10).pow(U256::from(18));
+            let default_threshold = U256::from(1000) * U256::from(10).pow(U256::from(decimals));
             amount >= &default_threshold
         }
     }
 }
 
+/**
+ * 根据池子价格估算交易的美元价值
+ * sqrtPriceX96的平方为token0以token1计价的价格，需要有一侧为稳定币
+ */
+fn estimate_usd_value(
+    sqrt_price_x96: &U256,
+    amount0: &U256,
+    amount1: &U256,
+    pool_info: &PoolInfo,
+) -> Option<f64> {
+    let sqrt_price = u256_to_f64(sqrt_price_x96) / 2f64.powi(96);
+    let decimals_diff = pool_info.token0_decimals as i32 - pool_info.token1_decimals as i32;
+    let price = sqrt_price * sqrt_price * 10f64.powi(decimals_diff);
+    
+    let amount0 = u256_to_f64(amount0) / 10f64.powi(pool_info.token0_decimals as i32);
+    let amount1 = u256_to_f64(amount1) / 10f64.powi(pool_info.token1_decimals as i32);
+    
+    if is_stablecoin(&pool_info.token1_symbol) {
+        Some(amount0 * price)
+    } else if is_stablecoin(&pool_info.token0_symbol) && price > 0.0 {
+        Some(amount1 / price)
+    } else {
+        None
+    }
+}
+
+/**
+ * 判断token是否为稳定币
+ */
+fn is_stablecoin(symbol: &str) -> bool {
+    STABLECOINS.iter().any(|stable| stable.eq_ignore_ascii_case(symbol))
+}
+
+/**
+ * 将U256转换为浮点数，仅用于近似计算
+ */
+fn u256_to_f64(value: &U256) -> f64 {
+    value.to_string().parse().unwrap_or(0.0)
+}
+
 /**
  * 格式化交易金额为人类可读格式
  */
@@ -280,4 +794,229 @@ pub fn format_amount(amount: &U256, decimals: u8, symbol: &str) -> String {
             format!("{}.{} {}", whole, trimmed, symbol)
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{RoomConfig, TokenGate, TokenGateType, MAINNET_CHAIN_ID};
+    use crate::test_support::TestHarness;
+    
+    #[tokio::test]
+    async fn listener_failure_does_not_block_startup() {
+        let harness = TestHarness::start().await;
+        let state = harness.app_state();
+        
+        // 测试配置中的WS地址没有节点监听，监听器创建会失败
+        let handle = spawn_listener(harness.config.ethereum_ws_url.clone(), state.clone());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!handle.is_finished(), "listener task should keep retrying in the background");
+        
+        state.trigger_shutdown();
+        tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .expect("listener task did not stop on shutdown")
+            .unwrap();
+    }
+    
+    #[tokio::test]
+    async fn listener_starts_only_when_enabled() {
+        let mut harness = TestHarness::start().await;
+        
+        harness.config.blockchain_enabled = false;
+        assert!(start_listener(harness.app_state()).is_none());
+        
+        harness.config.blockchain_enabled = true;
+        let state = harness.app_state();
+        let handle = start_listener(state.clone()).expect("listener should start when enabled");
+        
+        state.trigger_shutdown();
+        tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .expect("listener task did not stop on shutdown")
+            .unwrap();
+    }
+    
+    /**
+     * 以最小单位表示的token数量
+     */
+    fn units(amount: u64, decimals: u8) -> U256 {
+        U256::from(amount) * U256::from(10).pow(U256::from(decimals))
+    }
+    
+    #[test]
+    fn large_transactions_use_token_decimals() {
+        let detector = LargeTransactionDetector::new(None, HashMap::new());
+        
+        // 同样是5,000个token，USDC低于10,000 USDC的阈值，WETH远超1 ETH的阈值
+        assert!(!detector.is_large_transaction("USDC", &units(5_000, 6), 6));
+        assert!(detector.is_large_transaction("WETH", &units(5_000, 18), 18));
+        assert!(detector.is_large_transaction("USDC", &units(10_000, 6), 6));
+        
+        // WBTC为8位精度，阈值为0.1 BTC
+        assert!(detector.is_large_transaction("WBTC", &U256::from(10_000_000u64), 8));
+        assert!(!detector.is_large_transaction("WBTC", &U256::from(9_999_999u64), 8));
+    }
+    
+    #[test]
+    fn amounts_are_formatted_with_token_decimals() {
+        assert_eq!(format_amount(&U256::from(1_500_000u64), 6, "USDC"), "1.5 USDC");
+        assert_eq!(format_amount(&U256::from(12_345_678u64), 8, "WBTC"), "0.12345678 WBTC");
+        assert_eq!(format_amount(&units(2, 18), 18, "WETH"), "2 WETH");
+    }
+    
+    #[test]
+    fn swaps_below_usd_threshold_are_suppressed() {
+        let pool = "0x88e6A0c2dDD26FEEb64F039a2c41296FcB3f5640";
+        let detector = LargeTransactionDetector::new(Some(50_000.0), HashMap::new());
+        let amounts = [("USDC", &U256::zero(), 6), ("WETH", &U256::zero(), 18)];
+        
+        assert!(!detector.is_large_swap(pool, Some(49_999.99), amounts));
+        assert!(detector.is_large_swap(pool, Some(50_000.0), amounts));
+        
+        // 池子阈值为0时广播该池子的所有Swap，池子地址不区分大小写
+        let pool_thresholds = HashMap::from([(pool.to_lowercase(), 0.0)]);
+        let detector = LargeTransactionDetector::new(Some(50_000.0), pool_thresholds);
+        assert!(detector.is_large_swap(pool, Some(1.0), amounts));
+    }
+    
+    /**
+     * 测试用的池子信息
+     */
+    fn pool_info(token0: (&str, u8), token1: (&str, u8)) -> PoolInfo {
+        PoolInfo {
+            token0_address: Address::zero(),
+            token0_symbol: token0.0.to_string(),
+            token0_decimals: token0.1,
+            token1_address: Address::zero(),
+            token1_symbol: token1.0.to_string(),
+            token1_decimals: token1.1,
+        }
+    }
+    
+    #[test]
+    fn usd_value_is_estimated_from_pool_price() {
+        // sqrtPriceX96 = 50 * 2^96，即1 WETH = 2500 DAI
+        let sqrt_price_x96 = U256::from(50) << 96;
+        let two_weth = units(2, 18);
+        
+        let weth_dai = pool_info(("WETH", 18), ("DAI", 18));
+        let usd = estimate_usd_value(&sqrt_price_x96, &two_weth, &U256::zero(), &weth_dai).unwrap();
+        assert!((usd - 5_000.0).abs() < 0.01);
+        
+        // 稳定币为token0时按倒数价格换算
+        let dai_weth = pool_info(("DAI", 18), ("WETH", 18));
+        let inverse_price = (U256::from(1) << 96) / U256::from(50);
+        let usd = estimate_usd_value(&inverse_price, &U256::zero(), &two_weth, &dai_weth).unwrap();
+        assert!((usd - 5_000.0).abs() < 0.01);
+        
+        // 没有稳定币的池子无法估算
+        let weth_wbtc = pool_info(("WETH", 18), ("WBTC", 8));
+        assert!(estimate_usd_value(&sqrt_price_x96, &two_weth, &U256::zero(), &weth_wbtc).is_none());
+    }
+    
+    #[test]
+    fn seen_logs_dedupe_and_evict_oldest_at_capacity() {
+        let mut seen = SeenLogs::new(2);
+        let log = |tx: u64, index: u64| (H256::from_low_u64_be(tx), U256::from(index));
+        
+        assert!(seen.insert(log(1, 0)));
+        assert!(!seen.insert(log(1, 0)));
+        // 同一交易中的不同日志分别记录
+        assert!(seen.insert(log(1, 1)));
+        
+        // 超过容量时淘汰最早的记录
+        assert!(seen.insert(log(2, 0)));
+        assert_eq!(seen.order.len(), 2);
+        assert!(!seen.insert(log(2, 0)));
+        assert!(!seen.insert(log(1, 1)));
+        assert!(seen.insert(log(1, 0)));
+    }
+    
+    /**
+     * 连接到本地WebSocket节点的监听器，节点接受连接但不响应请求
+     */
+    async fn test_listener(state: Arc<AppState>) -> BlockchainListener {
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = server.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(_)) = socket.next().await {}
+        });
+        BlockchainListener::new(&format!("ws://{}", addr), state).await.unwrap()
+    }
+    
+    /**
+     * 测试用的链上事件消息
+     */
+    fn chain_event() -> ServerMessage {
+        ServerMessage::ChainEvent(OnChainEvent::new("UniswapV3Swap".to_string(), format!("{:?}", H256::zero()), 1, json!({})))
+    }
+    
+    #[tokio::test]
+    async fn mapped_pool_events_go_to_their_room() {
+        let mut harness = TestHarness::start().await;
+        let pool: Address = "0x88e6A0c2dDD26FEEb64F039a2c41296FcB3f5640".parse().unwrap();
+        harness.config.pool_room_map = HashMap::from([(format!("{:?}", pool), "usdc-weth".to_string())]);
+        let state = harness.app_state();
+        let listener = test_listener(state.clone()).await;
+        
+        let info = pool_info(("USDC", 6), ("WETH", 18));
+        assert_eq!(listener.target_room(&pool, &info), Some("usdc-weth"));
+        assert_eq!(listener.target_room(&Address::repeat_byte(1), &info), None);
+        
+        let member = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        let outsider = "0xFFcf8FDEE72ac11b5c542428B35EEF5769C409f0";
+        for address in [member, outsider] {
+            state.add_client(address.to_string(), None).await.unwrap();
+        }
+        assert!(state.join_room(member, "usdc-weth").await);
+        let mut member_receiver = state.get_client(member).await.unwrap().sender.subscribe();
+        let mut outsider_receiver = state.get_client(outsider).await.unwrap().sender.subscribe();
+        
+        listener.deliver(listener.target_room(&pool, &info), &[], chain_event(), true).await;
+        assert!(matches!(member_receiver.try_recv().unwrap(), ServerMessage::ChainEvent(_)));
+        assert!(outsider_receiver.try_recv().is_err());
+    }
+    
+    #[tokio::test]
+    async fn swaps_of_a_gating_token_reach_the_gated_room() {
+        let harness = TestHarness::start().await;
+        let state = harness.app_state();
+        let listener = test_listener(state.clone()).await;
+        let weth: Address = "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2".parse().unwrap();
+        let usdc: Address = "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48".parse().unwrap();
+        let holder = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        
+        assert!(state.create_room(RoomConfig {
+            name: "weth-holders".to_string(),
+            description: None,
+            token_gate: Some(TokenGate {
+                gate_type: TokenGateType::ERC20,
+                contract_address: "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2".to_string(),
+                minimum_balance: None,
+                token_ids: None,
+                chain_id: MAINNET_CHAIN_ID,
+            }),
+            max_users: None,
+            max_history: None,
+            slow_mode_secs: None,
+            created_at: chrono::Utc::now(),
+            created_by: holder.to_string(),
+        }).await.unwrap());
+        state.add_client(holder.to_string(), None).await.unwrap();
+        assert!(state.join_room(holder, "weth-holders").await);
+        let mut receiver = state.get_client(holder).await.unwrap().sender.subscribe();
+        let mut global = state.global_sender.subscribe();
+        
+        listener.deliver(None, &[usdc, weth], chain_event(), true).await;
+        assert!(matches!(receiver.try_recv().unwrap(), ServerMessage::ChainEvent(_)));
+        assert!(global.try_recv().is_err());
+        
+        // 房间删除后映射随之更新，事件回到全局广播
+        state.delete_room("weth-holders", holder).await.unwrap();
+        listener.deliver(None, &[usdc, weth], chain_event(), true).await;
+        assert!(matches!(global.try_recv().unwrap(), ServerMessage::ChainEvent(_)));
+    }
+}
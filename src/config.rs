@@ -1,6 +1,14 @@
+use crate::content_filter::FilterMode;
+use crate::auth::validate_token_gate;
+use crate::models::{avatar_seed, display_name, RoomConfig, RuntimeConfig, TokenGate};
+use std::collections::HashMap;
 use anyhow::{anyhow, Result};
+use serde::Deserialize;
 use std::env;
 
+/// 默认的欢迎消息，{room}会被替换为默认房间名
+const DEFAULT_WELCOME_MESSAGE: &str = "Welcome to ChainTalk! Please authenticate to start chatting in #{room}.";
+
 /**
  * 应用配置结构体
  * 包含服务器地址、数据库连接、区块链节点等配置信息
@@ -9,12 +17,112 @@ use std::env;
 pub struct Config {
     pub server_address: String,
     pub redis_url: String,
+    pub redis_max_connections: u32,
+    pub redis_min_idle: Option<u32>,
+    pub redis_connect_timeout_ms: u64,
     pub ethereum_ws_url: String,
     pub ethereum_http_url: String,
+    pub blockchain_enabled: bool, // 是否启动链上事件监听器，关闭后聊天功能不受影响
     pub jwt_secret: String,
     pub cors_origins: Vec<String>,
+    pub cors_permissive: bool,
     pub uniswap_v3_factory: String,
     pub default_room: String,
+    pub admin_addresses: Vec<String>,
+    pub default_avatar_url: Option<String>,
+    pub display_name_prefix_len: usize, // 没有ENS名称时，缩写地址保留的开头字符数（包括0x）
+    pub display_name_suffix_len: usize, // 缩写地址保留的结尾字符数
+    pub auto_create_rooms: bool,
+    pub startup_rooms: Vec<StartupRoom>, // 启动时创建的常驻房间，不包括默认房间时会自动补上
+    pub motd: Option<String>,
+    pub welcome_message: Option<String>,
+    pub maintenance_mode: bool,
+    pub history_limit: usize,
+    pub default_max_history: usize,
+    pub history_page_max: usize,
+    pub rate_limit_messages: u32,
+    pub rate_limit_window_ms: u64,
+    pub default_room_max_users: Option<usize>,
+    pub token_gate_cache_ttl_secs: u64,
+    pub idle_timeout_secs: u64,
+    pub ws_ping_interval_secs: u64,
+    pub ws_max_missed_pongs: u32,
+    pub pool_room_map: HashMap<String, String>,
+    pub swap_alert_threshold_usd: Option<f64>,
+    pub pool_swap_thresholds_usd: HashMap<String, f64>,
+    pub watched_tokens: Vec<String>,
+    pub backfill_blocks: u64,
+    pub max_connections_per_address: usize,
+    pub max_rooms_per_user: usize,
+    pub siwe_domain: String,
+    pub siwe_scheme: String,
+    pub siwe_statement: String,
+    pub siwe_resources: Vec<String>,
+    pub jwt_expiry_hours: i64,
+    pub refresh_token_ttl_days: i64,
+    pub nonce_rate_limit_per_minute: u32,
+    pub nonce_ttl_secs: u64,
+    pub eip1271_enabled: bool,
+    pub ens_cache_ttl_secs: u64,
+    pub max_message_length: usize,
+    pub duplicate_message_window_ms: u64, // 同一用户在同一房间重复发送相同内容的拒绝窗口，0表示关闭
+    pub attachment_rate_limit_per_minute: u32,
+    pub attachment_verify_content_type: bool,
+    pub blocklist: Vec<String>,
+    pub filter_mode: FilterMode,
+    pub metrics_enabled: bool,
+    pub global_broadcast_capacity: usize,
+    pub client_broadcast_capacity: usize,
+    pub rpc_urls: HashMap<u64, String>,
+    pub rpc_retry_attempts: u32,       // RPC调用的最多尝试次数（包括第一次）
+    pub rpc_retry_base_delay_ms: u64,  // 第一次重试前的等待时间，之后每次翻倍
+    pub ws_max_frame_bytes: usize,
+    pub ws_max_frames_per_sec: u32,
+    pub admin_token: Option<String>,
+    pub audit_log_path: Option<String>, // 安全事件日志文件，未配置时输出到标准输出
+}
+
+/**
+ * 启动时创建的房间，config为None时房间没有门禁等配置
+ */
+#[derive(Debug, Clone)]
+pub struct StartupRoom {
+    pub name: String,
+    pub config: Option<RoomConfig>,
+}
+
+/**
+ * STARTUP_ROOMS_PATH文件中的房间条目
+ */
+#[derive(Debug, Deserialize)]
+struct StartupRoomEntry {
+    name: String,
+    description: Option<String>,
+    token_gate: Option<TokenGate>,
+    max_users: Option<usize>,
+    max_history: Option<usize>,
+    slow_mode_secs: Option<u64>,
+}
+
+impl StartupRoomEntry {
+    /**
+     * 转换为房间配置，创建者记为system
+     */
+    fn into_room(self) -> StartupRoom {
+        StartupRoom {
+            name: self.name.clone(),
+            config: Some(RoomConfig {
+                name: self.name,
+                description: self.description,
+                token_gate: self.token_gate,
+                max_users: self.max_users,
+                max_history: self.max_history,
+                slow_mode_secs: self.slow_mode_secs,
+                created_at: chrono::Utc::now(),
+                created_by: "system".to_string(),
+            }),
+        }
+    }
 }
 
 impl Config {
@@ -27,21 +135,402 @@ impl Config {
                 .unwrap_or_else(|_| "0.0.0.0:3000".to_string()),
             redis_url: env::var("REDIS_URL")
                 .unwrap_or_else(|_| "redis://localhost:6379".to_string()),
+            redis_max_connections: env::var("REDIS_MAX_CONNECTIONS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|size| *size > 0)
+                .unwrap_or(16),
+            redis_min_idle: env::var("REDIS_MIN_IDLE")
+                .ok()
+                .and_then(|v| v.parse().ok()),
+            redis_connect_timeout_ms: env::var("REDIS_CONNECT_TIMEOUT_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(2000),
             ethereum_ws_url: env::var("ETHEREUM_WS_URL")
                 .map_err(|_| anyhow!("ETHEREUM_WS_URL environment variable is required"))?,
             ethereum_http_url: env::var("ETHEREUM_HTTP_URL")
                 .map_err(|_| anyhow!("ETHEREUM_HTTP_URL environment variable is required"))?,
+            blockchain_enabled: env::var("BLOCKCHAIN_ENABLED")
+                .map(|v| v == "true" || v == "1")
+                .unwrap_or(true),
             jwt_secret: env::var("JWT_SECRET")
                 .map_err(|_| anyhow!("JWT_SECRET environment variable is required"))?,
             cors_origins: env::var("CORS_ORIGINS")
                 .unwrap_or_else(|_| "http://localhost:3000,http://localhost:5173".to_string())
                 .split(',')
                 .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
                 .collect(),
+            cors_permissive: env::var("CORS_PERMISSIVE")
+                .map(|v| v == "true" || v == "1")
+                .unwrap_or(false),
             uniswap_v3_factory: env::var("UNISWAP_V3_FACTORY")
                 .unwrap_or_else(|_| "0x1F98431c8aD98523631AE4a59f267346ea31F984".to_string()),
             default_room: env::var("DEFAULT_ROOM")
                 .unwrap_or_else(|_| "general".to_string()),
+            admin_addresses: env::var("ADMIN_ADDRESSES")
+                .unwrap_or_default()
+                .split(',')
+                .map(|s| s.trim().to_lowercase())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            default_avatar_url: env::var("DEFAULT_AVATAR_URL")
+                .ok()
+                .filter(|s| !s.trim().is_empty()),
+            display_name_prefix_len: env::var("DISPLAY_NAME_PREFIX_LEN")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(6),
+            display_name_suffix_len: env::var("DISPLAY_NAME_SUFFIX_LEN")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(4),
+            auto_create_rooms: env::var("AUTO_CREATE_ROOMS")
+                .map(|v| v != "false" && v != "0")
+                .unwrap_or(true),
+            startup_rooms: load_startup_rooms()?,
+            motd: env::var("MOTD")
+                .ok()
+                .filter(|s| !s.trim().is_empty()),
+            welcome_message: match env::var("WELCOME_MESSAGE") {
+                Ok(v) if v.trim().is_empty() => None,
+                Ok(v) => Some(v),
+                Err(_) => Some(DEFAULT_WELCOME_MESSAGE.to_string()),
+            },
+            maintenance_mode: env::var("MAINTENANCE_MODE")
+                .map(|v| v == "true" || v == "1")
+                .unwrap_or(false),
+            history_limit: env::var("ROOM_HISTORY_LIMIT")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(50),
+            default_max_history: env::var("DEFAULT_MAX_HISTORY")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(100),
+            history_page_max: env::var("HISTORY_PAGE_MAX")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|max| *max > 0)
+                .unwrap_or(200),
+            rate_limit_messages: env::var("RATE_LIMIT_MESSAGES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(5),
+            rate_limit_window_ms: env::var("RATE_LIMIT_WINDOW_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(2000),
+            default_room_max_users: env::var("DEFAULT_ROOM_MAX_USERS")
+                .ok()
+                .and_then(|v| v.parse().ok()),
+            token_gate_cache_ttl_secs: env::var("TOKEN_GATE_CACHE_TTL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(300),
+            idle_timeout_secs: env::var("IDLE_TIMEOUT_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(300),
+            ws_ping_interval_secs: env::var("WS_PING_INTERVAL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(30),
+            ws_max_missed_pongs: env::var("WS_MAX_MISSED_PONGS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(2),
+            pool_room_map: parse_pool_room_map(&env::var("POOL_ROOM_MAP").unwrap_or_default()),
+            swap_alert_threshold_usd: env::var("SWAP_ALERT_THRESHOLD_USD")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|usd: &f64| *usd >= 0.0),
+            pool_swap_thresholds_usd: parse_pool_thresholds(&env::var("POOL_SWAP_THRESHOLDS_USD").unwrap_or_default()),
+            watched_tokens: env::var("WATCHED_TOKENS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            backfill_blocks: env::var("BACKFILL_BLOCKS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(50),
+            max_connections_per_address: env::var("MAX_CONNECTIONS_PER_ADDRESS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(5),
+            max_rooms_per_user: env::var("MAX_ROOMS_PER_USER")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(50),
+            siwe_domain: env::var("SIWE_DOMAIN")
+                .unwrap_or_else(|_| "localhost:3000".to_string()),
+            siwe_scheme: env::var("SIWE_SCHEME")
+                .ok()
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+                .unwrap_or_else(|| "https".to_string()),
+            siwe_statement: env::var("SIWE_STATEMENT")
+                .map(|v| v.trim().to_string())
+                .unwrap_or_else(|_| "ChainTalk Authentication".to_string()),
+            siwe_resources: env::var("SIWE_RESOURCES")
+                .unwrap_or_default()
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            jwt_expiry_hours: env::var("JWT_EXPIRY_HOURS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|hours| *hours > 0)
+                .unwrap_or(24),
+            refresh_token_ttl_days: env::var("REFRESH_TOKEN_TTL_DAYS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|days| *days > 0)
+                .unwrap_or(30),
+            nonce_rate_limit_per_minute: env::var("NONCE_RATE_LIMIT_PER_MINUTE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(10),
+            nonce_ttl_secs: env::var("NONCE_TTL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|secs| *secs > 0)
+                .unwrap_or(300),
+            eip1271_enabled: env::var("EIP1271_ENABLED")
+                .map(|v| v == "true" || v == "1")
+                .unwrap_or(false),
+            ens_cache_ttl_secs: env::var("ENS_CACHE_TTL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(6 * 3600),
+            max_message_length: env::var("MAX_MESSAGE_LENGTH")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(1000),
+            duplicate_message_window_ms: env::var("DUPLICATE_MESSAGE_WINDOW_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(2000),
+            attachment_rate_limit_per_minute: env::var("ATTACHMENT_RATE_LIMIT_PER_MINUTE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(5),
+            attachment_verify_content_type: env::var("ATTACHMENT_VERIFY_CONTENT_TYPE")
+                .map(|v| v == "true" || v == "1")
+                .unwrap_or(false),
+            blocklist: load_blocklist()?,
+            filter_mode: FilterMode::parse(&env::var("FILTER_MODE").unwrap_or_default()),
+            metrics_enabled: env::var("METRICS_ENABLED")
+                .map(|v| v == "true" || v == "1")
+                .unwrap_or(false),
+            global_broadcast_capacity: env::var("GLOBAL_BROADCAST_CAPACITY")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|capacity| *capacity > 0)
+                .unwrap_or(1000),
+            // CLIENT_BUFFER_SIZE是旧的变量名，仍然兼容
+            client_broadcast_capacity: env::var("CLIENT_BROADCAST_CAPACITY")
+                .or_else(|_| env::var("CLIENT_BUFFER_SIZE"))
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|capacity| *capacity > 0)
+                .unwrap_or(128),
+            rpc_urls: parse_rpc_urls(&env::var("RPC_URLS").unwrap_or_default()),
+            rpc_retry_attempts: env::var("RPC_RETRY_ATTEMPTS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|attempts| *attempts > 0)
+                .unwrap_or(3),
+            rpc_retry_base_delay_ms: env::var("RPC_RETRY_BASE_DELAY_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(200),
+            ws_max_frame_bytes: env::var("WS_MAX_FRAME_BYTES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(64 * 1024),
+            ws_max_frames_per_sec: env::var("WS_MAX_FRAMES_PER_SEC")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(20),
+            admin_token: env::var("ADMIN_TOKEN")
+                .ok()
+                .filter(|v| !v.trim().is_empty()),
+            audit_log_path: env::var("AUDIT_LOG_PATH")
+                .ok()
+                .filter(|v| !v.trim().is_empty()),
         })
     }
+    
+    /**
+     * 判断地址是否为管理员
+     */
+    pub fn is_admin(&self, address: &str) -> bool {
+        let address = address.to_lowercase();
+        self.admin_addresses.iter().any(|admin| *admin == address)
+    }
+    
+    /**
+     * 生成连接建立时发送的欢迎消息，未配置时返回None
+     */
+    pub fn welcome_text(&self) -> Option<String> {
+        self.welcome_message
+            .as_ref()
+            .map(|template| template.replace("{room}", &self.default_room))
+    }
+    
+    /**
+     * 根据启动配置生成初始的运行时配置
+     */
+    pub fn runtime_config(&self) -> RuntimeConfig {
+        RuntimeConfig {
+            motd: self.motd.clone(),
+            maintenance_mode: self.maintenance_mode,
+            features: HashMap::new(),
+            rate_limit_messages: self.rate_limit_messages,
+            rate_limit_window_ms: self.rate_limit_window_ms,
+        }
+    }
+    
+    /**
+     * 生成默认头像URL，模板中的{address}会被替换为头像种子
+     */
+    pub fn default_avatar(&self, address: &str) -> Option<String> {
+        self.default_avatar_url
+            .as_ref()
+            .map(|template| template.replace("{address}", &avatar_seed(address)))
+    }
+    
+    /**
+     * 用户显示名称：优先使用ENS名称，否则按配置的长度缩写地址
+     */
+    pub fn display_name(&self, address: &str, ens_name: Option<&str>) -> String {
+        display_name(
+            address,
+            ens_name,
+            self.display_name_prefix_len,
+            self.display_name_suffix_len,
+        )
+    }
+}
+
+/**
+ * 加载屏蔽词列表：BLOCKLIST为逗号分隔的词，BLOCKLIST_PATH文件中每行一个词
+ */
+fn load_blocklist() -> Result<Vec<String>> {
+    let mut words: Vec<String> = env::var("BLOCKLIST")
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+    
+    if let Ok(path) = env::var("BLOCKLIST_PATH") {
+        if !path.trim().is_empty() {
+            let content = std::fs::read_to_string(&path)
+                .map_err(|e| anyhow!("Failed to read BLOCKLIST_PATH {}: {}", path, e))?;
+            words.extend(
+                content
+                    .lines()
+                    .map(|line| line.trim().to_string())
+                    .filter(|line| !line.is_empty() && !line.starts_with('#')),
+            );
+        }
+    }
+    
+    Ok(words)
+}
+
+/**
+ * 加载启动时创建的房间：STARTUP_ROOMS为逗号分隔的房间名，
+ * STARTUP_ROOMS_PATH为JSON数组文件，可为房间指定描述、Token门禁等配置，同名时以文件为准
+ */
+fn load_startup_rooms() -> Result<Vec<StartupRoom>> {
+    let mut rooms: Vec<StartupRoom> = Vec::new();
+    for name in env::var("STARTUP_ROOMS").unwrap_or_default().split(',') {
+        let name = name.trim();
+        if !name.is_empty() && !rooms.iter().any(|room| room.name == name) {
+            rooms.push(StartupRoom { name: name.to_string(), config: None });
+        }
+    }
+    
+    if let Ok(path) = env::var("STARTUP_ROOMS_PATH") {
+        if !path.trim().is_empty() {
+            let content = std::fs::read_to_string(&path)
+                .map_err(|e| anyhow!("Failed to read STARTUP_ROOMS_PATH {}: {}", path, e))?;
+            let entries: Vec<StartupRoomEntry> = serde_json::from_str(&content)
+                .map_err(|e| anyhow!("Invalid STARTUP_ROOMS_PATH {}: {}", path, e))?;
+            
+            for entry in entries {
+                if let Some(gate) = &entry.token_gate {
+                    validate_token_gate(gate)
+                        .map_err(|e| anyhow!("Invalid token gate for startup room {}: {}", entry.name, e))?;
+                }
+                
+                let room = entry.into_room();
+                match rooms.iter_mut().find(|existing| existing.name == room.name) {
+                    Some(existing) => *existing = room,
+                    None => rooms.push(room),
+                }
+            }
+        }
+    }
+    
+    Ok(rooms)
+}
+
+/**
+ * 解析各链的RPC地址，格式为 chain_id:url,chain_id:url
+ */
+fn parse_rpc_urls(raw: &str) -> HashMap<u64, String> {
+    raw.split(',')
+        .filter_map(|entry| {
+            let (chain_id, url) = entry.split_once(':')?;
+            let chain_id = chain_id.trim().parse().ok()?;
+            let url = url.trim();
+            if url.is_empty() {
+                return None;
+            }
+            Some((chain_id, url.to_string()))
+        })
+        .collect()
+}
+
+/**
+ * 解析各池子的Swap美元阈值，格式为 0xabc...:50000,0xdef...:0
+ * 地址统一转为小写
+ */
+fn parse_pool_thresholds(raw: &str) -> HashMap<String, f64> {
+    raw.split(',')
+        .filter_map(|entry| {
+            let (address, threshold) = entry.split_once(':')?;
+            let address = address.trim();
+            let threshold: f64 = threshold.trim().parse().ok()?;
+            if address.is_empty() || threshold < 0.0 {
+                return None;
+            }
+            Some((address.to_lowercase(), threshold))
+        })
+        .collect()
+}
+
+/**
+ * 解析池子/token到房间的映射，格式为 0xabc...:room,0xdef...:room
+ * 地址统一转为小写
+ */
+fn parse_pool_room_map(raw: &str) -> HashMap<String, String> {
+    raw.split(',')
+        .filter_map(|entry| {
+            let (address, room) = entry.split_once(':')?;
+            let (address, room) = (address.trim(), room.trim());
+            if address.is_empty() || room.is_empty() {
+                return None;
+            }
+            Some((address.to_lowercase(), room.to_string()))
+        })
+        .collect()
 }
\ No newline at end of file
@@ -0,0 +1,120 @@
+use crate::error::{AppError, Result};
+use regex::{Regex, RegexBuilder};
+
+/**
+ * 消息内容过滤器
+ * 在消息广播前调用，可返回处理后的文本或拒绝消息
+ */
+pub trait ContentFilter: Send + Sync {
+    fn filter(&self, text: &str) -> Result<String>;
+}
+
+/**
+ * 命中屏蔽词时的处理方式
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterMode {
+    Reject, // 拒绝整条消息
+    Mask,   // 将命中的内容替换为星号
+}
+
+impl FilterMode {
+    /**
+     * 从配置字符串解析，无法识别时按拒绝处理
+     */
+    pub fn parse(value: &str) -> Self {
+        match value.trim().to_lowercase().as_str() {
+            "mask" => FilterMode::Mask,
+            _ => FilterMode::Reject,
+        }
+    }
+}
+
+/**
+ * 基于屏蔽词列表的默认过滤器，匹配时忽略大小写
+ */
+pub struct WordListFilter {
+    pattern: Option<Regex>,
+    mode: FilterMode,
+}
+
+impl WordListFilter {
+    /**
+     * 根据屏蔽词列表创建过滤器，列表为空时不做任何过滤
+     */
+    pub fn new(words: &[String], mode: FilterMode) -> Result<Self> {
+        let alternatives: Vec<String> = words
+            .iter()
+            .map(|word| word.trim())
+            .filter(|word| !word.is_empty())
+            .map(regex::escape)
+            .collect();
+
+        let pattern = if alternatives.is_empty() {
+            None
+        } else {
+            let regex = RegexBuilder::new(&alternatives.join("|"))
+                .case_insensitive(true)
+                .build()
+                .map_err(|e| AppError::InternalError(format!("Invalid blocklist: {}", e)))?;
+            Some(regex)
+        };
+
+        Ok(Self { pattern, mode })
+    }
+}
+
+impl ContentFilter for WordListFilter {
+    fn filter(&self, text: &str) -> Result<String> {
+        let pattern = match &self.pattern {
+            Some(pattern) => pattern,
+            None => return Ok(text.to_string()),
+        };
+
+        match self.mode {
+            FilterMode::Reject => {
+                if pattern.is_match(text) {
+                    return Err(AppError::InvalidRequest(
+                        "Message contains disallowed content".to_string(),
+                    ));
+                }
+                Ok(text.to_string())
+            }
+            FilterMode::Mask => Ok(pattern
+                .replace_all(text, |caps: &regex::Captures| "*".repeat(caps[0].chars().count()))
+                .into_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocklist() -> Vec<String> {
+        vec!["scam".to_string(), "rug pull".to_string()]
+    }
+
+    #[test]
+    fn reject_mode_refuses_blocked_words() {
+        let filter = WordListFilter::new(&blocklist(), FilterMode::Reject).unwrap();
+
+        assert!(matches!(filter.filter("this is a SCAM"), Err(AppError::InvalidRequest(_))));
+        assert!(filter.filter("looks like a rug pull").is_err());
+        assert_eq!(filter.filter("gm everyone").unwrap(), "gm everyone");
+    }
+
+    #[test]
+    fn mask_mode_replaces_blocked_words() {
+        let filter = WordListFilter::new(&blocklist(), FilterMode::Mask).unwrap();
+
+        assert_eq!(filter.filter("Scam alert: rug pull").unwrap(), "**** alert: ********");
+        assert_eq!(filter.filter("gm everyone").unwrap(), "gm everyone");
+    }
+
+    #[test]
+    fn empty_blocklist_allows_everything() {
+        let filter = WordListFilter::new(&[" ".to_string()], FilterMode::Reject).unwrap();
+        assert_eq!(filter.filter("scam").unwrap(), "scam");
+    }
+}
@@ -45,15 +45,62 @@ pub enum AppError {
     #[error("Bad request: {0}")]
     BadRequest(String),
     
+    #[error("Room is full: {0}")]
+    RoomFull(String),
+    
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    
+    #[error("Not found: {0}")]
+    NotFound(String),
+    
+    #[error("Too many connections: {0}")]
+    TooManyConnections(String),
+    
+    #[error("Too many requests: {0}")]
+    TooManyRequests(String),
+    
+    #[error("Service unavailable: {0}")]
+    ServiceUnavailable(String),
+    
     #[error("Internal server error: {0}")]
     InternalError(String),
 }
 
+impl AppError {
+    /**
+     * 稳定的机器可读错误码，客户端应根据错误码而不是错误信息判断错误类型
+     */
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::AuthenticationFailed(_) => "AUTHENTICATION_FAILED",
+            AppError::AuthorizationFailed(_) => "AUTHORIZATION_FAILED",
+            AppError::InvalidSignature => "INVALID_SIGNATURE",
+            AppError::InvalidNonce => "INVALID_NONCE",
+            AppError::TokenGateFailed(_) => "TOKEN_GATE_FAILED",
+            AppError::DatabaseError(_) => "DATABASE_ERROR",
+            AppError::BlockchainError(_) => "BLOCKCHAIN_ERROR",
+            AppError::WebSocketError(_) => "WEBSOCKET_ERROR",
+            AppError::SerializationError(_) => "SERIALIZATION_ERROR",
+            AppError::InvalidRequest(_) => "INVALID_REQUEST",
+            AppError::BadRequest(_) => "BAD_REQUEST",
+            AppError::RoomFull(_) => "ROOM_FULL",
+            AppError::Conflict(_) => "CONFLICT",
+            AppError::NotFound(_) => "NOT_FOUND",
+            AppError::TooManyConnections(_) => "TOO_MANY_CONNECTIONS",
+            AppError::TooManyRequests(_) => "RATE_LIMITED",
+            AppError::ServiceUnavailable(_) => "SERVICE_UNAVAILABLE",
+            AppError::InternalError(_) => "INTERNAL_ERROR",
+        }
+    }
+}
+
 /**
  * 将AppError转换为HTTP响应
  */
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
+        let code = self.code();
         let (status, error_message) = match self {
             AppError::AuthenticationFailed(_) => (StatusCode::UNAUTHORIZED, self.to_string()),
             AppError::AuthorizationFailed(_) => (StatusCode::FORBIDDEN, self.to_string()),
@@ -62,11 +109,18 @@ impl IntoResponse for AppError {
             AppError::TokenGateFailed(_) => (StatusCode::FORBIDDEN, self.to_string()),
             AppError::InvalidRequest(_) => (StatusCode::BAD_REQUEST, self.to_string()),
             AppError::BadRequest(_) => (StatusCode::BAD_REQUEST, self.to_string()),
+            AppError::RoomFull(_) => (StatusCode::FORBIDDEN, self.to_string()),
+            AppError::Conflict(_) => (StatusCode::CONFLICT, self.to_string()),
+            AppError::NotFound(_) => (StatusCode::NOT_FOUND, self.to_string()),
+            AppError::TooManyConnections(_) => (StatusCode::TOO_MANY_REQUESTS, self.to_string()),
+            AppError::TooManyRequests(_) => (StatusCode::TOO_MANY_REQUESTS, self.to_string()),
+            AppError::ServiceUnavailable(_) => (StatusCode::SERVICE_UNAVAILABLE, self.to_string()),
             _ => (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error".to_string()),
         };
 
         let body = Json(json!({
             "error": error_message,
+            "code": code,
             "status": status.as_u16()
         }));
 
@@ -83,6 +137,20 @@ impl From<redis::RedisError> for AppError {
     }
 }
 
+/**
+ * 连接池获取连接超时说明服务繁忙，返回503；Redis本身的错误仍按数据库错误处理
+ */
+impl From<bb8::RunError<redis::RedisError>> for AppError {
+    fn from(err: bb8::RunError<redis::RedisError>) -> Self {
+        match err {
+            bb8::RunError::TimedOut => {
+                AppError::ServiceUnavailable("Timed out waiting for a Redis connection".to_string())
+            }
+            bb8::RunError::User(err) => AppError::DatabaseError(err.to_string()),
+        }
+    }
+}
+
 impl From<ethers::providers::ProviderError> for AppError {
     fn from(err: ethers::providers::ProviderError) -> Self {
         AppError::BlockchainError(err.to_string())
@@ -101,4 +169,72 @@ impl From<jsonwebtoken::errors::Error> for AppError {
     }
 }
 
-pub type Result<T> = std::result::Result<T, AppError>;
\ No newline at end of file
+pub type Result<T> = std::result::Result<T, AppError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn each_variant_has_a_stable_code() {
+        let s = || "x".to_string();
+        let cases = [
+            (AppError::AuthenticationFailed(s()), "AUTHENTICATION_FAILED"),
+            (AppError::AuthorizationFailed(s()), "AUTHORIZATION_FAILED"),
+            (AppError::InvalidSignature, "INVALID_SIGNATURE"),
+            (AppError::InvalidNonce, "INVALID_NONCE"),
+            (AppError::TokenGateFailed(s()), "TOKEN_GATE_FAILED"),
+            (AppError::DatabaseError(s()), "DATABASE_ERROR"),
+            (AppError::BlockchainError(s()), "BLOCKCHAIN_ERROR"),
+            (AppError::WebSocketError(s()), "WEBSOCKET_ERROR"),
+            (AppError::SerializationError(s()), "SERIALIZATION_ERROR"),
+            (AppError::InvalidRequest(s()), "INVALID_REQUEST"),
+            (AppError::BadRequest(s()), "BAD_REQUEST"),
+            (AppError::RoomFull(s()), "ROOM_FULL"),
+            (AppError::Conflict(s()), "CONFLICT"),
+            (AppError::NotFound(s()), "NOT_FOUND"),
+            (AppError::TooManyConnections(s()), "TOO_MANY_CONNECTIONS"),
+            (AppError::TooManyRequests(s()), "RATE_LIMITED"),
+            (AppError::ServiceUnavailable(s()), "SERVICE_UNAVAILABLE"),
+            (AppError::InternalError(s()), "INTERNAL_ERROR"),
+        ];
+        
+        for (error, code) in cases {
+            assert_eq!(error.code(), code);
+        }
+    }
+    
+    #[tokio::test]
+    async fn response_body_carries_code_and_status() {
+        let response = AppError::InvalidNonce.into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "INVALID_NONCE");
+        assert_eq!(body["error"], "Invalid nonce");
+        assert_eq!(body["status"], 401);
+    }
+    
+    #[tokio::test]
+    async fn exhausted_redis_pool_returns_503() {
+        let harness = crate::test_support::TestHarness::start().await;
+        let manager = bb8_redis::RedisConnectionManager::new(harness.config.redis_url.as_str()).unwrap();
+        let pool = bb8::Pool::builder()
+            .max_size(1)
+            .connection_timeout(std::time::Duration::from_millis(100))
+            .build(manager)
+            .await
+            .unwrap();
+        
+        // 唯一的连接被占用，再次获取连接会超时
+        let _held = pool.get().await.unwrap();
+        let error = match pool.get().await {
+            Ok(_) => panic!("pool should be exhausted"),
+            Err(e) => AppError::from(e),
+        };
+        
+        assert!(matches!(error, AppError::ServiceUnavailable(_)));
+        assert_eq!(error.into_response().status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -1,45 +1,100 @@
-use crate::auth::AuthService;
+use crate::audit;
+use crate::auth::{create_siwe_message, validate_token_gate};
 use crate::error::{AppError, Result};
-use crate::models::{LoginRequest, LoginResponse, NonceResponse, UserInfo};
+use crate::rate_limit;
+use crate::models::{
+    avatar_seed, AnnouncementRequest, AuditPage, AuditQuery, Claims, CreateRoomRequest, HealthQuery, LoginRequest, LoginResponse,
+    MessageHistoryQuery, NonceRequest, NonceResponse, OnlineUser, RefreshRequest, RoomConfig, RoomMember, RuntimeConfig, RuntimeConfigUpdate,
+    ServerMessage, ServerStats, TokenGate, TokenGateRequest, TokenGateResponse, TokenGateType, UserInfo,
+    UserRoomsResponse, DeniedRoom, MAINNET_CHAIN_ID,
+};
 use crate::state::AppState;
+use crate::telemetry;
 use axum::{
-    extract::State,
-    http::StatusCode,
+    async_trait,
+    extract::{rejection::JsonRejection, ConnectInfo, FromRequestParts, Query, State},
+    http::{header, request::Parts, HeaderMap, StatusCode},
     response::Json,
 };
+use ethers::types::Address;
+use ethers::utils::to_checksum;
+use std::net::SocketAddr;
+use std::str::FromStr;
 use std::sync::Arc;
 use tracing::{error, info};
 
+/// 系统公告的频率限制：每个时间窗口内的最大次数
+const ANNOUNCE_RATE_LIMIT: u32 = 5;
+
+/// 系统公告频率限制的时间窗口（毫秒）
+const ANNOUNCE_RATE_LIMIT_WINDOW_MS: u64 = 60_000;
+
+/// 房间可配置的最大消息历史条数
+const MAX_ROOM_HISTORY: usize = 10_000;
+
+/// nonce限流的时间窗口（毫秒）
+const NONCE_RATE_LIMIT_WINDOW_MS: u64 = 60_000;
+
+/// 健康检查中每项依赖检查的超时时间
+const HEALTH_CHECK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/**
+ * 已认证用户提取器
+ * 从Authorization: Bearer请求头校验JWT（包括吊销检查），失败时返回401
+ */
+pub struct AuthUser(pub UserInfo);
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for AuthUser {
+    type Rejection = AppError;
+    
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self> {
+        let claims = authenticate_bearer(state, &parts.headers).await?;
+        
+        Ok(AuthUser(UserInfo {
+            avatar: state.config.default_avatar(&claims.sub),
+            avatar_seed: avatar_seed(&claims.sub),
+            address: claims.sub,
+            ens_name: claims.ens,
+        }))
+    }
+}
+
 /**
  * 获取认证nonce
  * POST /api/auth/nonce
  */
 pub async fn get_nonce(
     State(state): State<Arc<AppState>>,
-    Json(request): Json<serde_json::Value>,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    Json(request): Json<NonceRequest>,
 ) -> Result<Json<NonceResponse>> {
-    let address = request.get("address")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| AppError::BadRequest("Missing address field".to_string()))?;
-    
-    info!("Generating new nonce for address: {}", address);
+    // 接受校验和格式或全小写的地址，统一转为校验和格式
+    let address = Address::from_str(request.address.trim())
+        .map(|address| to_checksum(&address, None))
+        .map_err(|e| AppError::InvalidRequest(format!("Invalid address: {}", e)))?;
     
-    // 创建认证服务实例
-    let jwt_secret = std::env::var("JWT_SECRET")
-        .map_err(|_| AppError::InternalError("JWT secret not configured".to_string()))?;
+    let chain_id = request.chain_id.unwrap_or(MAINNET_CHAIN_ID);
+    if !state.auth_service.supports_chain(chain_id) {
+        return Err(AppError::InvalidRequest(format!("Unsupported chain id: {}", chain_id)));
+    }
     
-    let eth_rpc_url = std::env::var("ETHEREUM_HTTP_URL")
-        .unwrap_or_else(|_| "https://mainnet.infura.io/v3/YOUR_PROJECT_ID".to_string());
+    // 按客户端IP限流，防止大量nonce写入Redis
+    // 不按请求的地址限流：地址可由任何人提交，否则他人可以持续占满受害者地址的额度阻止其登录
+    let limit = state.config.nonce_rate_limit_per_minute;
+    let ip = remote_addr.ip().to_string();
+    if !rate_limit::check_rate_limit(&state.redis_pool, "nonce_ip", &ip, limit, NONCE_RATE_LIMIT_WINDOW_MS).await? {
+        return Err(AppError::TooManyRequests(
+            "Too many nonce requests, please try again later".to_string(),
+        ));
+    }
     
-    let auth_service = AuthService::new(
-        jwt_secret,
-        state.redis_pool.clone(),
-        &eth_rpc_url,
-    )?;
+    info!("Generating new nonce for address: {}", address);
     
-    let nonce = auth_service.generate_nonce().await?;
+    let nonce = state.auth_service.generate_nonce().await?;
+    let message = create_siwe_message(&state.config, &address, &nonce, chain_id);
     
-    Ok(Json(NonceResponse { nonce }))
+    Ok(Json(NonceResponse { nonce, address, message }))
 }
 
 /**
@@ -52,26 +107,13 @@ pub async fn login(
 ) -> Result<Json<LoginResponse>> {
     info!("Processing login request");
     
-    // 创建认证服务实例
-    let jwt_secret = std::env::var("JWT_SECRET")
-        .map_err(|_| AppError::InternalError("JWT secret not configured".to_string()))?;
-    
-    let eth_rpc_url = std::env::var("ETHEREUM_HTTP_URL")
-        .unwrap_or_else(|_| "https://mainnet.infura.io/v3/YOUR_PROJECT_ID".to_string());
-    
-    let auth_service = AuthService::new(
-        jwt_secret,
-        state.redis_pool.clone(),
-        &eth_rpc_url,
-    )?;
-    
     // 验证SIWE消息和签名
-    let user_auth = auth_service
+    let user_auth = state.auth_service
         .verify_siwe_message(&request.message, &request.signature)
         .await?;
     
-    // 生成JWT token
-    let token = auth_service.generate_jwt(&user_auth)?;
+    // 生成JWT token及刷新token
+    let (token, refresh_token) = state.auth_service.issue_tokens(&user_auth).await?;
     
     // 缓存用户认证信息
     state.cache_user_auth(user_auth.address.clone(), user_auth.clone()).await;
@@ -80,43 +122,187 @@ pub async fn login(
     let user_info = UserInfo {
         address: user_auth.address.clone(),
         ens_name: user_auth.ens_name.clone(),
-        avatar: None, // 可以从ENS或其他来源获取
+        avatar: resolve_user_avatar(&state, &user_auth.address).await,
+        avatar_seed: avatar_seed(&user_auth.address),
     };
     
     info!("User {} authenticated successfully", user_auth.address);
     
     Ok(Json(LoginResponse {
         token,
+        refresh_token,
         user: user_info,
     }))
 }
 
 /**
- * 健康检查端点
- * GET /health
+ * 使用刷新token换取新的访问token，刷新token同时轮换
+ * POST /api/auth/refresh
+ */
+pub async fn refresh_token(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<RefreshRequest>,
+) -> Result<Json<LoginResponse>> {
+    let (user_auth, token, refresh_token) = state.auth_service
+        .refresh_session(&request.refresh_token)
+        .await?;
+    
+    info!("Refreshed session for {}", user_auth.address);
+    audit::log_event(
+        &state.redis_pool,
+        "refresh_session",
+        &user_auth.address,
+        "success",
+        serde_json::json!({}),
+    ).await;
+    
+    Ok(Json(LoginResponse {
+        token,
+        refresh_token,
+        user: UserInfo {
+            avatar: resolve_user_avatar(&state, &user_auth.address).await,
+            avatar_seed: avatar_seed(&user_auth.address),
+            address: user_auth.address,
+            ens_name: user_auth.ens_name,
+        },
+    }))
+}
+
+/**
+ * 用户登出，吊销当前token及与其一同签发的刷新token
+ * POST /api/auth/logout
+ */
+pub async fn logout(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    headers: HeaderMap,
+) -> Result<StatusCode> {
+    let token = bearer_token(&headers)?;
+    
+    state.auth_service.revoke_jwt(token).await?;
+    
+    info!("User {} logged out", user.address);
+    
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/**
+ * 健康检查端点，检查Redis和以太坊RPC的连通性
+ * 任一依赖不可用时返回503；deep=false时只作存活检查
+ * GET /health?deep=false
+ */
+pub async fn health_check(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<HealthQuery>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if !query.deep.unwrap_or(true) {
+        return (StatusCode::OK, Json(serde_json::json!({ "status": "ok" })));
+    }
+    
+    let redis = match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, ping_redis(&state)).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("timed out".to_string()),
+    };
+    
+    let rpc = match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, state.auth_service.check_rpc()).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("timed out".to_string()),
+    };
+    
+    let healthy = redis.is_ok() && rpc.is_ok();
+    let status = if healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    
+    if !healthy {
+        error!("Health check failed: redis={:?}, rpc={:?}", redis, rpc);
+    }
+    
+    let describe = |result: &std::result::Result<(), String>| match result {
+        Ok(()) => "ok".to_string(),
+        Err(e) => format!("error: {}", e),
+    };
+    
+    (status, Json(serde_json::json!({
+        "status": if healthy { "ok" } else { "unavailable" },
+        "redis": describe(&redis),
+        "rpc": describe(&rpc),
+    })))
+}
+
+/**
+ * Prometheus指标端点，仅在METRICS_ENABLED时注册
+ * GET /metrics
+ */
+pub async fn metrics() -> Result<String> {
+    telemetry::render().ok_or_else(|| AppError::NotFound("Metrics are disabled".to_string()))
+}
+
+/**
+ * 获取Redis连接并执行PING
  */
-pub async fn health_check() -> StatusCode {
-    StatusCode::OK
+async fn ping_redis(state: &AppState) -> Result<()> {
+    let mut conn = state.redis_pool.get().await?;
+    let _: String = redis::cmd("PING").query_async(&mut *conn).await?;
+    Ok(())
 }
 
 /**
- * 获取用户信息
- * GET /api/user/:address
+ * 获取当前登录用户的信息
+ * GET /api/user/info
  */
 pub async fn get_user_info(
     State(state): State<Arc<AppState>>,
-    axum::extract::Path(address): axum::extract::Path<String>,
+    AuthUser(mut user): AuthUser,
 ) -> Result<Json<UserInfo>> {
-    // 从缓存中获取用户信息
-    if let Some(user_auth) = state.get_cached_user_auth(&address).await {
-        return Ok(Json(UserInfo {
-            address: user_auth.address,
-            ens_name: user_auth.ens_name,
-            avatar: None,
-        }));
+    user.avatar = resolve_user_avatar(&state, &user.address).await;
+    Ok(Json(user))
+}
+
+/**
+ * 获取当前用户可以进入的房间，受Token门禁保护的房间逐个校验持仓
+ * 校验通过的结果与加入房间共用缓存
+ * GET /api/user/rooms
+ */
+pub async fn get_user_rooms(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+) -> Result<Json<UserRoomsResponse>> {
+    let mut response = UserRoomsResponse {
+        accessible: Vec::new(),
+        denied: Vec::new(),
+    };
+    
+    for room in state.list_room_names().await? {
+        match state.check_room_token_gate(&user.address, &room).await {
+            Ok(()) => response.accessible.push(room),
+            Err(AppError::TokenGateFailed(reason)) => response.denied.push(DeniedRoom { room, reason }),
+            Err(e) => {
+                error!("Failed to check token gate of room {} for {}: {}", room, user.address, e);
+                response.denied.push(DeniedRoom {
+                    room,
+                    reason: "Token gate check unavailable".to_string(),
+                });
+            }
+        }
     }
     
-    Err(AppError::InvalidRequest("User not found".to_string()))
+    Ok(Json(response))
+}
+
+/**
+ * 获取用户头像，优先使用ENS avatar记录，解析失败时使用默认头像
+ */
+async fn resolve_user_avatar(state: &AppState, user_address: &str) -> Option<String> {
+    let ens_avatar = match Address::from_str(user_address) {
+        Ok(address) => state.auth_service.resolve_avatar(&address).await.unwrap_or_else(|e| {
+            error!("Failed to resolve ENS avatar for {}: {}", user_address, e);
+            None
+        }),
+        Err(_) => None,
+    };
+    
+    ens_avatar.or_else(|| state.config.default_avatar(user_address))
 }
 
 /**
@@ -128,14 +314,101 @@ pub async fn get_room_info(
     axum::extract::Path(room_name): axum::extract::Path<String>,
 ) -> Result<Json<serde_json::Value>> {
     let users = state.get_room_users(&room_name).await;
+    let topic = state.get_room_topic(&room_name).await;
     
     Ok(Json(serde_json::json!({
         "name": room_name,
+        "topic": topic,
         "users": users,
         "user_count": users.len()
     })))
 }
 
+/**
+ * 获取房间的在线用户（当前有连接的成员），包含ENS名称和头像
+ * 与加入房间一致，受Token门禁保护的房间需先通过校验
+ * GET /api/rooms/:room_name/online
+ */
+pub async fn get_room_online_users(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    axum::extract::Path(room_name): axum::extract::Path<String>,
+) -> Result<Json<Vec<OnlineUser>>> {
+    state.check_room_token_gate(&user.address, &room_name).await?;
+    
+    if !state.rooms.read().await.contains_key(&room_name) {
+        return Err(AppError::NotFound(format!("Room not found: {}", room_name)));
+    }
+    
+    Ok(Json(state.get_online_users(&room_name).await))
+}
+
+/**
+ * 获取房间成员：在线用户和最近离开的用户，离线成员带有最后在线时间
+ * 与在线用户接口一致，受Token门禁保护的房间需先通过校验
+ * GET /api/rooms/:room_name/members
+ */
+pub async fn get_room_members(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    axum::extract::Path(room_name): axum::extract::Path<String>,
+) -> Result<Json<Vec<RoomMember>>> {
+    state.check_room_token_gate(&user.address, &room_name).await?;
+    
+    if !state.rooms.read().await.contains_key(&room_name) {
+        return Err(AppError::NotFound(format!("Room not found: {}", room_name)));
+    }
+    
+    Ok(Json(state.get_room_members(&room_name).await?))
+}
+
+/**
+ * 删除房间，仅房间创建者或管理员可操作
+ * DELETE /api/rooms/:room_name
+ */
+pub async fn delete_room(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    axum::extract::Path(room_name): axum::extract::Path<String>,
+) -> Result<StatusCode> {
+    state.delete_room(&room_name, &user.address).await?;
+    
+    info!("Room {} deleted", room_name);
+    audit::log_event(
+        &state.redis_pool,
+        "delete_room",
+        &user.address,
+        "success",
+        serde_json::json!({ "room": room_name }),
+    ).await;
+    
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/**
+ * 获取房间的聊天消息历史
+ * GET /api/rooms/:room_id/messages?limit=50&before=<id>
+ */
+pub async fn get_room_messages(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    axum::extract::Path(room_name): axum::extract::Path<String>,
+    Query(query): Query<MessageHistoryQuery>,
+) -> Result<Json<Vec<ServerMessage>>> {
+    // 与WebSocket加入房间一致，受Token门禁保护的房间需先通过校验
+    state.check_room_token_gate(&user.address, &room_name).await?;
+    
+    // 条数上限由get_room_messages统一限制
+    let limit = query.limit.unwrap_or(state.config.history_limit);
+    
+    let messages = state
+        .get_room_messages(&room_name, limit, query.before.as_deref())
+        .await
+        .ok_or_else(|| AppError::NotFound(format!("Room not found: {}", room_name)))?;
+    
+    Ok(Json(messages))
+}
+
 /**
  * 获取所有房间列表
  * GET /api/rooms
@@ -145,64 +418,281 @@ pub async fn get_rooms(
 ) -> Result<Json<Vec<serde_json::Value>>> {
     let rooms = state.rooms.read().await;
     let room_list: Vec<serde_json::Value> = rooms
-        .iter()
-        .map(|(name, room)| {
-            serde_json::json!({
-                "name": name,
-                "user_count": room.users.len(),
-                "users": room.users.iter().collect::<Vec<_>>()
-            })
-        })
+        .values()
+        .map(|room| room.to_json(state.config.default_room_max_users))
         .collect();
     
     Ok(Json(room_list))
 }
 
+/**
+ * 获取服务运行状态
+ * GET /api/stats
+ */
+pub async fn get_stats(
+    State(state): State<Arc<AppState>>,
+) -> Json<ServerStats> {
+    Json(state.stats().await)
+}
+
+/**
+ * 创建房间（支持Token门禁）
+ * POST /api/rooms
+ */
+pub async fn create_room(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    Json(request): Json<CreateRoomRequest>,
+) -> Result<(StatusCode, Json<serde_json::Value>)> {
+    let name = request.name.trim().to_string();
+    validate_room_name(&name)?;
+    
+    if let Some(gate) = &request.token_gate {
+        validate_token_gate(gate)?;
+        
+        if !state.auth_service.supports_chain(gate.chain_id) {
+            return Err(AppError::InvalidRequest(format!("Unsupported chain id: {}", gate.chain_id)));
+        }
+    }
+    
+    if request.max_users == Some(0) {
+        return Err(AppError::InvalidRequest("max_users must be greater than 0".to_string()));
+    }
+    
+    if request.max_history.is_some_and(|max_history| max_history > MAX_ROOM_HISTORY) {
+        return Err(AppError::InvalidRequest(format!(
+            "max_history must be at most {}",
+            MAX_ROOM_HISTORY
+        )));
+    }
+    
+    let config = RoomConfig {
+        name: name.clone(),
+        description: request.description,
+        token_gate: request.token_gate,
+        max_users: request.max_users,
+        max_history: request.max_history,
+        slow_mode_secs: None,
+        created_at: chrono::Utc::now(),
+        created_by: user.address,
+    };
+    
+    if !state.create_room(config).await? {
+        return Err(AppError::Conflict(format!("Room already exists: {}", name)));
+    }
+    
+    info!("Room {} created", name);
+    
+    let rooms = state.rooms.read().await;
+    let room = rooms
+        .get(&name)
+        .ok_or_else(|| AppError::InternalError("Room missing after creation".to_string()))?;
+    
+    Ok((StatusCode::CREATED, Json(room.to_json(state.config.default_room_max_users))))
+}
+
+/**
+ * 校验房间名称
+ */
+fn validate_room_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > 64 {
+        return Err(AppError::InvalidRequest("Room name must be 1-64 characters".to_string()));
+    }
+    
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(AppError::InvalidRequest(
+            "Room name may only contain letters, digits, '-' and '_'".to_string(),
+        ));
+    }
+    
+    Ok(())
+}
+
 /**
  * 验证token门禁
  * POST /api/verify-token-gate
  */
 pub async fn verify_token_gate(
     State(state): State<Arc<AppState>>,
-    Json(request): Json<serde_json::Value>,
-) -> Result<Json<serde_json::Value>> {
-    let user_address = request["user_address"]
-        .as_str()
-        .ok_or_else(|| AppError::InvalidRequest("Missing user_address".to_string()))?;
+    request: std::result::Result<Json<TokenGateRequest>, JsonRejection>,
+) -> Result<Json<TokenGateResponse>> {
+    // 请求体格式错误统一返回400
+    let Json(request) = request.map_err(|e| AppError::InvalidRequest(e.body_text()))?;
     
-    let contract_address = request["contract_address"]
-        .as_str()
-        .ok_or_else(|| AppError::InvalidRequest("Missing contract_address".to_string()))?;
+    let address = Address::from_str(request.user_address.trim())
+        .map_err(|e| AppError::InvalidRequest(format!("Invalid user_address: {}", e)))?;
     
-    let minimum_balance = request["minimum_balance"].as_str();
+    let gate = TokenGate {
+        gate_type: request.gate_type.unwrap_or(TokenGateType::ERC20),
+        contract_address: request.contract_address,
+        minimum_balance: request.minimum_balance,
+        token_ids: request.token_ids,
+        chain_id: request.chain_id.unwrap_or(MAINNET_CHAIN_ID),
+    };
+    validate_token_gate(&gate)?;
     
-    // 创建认证服务实例
-    let jwt_secret = std::env::var("JWT_SECRET")
-        .map_err(|_| AppError::InternalError("JWT secret not configured".to_string()))?;
+    if !state.auth_service.supports_chain(gate.chain_id) {
+        return Err(AppError::InvalidRequest(format!("Unsupported chain id: {}", gate.chain_id)));
+    }
     
-    let eth_rpc_url = std::env::var("ETHEREUM_HTTP_URL")
-        .unwrap_or_else(|_| "https://mainnet.infura.io/v3/YOUR_PROJECT_ID".to_string());
+    // 检查token门禁
+    let has_access = state.auth_service
+        .check_token_gate(&address, &gate)
+        .await?;
     
-    let auth_service = AuthService::new(
-        jwt_secret,
-        state.redis_pool.clone(),
-        &eth_rpc_url,
-    )?;
+    Ok(Json(TokenGateResponse {
+        has_access,
+        user_address: to_checksum(&address, None),
+        contract_address: gate.contract_address,
+        gate_type: gate.gate_type,
+        chain_id: gate.chain_id,
+    }))
+}
+
+/**
+ * 获取客户端可见的运行时配置
+ * GET /api/config
+ */
+pub async fn get_config(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<RuntimeConfig>> {
+    Ok(Json(state.get_runtime_config().await))
+}
+
+/**
+ * 更新运行时配置（仅管理员），并广播给所有在线客户端
+ * PUT /api/admin/config
+ */
+pub async fn update_config(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(update): Json<RuntimeConfigUpdate>,
+) -> Result<Json<RuntimeConfig>> {
+    let admin = authorize_admin(&state, &headers).await?;
     
-    // 解析用户地址
-    let address = user_address.parse()
-        .map_err(|e| AppError::InvalidRequest(format!("Invalid address: {}", e)))?;
+    info!("Admin {} updating runtime config: {:?}", admin, update);
     
-    // 检查token门禁
-    let has_access = auth_service
-        .check_token_gate(&address, contract_address, minimum_balance)
-        .await?;
+    let details = serde_json::json!({ "update": format!("{:?}", update) });
+    let updated = state.update_runtime_config(update).await;
+    audit::log_event(&state.redis_pool, "update_config", &admin, "success", details).await;
     
-    Ok(Json(serde_json::json!({
-        "has_access": has_access,
-        "user_address": user_address,
-        "contract_address": contract_address
-    })))
+    Ok(Json(updated))
+}
+
+/**
+ * 向所有在线用户广播系统公告，需要在X-Admin-Token中携带ADMIN_TOKEN
+ * POST /api/admin/announce
+ */
+pub async fn announce(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(request): Json<AnnouncementRequest>,
+) -> Result<StatusCode> {
+    authorize_admin_token(&state, &headers)?;
+    
+    let text = request.text.trim().to_string();
+    if text.is_empty() {
+        return Err(AppError::InvalidRequest("Announcement cannot be empty".to_string()));
+    }
+    
+    if !rate_limit::check_rate_limit(
+        &state.redis_pool,
+        "announce",
+        "admin",
+        ANNOUNCE_RATE_LIMIT,
+        ANNOUNCE_RATE_LIMIT_WINDOW_MS,
+    ).await? {
+        return Err(AppError::TooManyRequests("Too many announcements".to_string()));
+    }
+    
+    info!("Broadcasting announcement: {}", text);
+    audit::log_event(
+        &state.redis_pool,
+        "announce",
+        "admin_token",
+        "success",
+        serde_json::json!({ "text": text }),
+    ).await;
+    
+    state.broadcast_global(ServerMessage::Announcement {
+        text,
+        timestamp: chrono::Utc::now(),
+    }).await;
+    
+    Ok(StatusCode::ACCEPTED)
+}
+
+/**
+ * 校验请求携带的运维密钥，未配置ADMIN_TOKEN时拒绝所有请求
+ */
+fn authorize_admin_token(state: &AppState, headers: &HeaderMap) -> Result<()> {
+    let provided = headers
+        .get("x-admin-token")
+        .and_then(|v| v.to_str().ok());
+    
+    match (state.config.admin_token.as_deref(), provided) {
+        (Some(expected), Some(provided)) if constant_time_eq(expected.as_bytes(), provided.as_bytes()) => Ok(()),
+        _ => Err(AppError::AuthorizationFailed("Invalid admin token".to_string())),
+    }
+}
+
+/**
+ * 比较两个字节串，耗时与内容无关，避免通过时间差猜测密钥
+ */
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/**
+ * 查询审计日志（仅管理员）
+ * GET /api/admin/audit?action=&actor=&target=&since=&cursor=&limit=
+ */
+pub async fn get_audit_log(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<AuditPage>> {
+    let admin = authorize_admin(&state, &headers).await?;
+    
+    info!("Admin {} querying audit log: {:?}", admin, query);
+    
+    let page = audit::query_audit_log(&state.redis_pool, &query).await?;
+    
+    Ok(Json(page))
+}
+
+/**
+ * 从请求头中提取bearer token
+ */
+fn bearer_token(headers: &HeaderMap) -> Result<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or_else(|| AppError::AuthenticationFailed("Missing bearer token".to_string()))
+}
+
+/**
+ * 校验请求携带的JWT bearer token，返回其中的声明
+ */
+async fn authenticate_bearer(state: &AppState, headers: &HeaderMap) -> Result<Claims> {
+    state.auth_service.verify_jwt(bearer_token(headers)?).await
+}
+
+/**
+ * 校验请求携带的JWT属于管理员，返回管理员地址
+ */
+async fn authorize_admin(state: &AppState, headers: &HeaderMap) -> Result<String> {
+    let claims = authenticate_bearer(state, headers).await?;
+    
+    if !state.config.is_admin(&claims.sub) {
+        return Err(AppError::AuthorizationFailed("Admin privileges required".to_string()));
+    }
+    
+    Ok(claims.sub)
 }
 
 /**
@@ -211,4 +701,208 @@ pub async fn verify_token_gate(
 pub async fn handle_error(err: Box<dyn std::error::Error + Send + Sync>) -> AppError {
     error!("Unhandled error: {}", err);
     AppError::InternalError("Internal server error".to_string())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{serve_rpc_result, TestHarness, TEST_PRIVATE_KEY};
+    use ethers::signers::{LocalWallet, Signer};
+    
+    #[tokio::test]
+    async fn shared_auth_service_serves_nonces_for_login() {
+        let harness = TestHarness::start().await;
+        let state = harness.app_state();
+        let wallet: LocalWallet = TEST_PRIVATE_KEY.parse().unwrap();
+        let client = ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000)));
+        
+        let request = || Json(NonceRequest {
+            address: format!("{:?}", wallet.address()),
+            chain_id: None,
+        });
+        let Json(first) = get_nonce(State(state.clone()), client, request()).await.unwrap();
+        let Json(second) = get_nonce(State(state.clone()), client, request()).await.unwrap();
+        assert_ne!(first.nonce, second.nonce);
+        assert!(first.message.contains(&format!("Nonce: {}", first.nonce)));
+        
+        // 同一个共享实例签发的nonce可以用于登录
+        let signature = wallet.sign_message(&first.message).await.unwrap();
+        let Json(response) = login(
+            State(state.clone()),
+            Json(LoginRequest {
+                message: first.message,
+                signature: format!("0x{}", hex::encode(signature.to_vec())),
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.user.address, to_checksum(&wallet.address(), None));
+    }
+    
+    #[tokio::test]
+    async fn nonce_requests_are_limited_per_client_ip() {
+        let mut harness = TestHarness::start().await;
+        harness.config.nonce_rate_limit_per_minute = 2;
+        let state = harness.app_state();
+        let victim = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        let attacker = ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 40000)));
+        let owner = ConnectInfo(SocketAddr::from(([10, 0, 0, 2], 40000)));
+        
+        let request = || Json(NonceRequest {
+            address: victim.to_string(),
+            chain_id: None,
+        });
+        for _ in 0..2 {
+            get_nonce(State(state.clone()), attacker, request()).await.unwrap();
+        }
+        for _ in 0..3 {
+            assert!(matches!(
+                get_nonce(State(state.clone()), attacker, request()).await,
+                Err(AppError::TooManyRequests(_))
+            ));
+        }
+        
+        // 其他IP请求同一地址的nonce不受影响
+        get_nonce(State(state.clone()), owner, request()).await.unwrap();
+    }
+    
+    #[tokio::test]
+    async fn nonce_addresses_are_normalized_to_checksum() {
+        let harness = TestHarness::start().await;
+        let state = harness.app_state();
+        let client = ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 40000)));
+        let checksum = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        let request = |address: &str| Json(NonceRequest {
+            address: address.to_string(),
+            chain_id: None,
+        });
+        
+        let response = get_nonce(State(state.clone()), client, request(checksum)).await.unwrap();
+        assert_eq!(response.address, checksum);
+        assert!(response.message.contains(checksum));
+        
+        // 全小写地址被转换为校验和格式
+        let response = get_nonce(State(state.clone()), client, request(&checksum.to_lowercase())).await.unwrap();
+        assert_eq!(response.address, checksum);
+        assert!(response.message.contains(checksum));
+        
+        for malformed in ["0x1234", "not-an-address", ""] {
+            assert!(matches!(
+                get_nonce(State(state.clone()), client, request(malformed)).await,
+                Err(AppError::InvalidRequest(_))
+            ));
+        }
+    }
+    
+    #[tokio::test]
+    async fn online_users_list_connected_members_with_ens_names() {
+        let harness = TestHarness::start().await;
+        let state = harness.app_state();
+        let alice = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        let bob = "0xFFcf8FDEE72ac11b5c542428B35EEF5769C409f0";
+        
+        state.add_client(alice.to_string(), Some("alice.eth".to_string())).await.unwrap();
+        state.add_client(bob.to_string(), None).await.unwrap();
+        assert!(state.join_room(alice, "general").await);
+        assert!(state.join_room(bob, "general").await);
+        
+        // 断开连接的成员不再出现在在线列表中
+        state.remove_client(bob).await;
+        
+        let viewer = AuthUser(UserInfo {
+            address: alice.to_string(),
+            ens_name: Some("alice.eth".to_string()),
+            avatar: None,
+            avatar_seed: avatar_seed(alice),
+        });
+        let Json(users) = get_room_online_users(
+            State(state.clone()),
+            viewer,
+            axum::extract::Path("general".to_string()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].address, alice);
+        assert_eq!(users[0].ens_name.as_deref(), Some("alice.eth"));
+    }
+    
+    #[tokio::test]
+    async fn announcements_require_the_admin_token() {
+        let mut harness = TestHarness::start().await;
+        harness.config.admin_token = Some("s3cret".to_string());
+        let state = harness.app_state();
+        let mut receiver = state.global_sender.subscribe();
+        
+        let headers = |token: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(token) = token {
+                headers.insert("x-admin-token", token.parse().unwrap());
+            }
+            headers
+        };
+        let request = |text: &str| Json(AnnouncementRequest { text: text.to_string() });
+        
+        for token in [None, Some(""), Some("wrong"), Some("s3cret ")] {
+            assert!(matches!(
+                announce(State(state.clone()), headers(token), request("maintenance at noon")).await,
+                Err(AppError::AuthorizationFailed(_))
+            ));
+        }
+        assert!(matches!(
+            announce(State(state.clone()), headers(Some("s3cret")), request("   ")).await,
+            Err(AppError::InvalidRequest(_))
+        ));
+        assert!(receiver.try_recv().is_err());
+        
+        let status = announce(State(state.clone()), headers(Some("s3cret")), request("maintenance at noon")).await.unwrap();
+        assert_eq!(status, StatusCode::ACCEPTED);
+        match receiver.try_recv().unwrap() {
+            ServerMessage::Announcement { text, .. } => assert_eq!(text, "maintenance at noon"),
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn user_rooms_split_accessible_and_denied() {
+        let mut harness = TestHarness::start().await;
+        // 节点对balanceOf返回500
+        let balance = ethers::abi::encode(&[ethers::abi::Token::Uint(ethers::types::U256::from(500))]);
+        harness.config.ethereum_http_url = serve_rpc_result(balance.into()).await;
+        let state = harness.app_state();
+        let address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        
+        for (room, minimum_balance) in [("holders", "100"), ("whales", "1000")] {
+            assert!(state.create_room(RoomConfig {
+                name: room.to_string(),
+                description: None,
+                token_gate: Some(TokenGate {
+                    gate_type: TokenGateType::ERC20,
+                    contract_address: "0x6B175474E89094C44Da98b954EedeAC495271d0F".to_string(),
+                    minimum_balance: Some(minimum_balance.to_string()),
+                    token_ids: None,
+                    chain_id: MAINNET_CHAIN_ID,
+                }),
+                max_users: None,
+                max_history: None,
+                slow_mode_secs: None,
+                created_at: chrono::Utc::now(),
+                created_by: address.to_string(),
+            }).await.unwrap());
+        }
+        
+        let user = AuthUser(UserInfo {
+            address: address.to_string(),
+            ens_name: None,
+            avatar: None,
+            avatar_seed: avatar_seed(address),
+        });
+        let Json(response) = get_user_rooms(State(state.clone()), user).await.unwrap();
+        
+        assert!(response.accessible.contains(&"holders".to_string()));
+        assert!(response.accessible.contains(&state.config.default_room));
+        assert_eq!(response.denied.len(), 1);
+        assert_eq!(response.denied[0].room, "whales");
+        assert!(response.denied[0].reason.contains("0x6B175474E89094C44Da98b954EedeAC495271d0F"));
+    }
+}
@@ -1,29 +1,42 @@
 use anyhow::Result;
 use axum::{
-    extract::{State, WebSocketUpgrade},
-    http::StatusCode,
-    response::Response,
-    routing::{get, post},
+    extract::{Query, Request, State, WebSocketUpgrade},
+    http::{header, HeaderMap, HeaderValue, Method},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
     Router,
 };
+use tower::ServiceBuilder;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
 use tower_http::services::ServeDir;
+use tower_http::trace::TraceLayer;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::net::TcpListener;
 use tower_http::cors::CorsLayer;
 use tracing::{info, warn, error};
-use std::env;
 
+mod audit;
 mod auth;
 mod blockchain;
 mod config;
+mod content_filter;
 mod error;
 mod handlers;
 mod models;
+mod pubsub;
+mod rate_limit;
+mod retry;
 mod state;
+mod telemetry;
 mod websocket;
 
+#[cfg(test)]
+mod test_support;
+
 use auth::AuthService;
 use config::Config;
+use content_filter::WordListFilter;
 use state::AppState;
 
 /**
@@ -32,76 +45,139 @@ use state::AppState;
  */
 #[tokio::main]
 async fn main() -> Result<()> {
-    // 初始化日志
-    tracing_subscriber::fmt::init();
-    
     // 加载配置
     dotenv::dotenv().ok();
     let config = Config::from_env()?;
     
+    // 初始化日志，审计日志的输出位置由配置决定
+    init_tracing(&config)?;
+    
     info!("Starting ChainTalk server...");
     
+    // 启用Prometheus指标导出
+    if config.metrics_enabled {
+        telemetry::install()?;
+        info!("Prometheus metrics enabled at /metrics");
+    }
+    
     // 初始化Redis连接池
-    let redis_pool = create_redis_pool(&config.redis_url).await?;
+    let redis_pool = create_redis_pool(&config).await?;
     
     // 创建认证服务
-    let auth_service = AuthService::new(
-        config.jwt_secret.clone(),
-        redis_pool.clone(),
-        &config.ethereum_http_url,
-    )?;
+    let auth_service = AuthService::new(&config, redis_pool.clone())?;
+    
+    // 创建消息内容过滤器，可替换为其他ContentFilter实现
+    let content_filter = Arc::new(WordListFilter::new(&config.blocklist, config.filter_mode)?);
     
     // 创建应用状态
-    let app_state = Arc::new(AppState::new(redis_pool, auth_service));
+    let app_state = Arc::new(AppState::new(redis_pool, auth_service, config.clone(), content_filter));
     
-    // 启动区块链监听器 (暂时禁用以避免API限制)
-    let blockchain_listener = blockchain::BlockchainListener::new(
-        &config.ethereum_ws_url,
-        app_state.clone(),
-    ).await?;
+    // 启动Redis订阅者，接收其他实例的广播
+    tokio::spawn(pubsub::run_subscriber(app_state.clone()));
     
-    tokio::spawn(async move {
-        if let Err(e) = blockchain_listener.start().await {
-            warn!("Blockchain listener error: {}", e);
-        }
-    });
-    
-    if let (Ok(ws_url), Ok(_http_url)) = (
-        env::var("ETHEREUM_WS_URL"),
-        env::var("ETHEREUM_HTTP_URL")
-    ) {
-        info!("🔗 Blockchain listener configured but disabled for demo");
-        info!("   To enable: Set valid ETHEREUM_WS_URL and ETHEREUM_HTTP_URL in .env");
-        info!("   Example: ETHEREUM_WS_URL=wss://mainnet.infura.io/ws/v3/YOUR_PROJECT_ID");
-        let blockchain_listener = blockchain::BlockchainListener::new(&ws_url, app_state.clone()).await?;
-        let _listener_handle = tokio::spawn(async move {
-            if let Err(e) = blockchain_listener.start().await {
-                error!("Blockchain listener error: {}", e);
-            }
-        });
+    // 启动区块链监听器（由BLOCKCHAIN_ENABLED控制），连接失败时在后台重试，不影响聊天服务
+    if blockchain::start_listener(app_state.clone()).is_some() {
+        info!("🔗 Blockchain listener started for {}", config.ethereum_ws_url);
     } else {
-        info!("⚠️ Blockchain listener disabled - missing ETHEREUM_WS_URL or ETHEREUM_HTTP_URL");
-        info!("   See .env.example for configuration details");
+        info!("⚠️ Blockchain listener disabled (BLOCKCHAIN_ENABLED=false)");
     }
     
     // 创建路由
-    let app = create_router(app_state);
+    let app = create_router(app_state.clone());
     
     // 启动服务器
     let listener = TcpListener::bind(&config.server_address).await?;
     info!("Server listening on {}", config.server_address);
     
-    axum::serve(listener, app).await?;
+    // 记录客户端地址，供按IP限流使用
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown_signal(app_state))
+        .await?;
+    
+    info!("Server stopped");
     
     Ok(())
 }
 
 /**
- * 创建Redis连接池
+ * 等待ctrl-c或SIGTERM信号，收到后通知客户端并清理连接
  */
-async fn create_redis_pool(redis_url: &str) -> Result<bb8::Pool<bb8_redis::RedisConnectionManager>> {
-    let manager = bb8_redis::RedisConnectionManager::new(redis_url)?;
-    let pool = bb8::Pool::builder().build(manager).await?;
+async fn shutdown_signal(app_state: Arc<AppState>) {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for ctrl-c: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+    
+    info!("Shutdown signal received, closing connections...");
+    app_state.shutdown().await;
+}
+
+/**
+ * 初始化日志
+ * 普通日志按RUST_LOG输出到标准输出；安全事件以JSON行写入AUDIT_LOG_PATH，未配置时写到标准输出
+ */
+fn init_tracing(config: &Config) -> Result<()> {
+    use tracing_subscriber::fmt::writer::BoxMakeWriter;
+    use tracing_subscriber::prelude::*;
+    use tracing_subscriber::EnvFilter;
+    
+    let audit_writer = match &config.audit_log_path {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| anyhow::anyhow!("Failed to open AUDIT_LOG_PATH {}: {}", path, e))?;
+            BoxMakeWriter::new(std::sync::Mutex::new(file))
+        }
+        None => BoxMakeWriter::new(std::io::stdout),
+    };
+    
+    let env_filter = EnvFilter::from_default_env()
+        .add_directive(format!("{}=off", audit::AUDIT_TARGET).parse()?);
+    
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(env_filter))
+        .with(audit::AuditLayer::new(audit_writer))
+        .init();
+    
+    Ok(())
+}
+
+/**
+ * 创建Redis连接池，获取连接超时后请求以503失败而不是一直等待
+ */
+async fn create_redis_pool(config: &Config) -> Result<bb8::Pool<bb8_redis::RedisConnectionManager>> {
+    let manager = bb8_redis::RedisConnectionManager::new(config.redis_url.as_str())?;
+    let pool = bb8::Pool::builder()
+        .max_size(config.redis_max_connections)
+        .min_idle(config.redis_min_idle)
+        .connection_timeout(std::time::Duration::from_millis(config.redis_connect_timeout_ms))
+        .build(manager)
+        .await?;
     Ok(pool)
 }
 
@@ -109,38 +185,388 @@ async fn create_redis_pool(redis_url: &str) -> Result<bb8::Pool<bb8_redis::Redis
  * 创建应用路由
  */
 fn create_router(app_state: Arc<AppState>) -> Router {
-    Router::new()
+    let mut router = Router::new()
         // WebSocket路由
         .route("/ws", get(websocket_handler))
         // API路由
         .route("/api/auth/nonce", post(handlers::get_nonce))
         .route("/api/auth/login", post(handlers::login))
+        .route("/api/auth/refresh", post(handlers::refresh_token))
+        .route("/api/auth/logout", post(handlers::logout))
         .route("/api/user/info", get(handlers::get_user_info))
-        .route("/api/rooms", get(handlers::get_rooms))
-        .route("/api/rooms/:room_id", get(handlers::get_room_info))
+        .route("/api/user/rooms", get(handlers::get_user_rooms))
+        .route("/api/rooms", get(handlers::get_rooms).post(handlers::create_room))
+        .route("/api/rooms/:room_id", get(handlers::get_room_info).delete(handlers::delete_room))
+        .route("/api/rooms/:room_id/messages", get(handlers::get_room_messages))
+        .route("/api/rooms/:room_id/online", get(handlers::get_room_online_users))
+        .route("/api/rooms/:room_id/members", get(handlers::get_room_members))
         .route("/api/token-gate/verify", post(handlers::verify_token_gate))
+        .route("/api/config", get(handlers::get_config))
+        .route("/api/stats", get(handlers::get_stats))
+        // 管理接口
+        .route("/api/admin/audit", get(handlers::get_audit_log))
+        .route("/api/admin/config", put(handlers::update_config))
+        .route("/api/admin/announce", post(handlers::announce))
         // 健康检查
-        .route("/health", get(health_check))
+        .route("/health", get(handlers::health_check));
+    
+    // 指标导出（可选）
+    if app_state.config.metrics_enabled {
+        router = router.route("/metrics", get(handlers::metrics));
+    }
+    
+    router
         // 静态文件服务
         .nest_service("/frontend", ServeDir::new("frontend"))
         .nest_service("/", ServeDir::new("frontend"))
-        .layer(CorsLayer::permissive())
+        .layer(cors_layer(&app_state.config))
+        // 为每个HTTP请求分配x-request-id，请求内的日志都带有该id
+        .layer(
+            ServiceBuilder::new()
+                .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+                .layer(TraceLayer::new_for_http().make_span_with(|request: &Request| {
+                    tracing::info_span!(
+                        "request",
+                        id = %request_id(request.headers()),
+                        method = %request.method(),
+                        // 只记录路径，查询参数中可能带有WebSocket升级用的JWT
+                        path = %request.uri().path(),
+                    )
+                }))
+                .layer(PropagateRequestIdLayer::x_request_id()),
+        )
         .with_state(app_state)
 }
 
+/**
+ * 创建CORS层，只允许配置的来源；CORS_PERMISSIVE=true时允许任意来源
+ */
+fn cors_layer(config: &Config) -> CorsLayer {
+    if config.cors_permissive {
+        warn!("CORS is permissive, do not use this in production");
+        return CorsLayer::permissive();
+    }
+    
+    let origins: Vec<HeaderValue> = config.cors_origins
+        .iter()
+        .filter_map(|origin| match HeaderValue::from_str(origin) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                warn!("Ignoring invalid CORS origin {}: {}", origin, e);
+                None
+            }
+        })
+        .collect();
+    
+    CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
+}
+
 /**
  * WebSocket连接处理器
  */
 async fn websocket_handler(
     ws: WebSocketUpgrade,
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<models::WebSocketQuery>,
 ) -> Response {
-    ws.on_upgrade(move |socket| websocket::handle_connection(socket, state))
+    // 连接id沿用升级请求的request id，便于关联HTTP与WebSocket日志
+    let connection_id = request_id(&headers);
+    
+    // 协商协议版本，客户端只声明了不支持的版本时以协议错误关闭
+    let requested = headers
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok());
+    if !websocket::protocol_supported(requested) {
+        return ws.on_upgrade(websocket::reject_unsupported_protocol);
+    }
+    
+    // 携带了JWT的升级请求在升级前认证，token无效时以401拒绝，不占用WebSocket连接
+    let claims = match websocket::authenticate_upgrade(&state, &headers, query.token.as_deref()).await {
+        Ok(claims) => claims,
+        Err(e) => return e.into_response(),
+    };
+    
+    // 在解析之前限制帧和消息大小，超限时连接以错误结束
+    // 不协商permessage-deflate：axum 0.7使用的tungstenite 0.21不支持该扩展，Sec-WebSocket-Extensions会被忽略，
+    // 客户端始终按未压缩的帧通信
+    let max_bytes = state.config.ws_max_frame_bytes;
+    ws.protocols(websocket::SUPPORTED_PROTOCOLS)
+        .max_frame_size(max_bytes)
+        .max_message_size(max_bytes)
+        .on_upgrade(move |socket| websocket::handle_connection(socket, state, connection_id, claims))
 }
 
 /**
- * 健康检查端点
+ * 读取请求的x-request-id，缺失时生成新的id
  */
-async fn health_check() -> StatusCode {
-    StatusCode::OK
-}
\ No newline at end of file
+fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string())
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{capture_log, TestHarness};
+    use futures_util::{SinkExt, StreamExt};
+    use std::time::Duration;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use tokio_tungstenite::tungstenite::Message;
+    use tower::ServiceExt;
+    
+    /**
+     * 在随机端口上启动服务，返回监听地址
+     */
+    async fn serve(app_state: Arc<AppState>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = create_router(app_state);
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .unwrap();
+        });
+        addr
+    }
+    
+    /**
+     * 声明指定子协议的WebSocket握手请求
+     */
+    fn ws_request(addr: SocketAddr, protocol: &str) -> axum::http::Request<()> {
+        let mut request = format!("ws://{}/ws", addr).into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert(header::SEC_WEBSOCKET_PROTOCOL, protocol.parse().unwrap());
+        request
+    }
+    
+    #[tokio::test]
+    async fn websocket_subprotocol_is_negotiated() {
+        let harness = TestHarness::start().await;
+        let addr = serve(harness.app_state()).await;
+        
+        let (_socket, response) = tokio_tungstenite::connect_async(ws_request(addr, websocket::PROTOCOL_VERSION))
+            .await
+            .expect("supported protocol should connect");
+        assert_eq!(
+            response.headers().get(header::SEC_WEBSOCKET_PROTOCOL).unwrap(),
+            websocket::PROTOCOL_VERSION
+        );
+        
+        // 不支持的版本在握手阶段失败，或者连接后立即以协议错误关闭
+        if let Ok((mut socket, _)) = tokio_tungstenite::connect_async(ws_request(addr, "chaintalk.v0")).await {
+            match socket.next().await {
+                Some(Ok(Message::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Protocol),
+                other => panic!("unsupported protocol was not rejected: {:?}", other),
+            }
+        }
+    }
+    
+    #[tokio::test]
+    async fn idle_connection_is_closed_after_timeout() {
+        let mut harness = TestHarness::start().await;
+        harness.config.idle_timeout_secs = 1;
+        let addr = serve(harness.app_state()).await;
+        
+        let (mut socket, _) = tokio_tungstenite::connect_async(ws_request(addr, websocket::PROTOCOL_VERSION))
+            .await
+            .unwrap();
+        
+        // 欢迎消息之后不再发送任何内容，等待服务端关闭连接
+        let frame = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match socket.next().await {
+                    Some(Ok(Message::Close(frame))) => break frame,
+                    Some(Ok(_)) => continue,
+                    other => panic!("connection ended without close frame: {:?}", other),
+                }
+            }
+        })
+        .await
+        .expect("idle connection was not closed")
+        .expect("close frame should carry a reason");
+        assert_eq!(frame.code, CloseCode::Away);
+        assert_eq!(frame.reason, "Idle timeout");
+    }
+    
+    #[tokio::test]
+    async fn cors_allows_only_configured_origins() {
+        let mut harness = TestHarness::start().await;
+        harness.config.cors_permissive = false;
+        harness.config.cors_origins = vec!["https://app.chaintalk.xyz".to_string()];
+        let app = create_router(harness.app_state());
+        
+        let preflight = |origin: &str| {
+            axum::http::Request::builder()
+                .method(Method::OPTIONS)
+                .uri("/api/rooms")
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+                .body(axum::body::Body::empty())
+                .unwrap()
+        };
+        
+        let response = app.clone().oneshot(preflight("https://app.chaintalk.xyz")).await.unwrap();
+        assert_eq!(
+            response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+            "https://app.chaintalk.xyz"
+        );
+        
+        let response = app.oneshot(preflight("https://evil.example")).await.unwrap();
+        assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+    
+    #[tokio::test]
+    async fn connection_logs_share_connection_span() {
+        let (logs, _guard) = capture_log();
+        let harness = TestHarness::start().await;
+        let addr = serve(harness.app_state()).await;
+        
+        let mut request = ws_request(addr, websocket::PROTOCOL_VERSION);
+        request.headers_mut().insert("x-request-id", "conn-span-test".parse().unwrap());
+        let (mut socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        
+        // 等待服务端处理完这条消息后再检查日志
+        socket.send(Message::Text(r#"{"type":"Teleport"}"#.to_string())).await.unwrap();
+        loop {
+            match socket.next().await {
+                Some(Ok(Message::Text(text))) if text.contains("Unknown message type") => break,
+                Some(Ok(_)) => continue,
+                other => panic!("no error reply for unknown message: {:?}", other),
+            }
+        }
+        
+        let lines = logs.lines();
+        for event in ["New WebSocket connection established", "Received client message"] {
+            let line = lines
+                .iter()
+                .find(|line| line.contains(event))
+                .unwrap_or_else(|| panic!("missing log line {:?} in {:#?}", event, lines));
+            assert!(line.contains("connection{id=conn-span-test"), "{}", line);
+        }
+    }
+    
+    #[tokio::test]
+    async fn malformed_token_gate_bodies_return_400() {
+        let harness = TestHarness::start().await;
+        let app = create_router(harness.app_state());
+        
+        let verify = |content_type: Option<&str>, body: &str| {
+            let mut request = axum::http::Request::builder()
+                .method(Method::POST)
+                .uri("/api/token-gate/verify");
+            if let Some(content_type) = content_type {
+                request = request.header(header::CONTENT_TYPE, content_type);
+            }
+            request.body(axum::body::Body::from(body.to_string())).unwrap()
+        };
+        
+        let json = Some("application/json");
+        let cases = [
+            // JSON语法错误
+            (json, r#"{"user_address":"#),
+            // 缺少必填字段
+            (json, r#"{"user_address":"0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1"}"#),
+            // 字段类型错误
+            (json, r#"{"user_address":"0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1","contract_address":"0x6B175474E89094C44Da98b954EedeAC495271d0F","chain_id":"one"}"#),
+            // 缺少Content-Type
+            (None, r#"{"user_address":"0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1","contract_address":"0x6B175474E89094C44Da98b954EedeAC495271d0F"}"#),
+            // 地址格式错误
+            (json, r#"{"user_address":"0x1234","contract_address":"0x6B175474E89094C44Da98b954EedeAC495271d0F"}"#),
+        ];
+        for (content_type, body) in cases {
+            let response = app.clone().oneshot(verify(content_type, body)).await.unwrap();
+            assert_eq!(response.status(), axum::http::StatusCode::BAD_REQUEST, "{}", body);
+        }
+    }
+    
+    #[tokio::test]
+    async fn oversized_frame_disconnects_the_client() {
+        let mut harness = TestHarness::start().await;
+        harness.config.ws_max_frame_bytes = 1024;
+        let addr = serve(harness.app_state()).await;
+        
+        let (mut socket, _) = tokio_tungstenite::connect_async(ws_request(addr, websocket::PROTOCOL_VERSION))
+            .await
+            .unwrap();
+        let _ = socket.send(Message::Text("x".repeat(64 * 1024))).await;
+        
+        // 超限的帧在解析前被拒绝，服务端结束连接
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match socket.next().await {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                }
+            }
+        })
+        .await
+        .expect("oversized frame did not end the connection");
+        
+        // 其他连接不受影响
+        let (mut socket, _) = tokio_tungstenite::connect_async(ws_request(addr, websocket::PROTOCOL_VERSION))
+            .await
+            .unwrap();
+        socket.send(Message::Text(r#"{"type":"Ping"}"#.to_string())).await.unwrap();
+        let reply = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match socket.next().await {
+                    Some(Ok(Message::Text(text))) if text.contains("Not authenticated") => break,
+                    Some(Ok(_)) => continue,
+                    other => panic!("no reply after oversized frame on another connection: {:?}", other),
+                }
+            }
+        })
+        .await;
+        assert!(reply.is_ok());
+    }
+    
+    #[tokio::test]
+    async fn redis_pool_uses_configured_limits() {
+        let mut harness = TestHarness::start().await;
+        harness.config.redis_max_connections = 2;
+        harness.config.redis_min_idle = Some(1);
+        harness.config.redis_connect_timeout_ms = 200;
+        let pool = create_redis_pool(&harness.config).await.unwrap();
+        
+        // 构建完成时已建立min_idle个空闲连接
+        assert_eq!(pool.state().idle_connections, 1);
+        
+        let _first = pool.get().await.unwrap();
+        let _second = pool.get().await.unwrap();
+        assert_eq!(pool.state().connections, 2);
+        
+        // 连接用尽后按配置的超时时间失败
+        let started = std::time::Instant::now();
+        assert!(pool.get().await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+    
+    #[tokio::test]
+    async fn empty_welcome_message_sends_no_frame() {
+        let mut harness = TestHarness::start().await;
+        harness.config.welcome_message = None;
+        let addr = serve(harness.app_state()).await;
+        
+        let (mut socket, _) = tokio_tungstenite::connect_async(ws_request(addr, websocket::PROTOCOL_VERSION))
+            .await
+            .unwrap();
+        socket.send(Message::Text(r#"{"type":"Ping"}"#.to_string())).await.unwrap();
+        
+        // 第一帧就是对这条消息的回复，之前没有欢迎消息
+        let first = tokio::time::timeout(Duration::from_secs(5), socket.next())
+            .await
+            .expect("no reply from server");
+        match first {
+            Some(Ok(Message::Text(text))) => assert!(text.contains("Not authenticated"), "{}", text),
+            other => panic!("unexpected first frame: {:?}", other),
+        }
+    }
+}
@@ -11,40 +11,119 @@ use uuid::Uuid;
 pub enum ClientMessage {
     Authenticate { message: String, signature: String },
     SimpleAuth { address: String, message: String, signature: String, nonce: String },
-    SendText { room: String, text: String },
+    TokenAuth { token: String },
+    SendText {
+        room: String,
+        text: String,
+        #[serde(default)]
+        client_msg_id: Option<String>, // 客户端生成的消息id，广播成功后通过Ack回传
+        #[serde(default)]
+        reply_to: Option<String>, // 回复的消息id
+    },
     JoinRoom { room: String },
     LeaveRoom { room: String },
+    DirectMessage { to: String, text: String },
+    React { room: String, message_id: String, emoji: String },
+    DeleteMessage { room: String, message_id: String },
+    EditMessage { room: String, message_id: String, new_text: String },
+    Kick { room: String, target: String },
+    Mute { room: String, target: String, duration_secs: u64 },
+    SetSlowMode { room: String, secs: u64 },
+    SetTopic { room: String, topic: String }, // 空字符串清除话题
+    BlockUser { address: String },
+    UnblockUser { address: String },
+    FetchHistory {
+        room: String,
+        #[serde(default)]
+        before_id: Option<String>, // 返回早于该消息id的消息，为空时返回最新消息
+        #[serde(default)]
+        limit: usize, // 为0时使用默认的历史条数
+    },
+    SendAttachment {
+        room: String,
+        url: String, // 只接受https和ipfs地址
+        #[serde(default)]
+        mime_hint: Option<String>, // 客户端声明的内容类型
+    },
+    Logout {
+        #[serde(default)]
+        token: Option<String>, // 同时吊销的JWT，payload可为空对象
+    },
     Ping,
 }
 
+/// ClientMessage的所有type取值，新增变体时需同步更新
+pub const CLIENT_MESSAGE_TYPES: &[&str] = &[
+    "Authenticate",
+    "SimpleAuth",
+    "TokenAuth",
+    "SendText",
+    "JoinRoom",
+    "LeaveRoom",
+    "DirectMessage",
+    "React",
+    "DeleteMessage",
+    "EditMessage",
+    "Kick",
+    "Mute",
+    "SetSlowMode",
+    "SetTopic",
+    "BlockUser",
+    "UnblockUser",
+    "FetchHistory",
+    "SendAttachment",
+    "Logout",
+    "Ping",
+];
+
 /**
  * 从服务端广播给客户端的消息类型
  */
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "type", content = "payload")]
 pub enum ServerMessage {
     NewText {
         id: String,
-        from: String,
+        from: String, // 显示名称（ENS或缩写地址）
+        #[serde(default)]
+        from_address: String, // 作者地址，系统消息为空
         text: String,
         room: String,
         timestamp: DateTime<Utc>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        edited_at: Option<DateTime<Utc>>, // 编辑过的消息带有最后编辑时间
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<String>, // 回复的消息id，客户端据此显示引用预览
+    },
+    Attachment {
+        id: String,
+        from: String, // 显示名称（ENS或缩写地址）
+        from_address: String,
+        room: String,
+        url: String,
+        mime: Option<String>,
+        timestamp: DateTime<Utc>,
     },
     UserJoined {
         user: String,
         room: String,
         timestamp: DateTime<Utc>,
         ens_name: Option<String>,
+        display_name: String, // 显示名称（ENS或缩写地址）
     },
     UserLeft {
         user: String,
         room: String,
         timestamp: DateTime<Utc>,
         ens_name: Option<String>,
+        display_name: String,
     },
-    RoomUsers {
-        room: String,
-        users: Vec<String>,
+    DirectMessage {
+        id: String,
+        from: String,
+        to: String,
+        text: String,
+        timestamp: DateTime<Utc>,
     },
     ChainEvent(OnChainEvent),
     Error {
@@ -53,6 +132,7 @@ pub enum ServerMessage {
     AuthSuccess {
         user_address: String,
         ens_name: Option<String>,
+        protocol_version: String, // 本连接使用的协议版本
     },
     AuthFailed {
         error: String,
@@ -62,18 +142,141 @@ pub enum ServerMessage {
         users: Vec<OnlineUser>,
         room: String,
     },
+    ConfigUpdated(RuntimeConfig),
+    MessageDeleted {
+        room: String,
+        message_id: String,
+    },
+    Kicked {
+        room: String,
+        by: String,
+    },
+    Reaction {
+        room: String,
+        message_id: String,
+        emoji: String,
+        user: String,
+        count: u64,
+    },
+    History {
+        room: String,
+        messages: Vec<ServerMessage>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        topic: Option<String>, // 加入房间和重新同步时携带当前话题，分页历史不携带
+    },
+    ServerShutdown {
+        message: String,
+    },
+    Ack {
+        client_msg_id: String,
+        server_id: String,
+        timestamp: DateTime<Utc>,
+    },
+    RoomDeleted {
+        room: String,
+    },
+    Mention {
+        room: String,
+        message_id: String,
+        from: String,
+    },
+    MessageEdited {
+        room: String,
+        message_id: String,
+        new_text: String,
+        edited_at: DateTime<Utc>,
+    },
+    Announcement {
+        text: String,
+        timestamp: DateTime<Utc>,
+    },
+    TopicChanged {
+        room: String,
+        topic: Option<String>, // None表示话题已清除
+        by: String,
+    },
+    BlockListUpdated {
+        blocked: Vec<String>,
+    },
+    // 客户端错过了部分消息，随后会重新发送配置、房间历史和在线用户列表
+    Resync {
+        reason: String,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OnlineUser {
     pub address: String,
     pub ens_name: Option<String>,
+    pub avatar: Option<String>,
+    pub avatar_seed: String,
+}
+
+/**
+ * 房间成员，包括在线用户和最近离开的用户
+ * 离线成员带有最后在线时间，前端据此显示离开状态
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomMember {
+    pub address: String,
+    pub ens_name: Option<String>,
+    pub avatar: Option<String>,
+    pub avatar_seed: String,
+    pub online: bool,
+    pub last_seen: Option<DateTime<Utc>>, // 在线成员为None
+}
+
+/**
+ * 运行时可修改的配置，同时也是客户端可见的配置
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuntimeConfig {
+    pub motd: Option<String>,
+    pub maintenance_mode: bool,
+    pub features: HashMap<String, bool>,
+    pub rate_limit_messages: u32,
+    pub rate_limit_window_ms: u64,
+}
+
+/**
+ * 运行时配置更新请求，未提供的字段保持不变
+ */
+#[derive(Debug, Deserialize)]
+pub struct RuntimeConfigUpdate {
+    pub motd: Option<String>,
+    pub maintenance_mode: Option<bool>,
+    pub features: Option<HashMap<String, bool>>,
+    pub rate_limit_messages: Option<u32>,
+    pub rate_limit_window_ms: Option<u64>,
+}
+
+impl RuntimeConfig {
+    /**
+     * 应用配置更新
+     */
+    pub fn apply(&mut self, update: RuntimeConfigUpdate) {
+        if let Some(motd) = update.motd {
+            self.motd = if motd.trim().is_empty() { None } else { Some(motd) };
+        }
+        if let Some(maintenance_mode) = update.maintenance_mode {
+            self.maintenance_mode = maintenance_mode;
+        }
+        if let Some(features) = update.features {
+            self.features.extend(features);
+        }
+        if let Some(rate_limit_messages) = update.rate_limit_messages {
+            self.rate_limit_messages = rate_limit_messages;
+        }
+        if let Some(rate_limit_window_ms) = update.rate_limit_window_ms {
+            self.rate_limit_window_ms = rate_limit_window_ms;
+        }
+    }
 }
 
 /**
  * 链上事件数据模型
  */
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct OnChainEvent {
     pub id: String,
     pub event_type: String,
@@ -92,18 +295,37 @@ pub struct UniswapV3SwapDetails {
     pub recipient: String,
     pub amount0: String,
     pub amount1: String,
+    pub amount0_formatted: String,
+    pub amount1_formatted: String,
     pub sqrt_price_x96: String,
     pub liquidity: String,
     pub tick: i32,
     pub pool_address: String,
     pub token0: String,
     pub token1: String,
+    pub token0_address: String,
+    pub token1_address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usd_value: Option<String>, // 按池子价格估算的美元价值，无稳定币一侧时省略
+}
+
+/**
+ * ERC20 Transfer事件详情
+ */
+#[derive(Debug, Serialize, Clone)]
+pub struct Erc20TransferDetails {
+    pub from: String,
+    pub to: String,
+    pub amount: String,
+    pub amount_formatted: String,
+    pub symbol: String,
+    pub token_address: String,
 }
 
 /**
  * 用户认证信息
  */
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserAuth {
     pub address: String,
     pub ens_name: Option<String>,
@@ -120,6 +342,8 @@ pub struct Claims {
     pub exp: usize,  // 过期时间
     pub iat: usize,  // 签发时间
     pub ens: Option<String>, // ENS名称
+    #[serde(default)]
+    pub jti: String, // token唯一标识，用于吊销
 }
 
 /**
@@ -137,9 +361,18 @@ pub struct LoginRequest {
 #[derive(Debug, Serialize)]
 pub struct LoginResponse {
     pub token: String,
+    pub refresh_token: String,
     pub user: UserInfo,
 }
 
+/**
+ * 刷新token请求
+ */
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
 /**
  * 用户信息
  */
@@ -148,6 +381,56 @@ pub struct UserInfo {
     pub address: String,
     pub ens_name: Option<String>,
     pub avatar: Option<String>,
+    pub avatar_seed: String,
+}
+
+/**
+ * 当前用户可进入的房间
+ */
+#[derive(Debug, Serialize)]
+pub struct UserRoomsResponse {
+    pub accessible: Vec<String>,
+    pub denied: Vec<DeniedRoom>,
+}
+
+/**
+ * 用户无法进入的房间及原因
+ */
+#[derive(Debug, Serialize)]
+pub struct DeniedRoom {
+    pub room: String,
+    pub reason: String,
+}
+
+/**
+ * 服务运行状态
+ */
+#[derive(Debug, Serialize)]
+pub struct ServerStats {
+    pub total_clients: usize,
+    pub total_rooms: usize,
+    pub rooms: Vec<RoomStats>,
+    pub uptime_secs: u64,
+    pub blockchain_events_processed: u64,
+}
+
+/**
+ * 房间在线人数
+ */
+#[derive(Debug, Serialize)]
+pub struct RoomStats {
+    pub name: String,
+    pub user_count: usize,
+}
+
+/**
+ * Nonce请求
+ */
+#[derive(Debug, Deserialize)]
+pub struct NonceRequest {
+    pub address: String,
+    #[serde(default)]
+    pub chain_id: Option<u64>, // 签名所用的链，未指定时为以太坊主网
 }
 
 /**
@@ -156,6 +439,8 @@ pub struct UserInfo {
 #[derive(Debug, Serialize)]
 pub struct NonceResponse {
     pub nonce: String,
+    pub address: String, // EIP-55校验和格式的地址
+    pub message: String, // 待签名的SIWE消息
 }
 
 /**
@@ -167,10 +452,26 @@ pub struct RoomConfig {
     pub description: Option<String>,
     pub token_gate: Option<TokenGate>,
     pub max_users: Option<usize>,
+    #[serde(default)]
+    pub max_history: Option<usize>, // 覆盖默认的消息历史条数
+    #[serde(default)]
+    pub slow_mode_secs: Option<u64>, // 每个用户两条消息之间的最短间隔
     pub created_at: DateTime<Utc>,
     pub created_by: String,
 }
 
+/**
+ * 创建房间请求
+ */
+#[derive(Debug, Deserialize)]
+pub struct CreateRoomRequest {
+    pub name: String,
+    pub description: Option<String>,
+    pub token_gate: Option<TokenGate>,
+    pub max_users: Option<usize>,
+    pub max_history: Option<usize>,
+}
+
 /**
  * Token门禁配置
  */
@@ -180,6 +481,40 @@ pub struct TokenGate {
     pub contract_address: String,
     pub minimum_balance: Option<String>,
     pub token_ids: Option<Vec<String>>,
+    #[serde(default = "default_chain_id")]
+    pub chain_id: u64, // 合约所在链，未指定时为以太坊主网
+}
+
+/**
+ * Token门禁校验请求
+ */
+#[derive(Debug, Deserialize)]
+pub struct TokenGateRequest {
+    pub user_address: String,
+    pub contract_address: String,
+    pub gate_type: Option<TokenGateType>, // 未指定时按ERC20处理
+    pub minimum_balance: Option<String>,
+    pub token_ids: Option<Vec<String>>,
+    pub chain_id: Option<u64>,
+}
+
+/**
+ * Token门禁校验结果
+ */
+#[derive(Debug, Serialize)]
+pub struct TokenGateResponse {
+    pub has_access: bool,
+    pub user_address: String,
+    pub contract_address: String,
+    pub gate_type: TokenGateType,
+    pub chain_id: u64,
+}
+
+/// 以太坊主网的链id
+pub const MAINNET_CHAIN_ID: u64 = 1;
+
+fn default_chain_id() -> u64 {
+    MAINNET_CHAIN_ID
 }
 
 /**
@@ -192,6 +527,109 @@ pub enum TokenGateType {
     ERC1155,
 }
 
+/**
+ * 健康检查参数
+ */
+#[derive(Debug, Deserialize)]
+pub struct HealthQuery {
+    pub deep: Option<bool>, // 为false时只检查进程存活，不检查依赖
+}
+
+/**
+ * WebSocket升级请求的查询参数
+ */
+#[derive(Debug, Deserialize)]
+pub struct WebSocketQuery {
+    pub token: Option<String>, // 升级时认证使用的JWT，也可以通过Authorization头传递
+}
+
+/**
+ * 房间消息历史查询参数
+ */
+#[derive(Debug, Deserialize)]
+pub struct MessageHistoryQuery {
+    pub limit: Option<usize>,
+    pub before: Option<String>, // 返回早于该消息id的消息
+}
+
+/**
+ * 审计日志查询参数
+ */
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    pub action: Option<String>,
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub cursor: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/**
+ * 审计日志条目
+ */
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    pub id: String,
+    pub action: String,
+    pub actor: String,
+    pub target: Option<String>,
+    pub outcome: Option<String>, // success、failure或denied，较早的条目没有该字段
+    pub timestamp: Option<DateTime<Utc>>,
+    pub details: serde_json::Value,
+}
+
+/**
+ * 系统公告请求
+ */
+#[derive(Debug, Deserialize)]
+pub struct AnnouncementRequest {
+    pub text: String,
+}
+
+/**
+ * 审计日志分页响应
+ */
+#[derive(Debug, Serialize)]
+pub struct AuditPage {
+    pub entries: Vec<AuditEntry>,
+    pub next_cursor: Option<String>,
+}
+
+/**
+ * 根据地址生成确定性的默认头像种子
+ * 使用小写地址，与blockies/jazzicon等客户端库的输入保持一致
+ */
+pub fn avatar_seed(address: &str) -> String {
+    address.to_lowercase()
+}
+
+/// 缩写地址时中间使用的省略号
+const DISPLAY_NAME_ELLIPSIS: &str = "...";
+
+/**
+ * 用户显示名称：有ENS名称时直接使用，否则把地址缩写为开头prefix_len个字符和结尾suffix_len个字符
+ * 按字符而不是字节截取，缩写后不会更短的地址原样返回，格式异常的地址（过短、非ASCII）也不会panic
+ */
+pub fn display_name(address: &str, ens_name: Option<&str>, prefix_len: usize, suffix_len: usize) -> String {
+    if let Some(name) = ens_name {
+        return name.to_string();
+    }
+
+    let chars: Vec<char> = address.chars().collect();
+    // 长度来自配置，使用饱和加法避免过大的配置值溢出
+    let shortened_len = prefix_len
+        .saturating_add(suffix_len)
+        .saturating_add(DISPLAY_NAME_ELLIPSIS.len());
+    if chars.len() <= shortened_len {
+        return address.to_string();
+    }
+
+    let prefix: String = chars[..prefix_len].iter().collect();
+    let suffix: String = chars[chars.len() - suffix_len..].iter().collect();
+    format!("{}{}{}", prefix, DISPLAY_NAME_ELLIPSIS, suffix)
+}
+
 impl OnChainEvent {
     /**
      * 创建新的链上事件
@@ -215,39 +653,91 @@ impl OnChainEvent {
 
 impl ServerMessage {
     /**
-     * 创建新文本消息
+     * 用户发送的聊天消息（文本或附件）的id，其他消息返回None
      */
-    pub fn new_text(from: String, text: String, room: String) -> Self {
-        Self::NewText {
-            id: Uuid::new_v4().to_string(),
-            from,
-            text,
-            room,
-            timestamp: Utc::now(),
+    pub fn chat_message_id(&self) -> Option<&str> {
+        match self {
+            Self::NewText { id, .. } | Self::Attachment { id, .. } => Some(id.as_str()),
+            _ => None,
         }
     }
-
+    
     /**
-     * 创建用户加入消息
+     * 是否写入房间历史：只保留聊天消息和链上事件
+     * 加入、离开、在线列表等状态变化不进入历史，避免挤掉聊天记录或在加入时重放过期状态
      */
-    pub fn user_joined(user: String, room: String) -> Self {
-        Self::UserJoined {
-            user,
-            room,
-            timestamp: Utc::now(),
-            ens_name: None,
+    pub fn is_history_entry(&self) -> bool {
+        self.chat_message_id().is_some() || matches!(self, Self::ChainEvent(_))
+    }
+    
+    /**
+     * 聊天消息作者的地址，系统消息及其他消息返回None
+     */
+    pub fn chat_author(&self) -> Option<&str> {
+        match self {
+            Self::NewText { from_address, .. } | Self::Attachment { from_address, .. } if !from_address.is_empty() => {
+                Some(from_address.as_str())
+            }
+            _ => None,
         }
     }
-
+    
     /**
-     * 创建用户离开消息
+     * 创建新文本消息，from为显示名称，from_address为作者地址
      */
-    pub fn user_left(user: String, room: String) -> Self {
-        Self::UserLeft {
-            user,
+    pub fn new_text(
+        from: String,
+        from_address: String,
+        text: String,
+        room: String,
+        reply_to: Option<String>,
+    ) -> Self {
+        Self::NewText {
+            id: Uuid::new_v4().to_string(),
+            from,
+            from_address,
+            text,
             room,
             timestamp: Utc::now(),
-            ens_name: None,
+            edited_at: None,
+            reply_to,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_name_shortens_addresses() {
+        let address = "0x1234567890abcdef1234567890abcdef12345678";
+        assert_eq!(display_name(address, None, 6, 4), "0x1234...5678");
+        assert_eq!(display_name(address, None, 10, 6), "0x12345678...345678");
+    }
+
+    #[test]
+    fn display_name_keeps_short_strings() {
+        assert_eq!(display_name("0x1234", None, 6, 4), "0x1234");
+        assert_eq!(display_name("0x1234567890a", None, 6, 4), "0x1234567890a");
+        assert_eq!(display_name("", None, 6, 4), "");
+    }
+
+    #[test]
+    fn display_name_prefers_ens() {
+        let address = "0x1234567890abcdef1234567890abcdef12345678";
+        assert_eq!(display_name(address, Some("vitalik.eth"), 6, 4), "vitalik.eth");
+    }
+
+    #[test]
+    fn display_name_handles_malformed_addresses() {
+        assert_eq!(display_name("0x1", None, 6, 4), "0x1");
+
+        let unicode = "0x中文地址🦀🦀🦀🦀é̂ñabcdef";
+        assert_eq!(display_name(unicode, None, 6, 4), "0x中文地址...cdef");
+        assert_eq!(display_name("0x🦀🦀🦀🦀🦀🦀🦀🦀🦀🦀🦀", None, 3, 2), "0x🦀...🦀🦀");
+
+        // 配置了过大的长度时原样返回，而不是溢出或越界
+        assert_eq!(display_name(unicode, None, usize::MAX, usize::MAX), unicode);
+    }
+}
@@ -0,0 +1,148 @@
+use crate::error::{AppError, Result};
+use crate::models::ServerMessage;
+use crate::state::AppState;
+use bb8::Pool;
+use bb8_redis::RedisConnectionManager;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+/// 全局广播频道
+pub const GLOBAL_CHANNEL: &str = "global";
+
+/// 房间广播频道前缀
+const ROOM_CHANNEL_PREFIX: &str = "room:";
+
+/// 订阅断开后重新订阅的等待时间
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/**
+ * 跨实例传递的广播消息
+ * 附带发送方实例id，订阅方据此忽略自己发出的消息
+ */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    pub instance_id: String,
+    pub message: ServerMessage,
+}
+
+/**
+ * 房间对应的广播频道
+ */
+pub fn room_channel(room_name: &str) -> String {
+    format!("{}{}", ROOM_CHANNEL_PREFIX, room_name)
+}
+
+/**
+ * 将广播消息发布到Redis频道
+ */
+pub async fn publish(
+    redis_pool: &Pool<RedisConnectionManager>,
+    channel: &str,
+    instance_id: &str,
+    message: ServerMessage,
+) -> Result<()> {
+    let payload = serde_json::to_string(&Envelope {
+        instance_id: instance_id.to_string(),
+        message,
+    })?;
+
+    let mut conn = redis_pool.get().await?;
+
+    let _: i64 = redis::cmd("PUBLISH")
+        .arg(channel)
+        .arg(payload)
+        .query_async(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+/**
+ * 运行Redis订阅者，将其他实例发布的广播投递给本实例的客户端
+ * 订阅断开后自动重新订阅，收到关闭信号时退出
+ */
+pub async fn run_subscriber(state: Arc<AppState>) {
+    let mut shutdown = state.subscribe_shutdown();
+
+    loop {
+        match subscribe(&state, &mut shutdown).await {
+            Ok(()) => {
+                info!("Redis subscriber shutting down");
+                return;
+            }
+            Err(e) => error!("Redis subscriber failed: {}", e),
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(RESUBSCRIBE_DELAY) => {}
+            _ = shutdown.changed() => return,
+        }
+    }
+}
+
+/**
+ * 订阅全局及房间频道并处理消息，收到关闭信号时返回Ok
+ */
+async fn subscribe(state: &AppState, shutdown: &mut watch::Receiver<bool>) -> Result<()> {
+    let client = redis::Client::open(state.config.redis_url.as_str())?;
+    let mut pubsub = client.get_async_connection().await?.into_pubsub();
+
+    pubsub.subscribe(GLOBAL_CHANNEL).await?;
+    pubsub.psubscribe(format!("{}*", ROOM_CHANNEL_PREFIX)).await?;
+
+    info!("Subscribed to Redis broadcast channels as instance {}", state.instance_id);
+
+    let mut messages = pubsub.on_message();
+
+    loop {
+        tokio::select! {
+            msg = messages.next() => {
+                let msg = msg.ok_or_else(|| {
+                    AppError::DatabaseError("Redis subscription closed".to_string())
+                })?;
+
+                let channel = msg.get_channel_name().to_string();
+                let payload: String = match msg.get_payload() {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        warn!("Invalid payload on channel {}: {}", channel, e);
+                        continue;
+                    }
+                };
+
+                if let Err(e) = handle_message(state, &channel, &payload).await {
+                    warn!("Failed to handle message on channel {}: {}", channel, e);
+                }
+            }
+            _ = shutdown.changed() => return Ok(()),
+        }
+    }
+}
+
+/**
+ * 处理从Redis收到的广播消息
+ */
+async fn handle_message(state: &AppState, channel: &str, payload: &str) -> Result<()> {
+    let envelope: Envelope = serde_json::from_str(payload)?;
+
+    // 本实例发出的消息已在本地投递过
+    if envelope.instance_id == state.instance_id {
+        return Ok(());
+    }
+
+    if channel == GLOBAL_CHANNEL {
+        state.deliver_global(envelope.message).await;
+    } else if let Some(room_name) = channel.strip_prefix(ROOM_CHANNEL_PREFIX) {
+        if matches!(envelope.message, ServerMessage::RoomDeleted { .. }) {
+            state.remove_room(room_name).await;
+        } else {
+            state.deliver_to_room(room_name, envelope.message).await;
+        }
+    }
+
+    Ok(())
+}
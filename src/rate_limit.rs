@@ -0,0 +1,95 @@
+use crate::error::Result;
+use bb8::Pool;
+use bb8_redis::RedisConnectionManager;
+use chrono::Utc;
+use uuid::Uuid;
+
+/**
+ * 滑动窗口限流脚本：先清理窗口外的记录，未达上限时才记录本次请求
+ * 被拒绝的请求不计数，持续重试的客户端在窗口滑过后即可恢复
+ * KEYS[1] 限流键；ARGV: 窗口起点、当前时间、成员、上限、窗口毫秒数
+ */
+const RATE_LIMIT_SCRIPT: &str = r#"
+redis.call('ZREMRANGEBYSCORE', KEYS[1], 0, ARGV[1])
+if redis.call('ZCARD', KEYS[1]) >= tonumber(ARGV[4]) then
+    return 0
+end
+redis.call('ZADD', KEYS[1], ARGV[2], ARGV[3])
+redis.call('PEXPIRE', KEYS[1], ARGV[5])
+return 1
+"#;
+
+/**
+ * 基于Redis有序集合的滑动窗口限流
+ * 记录窗口内被允许的请求，多个服务实例共享同一计数
+ * 返回true表示允许本次请求
+ */
+pub async fn check_rate_limit(
+    redis_pool: &Pool<RedisConnectionManager>,
+    scope: &str,
+    key: &str,
+    max_requests: u32,
+    window_ms: u64,
+) -> Result<bool> {
+    if max_requests == 0 || window_ms == 0 {
+        // 未启用限流
+        return Ok(true);
+    }
+
+    let redis_key = format!("ratelimit:{}:{}", scope, key.to_lowercase());
+    let now = Utc::now().timestamp_millis();
+    let window_start = now - window_ms as i64;
+    let member = format!("{}-{}", now, Uuid::new_v4());
+
+    let mut conn = redis_pool.get().await?;
+
+    let allowed: i64 = redis::Script::new(RATE_LIMIT_SCRIPT)
+        .key(&redis_key)
+        .arg(window_start)
+        .arg(now)
+        .arg(&member)
+        .arg(max_requests)
+        .arg(window_ms)
+        .invoke_async(&mut *conn)
+        .await?;
+
+    Ok(allowed == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_rate_limit;
+    use crate::test_support::TestHarness;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn allows_up_to_limit_and_recovers_after_window() {
+        let harness = TestHarness::start().await;
+        let pool = &harness.redis_pool;
+
+        assert!(check_rate_limit(pool, "test", "alice", 2, 300).await.unwrap());
+        assert!(check_rate_limit(pool, "test", "ALICE", 2, 300).await.unwrap());
+        assert!(!check_rate_limit(pool, "test", "alice", 2, 300).await.unwrap());
+
+        // 其他键和其他作用域各自计数
+        assert!(check_rate_limit(pool, "test", "bob", 2, 300).await.unwrap());
+        assert!(check_rate_limit(pool, "other", "alice", 2, 300).await.unwrap());
+
+        // 窗口中途的重试被拒绝且不计数，最初的请求滑出窗口后即可恢复
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!check_rate_limit(pool, "test", "alice", 2, 300).await.unwrap());
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(check_rate_limit(pool, "test", "alice", 2, 300).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn zero_limit_or_window_disables_limiting() {
+        let harness = TestHarness::start().await;
+        let pool = &harness.redis_pool;
+
+        for _ in 0..5 {
+            assert!(check_rate_limit(pool, "test", "alice", 0, 300).await.unwrap());
+            assert!(check_rate_limit(pool, "test", "alice", 1, 0).await.unwrap());
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use crate::config::Config;
+use ethers::contract::ContractError;
+use ethers::providers::{Middleware, MiddlewareError, ProviderError};
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+
+/// 节点限流时返回的JSON-RPC错误码（部分节点直接使用HTTP状态码）
+const RATE_LIMIT_ERROR_CODES: [i64; 2] = [-32005, 429];
+
+/**
+ * RPC调用的重试策略：最多尝试attempts次，每次失败后的等待时间从base_delay开始翻倍
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    /**
+     * 从配置创建重试策略
+     */
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            attempts: config.rpc_retry_attempts,
+            base_delay: Duration::from_millis(config.rpc_retry_base_delay_ms),
+        }
+    }
+
+    /**
+     * 执行RPC调用，遇到可重试的错误时按指数退避重试
+     * 确定性的错误（执行回滚、ENS没有记录等）立即返回，重试次数用尽时返回最后一次的错误
+     */
+    pub async fn run<T, E, F, Fut>(&self, operation: &str, mut call: F) -> std::result::Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = std::result::Result<T, E>>,
+        E: Retryable + Display,
+    {
+        let mut attempt = 1;
+        let mut delay = self.base_delay;
+
+        loop {
+            match call().await {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt < self.attempts && e.is_retryable() => {
+                    tracing::warn!(
+                        "RPC call {} failed (attempt {}/{}), retrying in {:?}: {}",
+                        operation, attempt, self.attempts, delay, e
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/**
+ * 判断RPC错误是否为临时性的传输错误，重试可能成功
+ */
+pub trait Retryable {
+    fn is_retryable(&self) -> bool;
+}
+
+impl Retryable for ProviderError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            // 节点返回的JSON-RPC错误是确定性的，只有限流值得重试；没有错误响应说明是传输层失败
+            ProviderError::JsonRpcClientError(e) => e
+                .as_error_response()
+                .map_or(true, |response| RATE_LIMIT_ERROR_CODES.contains(&response.code)),
+            ProviderError::HTTPError(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl<M: Middleware> Retryable for ContractError<M> {
+    fn is_retryable(&self) -> bool {
+        match self {
+            ContractError::MiddlewareError { e } => e.as_provider_error().is_some_and(Retryable::is_retryable),
+            ContractError::ProviderError { e } => e.is_retryable(),
+            // 回滚、ABI编解码等错误重试结果不变
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::providers::{JsonRpcError, MockResponse, Provider};
+    use ethers::types::U64;
+
+    #[tokio::test]
+    async fn retries_transient_errors_until_success() {
+        let (provider, mock) = Provider::mocked();
+        let policy = RetryPolicy { attempts: 3, base_delay: Duration::from_millis(1) };
+
+        // MockProvider按后进先出的顺序返回响应：先返回两次限流错误，再返回区块号
+        mock.push::<U64, _>(U64::from(42)).unwrap();
+        for _ in 0..2 {
+            mock.push_response(MockResponse::Error(JsonRpcError {
+                code: -32005,
+                message: "rate limited".to_string(),
+                data: None,
+            }));
+        }
+
+        let block_number = policy.run("eth_blockNumber", || provider.get_block_number()).await.unwrap();
+        assert_eq!(block_number, U64::from(42));
+    }
+}
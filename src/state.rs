@@ -1,14 +1,30 @@
+use crate::audit;
 use crate::auth::AuthService;
-use crate::models::{ServerMessage, UserAuth};
+use crate::config::Config;
+use crate::content_filter::ContentFilter;
+use crate::error::{AppError, Result};
+use crate::models::{
+    RoomConfig, RoomMember, RoomStats, RuntimeConfig, RuntimeConfigUpdate, ServerMessage, ServerStats, TokenGate, UserAuth,
+    MAINNET_CHAIN_ID,
+};
+use crate::pubsub;
 use bb8::Pool;
 use bb8_redis::RedisConnectionManager;
+use ethers::types::Address;
+use redis::AsyncCommands;
 use std::collections::{HashMap, HashSet};
-use std::time::Instant;
-use tokio::sync::{broadcast, RwLock};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, watch, RwLock};
 use uuid::Uuid;
 
 /**
  * 客户端连接信息
+ * 同一地址的多个连接共享同一个客户端条目：所有连接订阅同一个消息通道接收房间消息，
+ * 请求的回复（Pong、Ack、分页历史等）通过各连接自己的回复通道发送，
+ * 房间成员关系按地址维护，最后一个连接断开时才离开房间
  */
 #[derive(Debug, Clone)]
 pub struct Client {
@@ -17,6 +33,9 @@ pub struct Client {
     pub ens_name: Option<String>,
     pub current_rooms: HashSet<String>,
     pub sender: broadcast::Sender<ServerMessage>,
+    pub last_activity: Instant,
+    pub connections: usize, // 该地址当前的连接数
+    pub blocked: HashSet<String>, // 该用户屏蔽的地址（小写）
 }
 
 /**
@@ -28,6 +47,11 @@ pub struct Room {
     pub users: HashSet<String>, // 用户地址集合
     pub message_history: Vec<ServerMessage>, // 最近的消息历史
     pub max_history: usize,
+    pub message_authors: HashMap<String, String>, // 消息ID -> 作者地址
+    pub moderators: HashSet<String>, // 管理员地址集合（小写）
+    pub config: Option<RoomConfig>, // 房间配置（门禁、人数上限等）
+    pub slow_mode_secs: u64, // 慢速模式间隔，0表示关闭
+    pub topic: Option<String>, // 房间话题，初始为配置中的描述
 }
 
 /**
@@ -40,6 +64,12 @@ pub struct AppState {
     /// 认证服务
     pub auth_service: AuthService,
     
+    /// 应用配置
+    pub config: Config,
+    
+    /// 运行时可修改的配置
+    pub runtime_config: RwLock<RuntimeConfig>,
+    
     /// 已连接的客户端 (user_address -> Client)
     pub clients: RwLock<HashMap<String, Client>>,
     
@@ -51,43 +81,104 @@ pub struct AppState {
     
     /// 全局消息广播通道
     pub global_sender: broadcast::Sender<ServerMessage>,
+    
+    /// 实例id，用于在Redis广播中识别本实例发出的消息
+    pub instance_id: String,
+    
+    /// 消息内容过滤器
+    pub content_filter: Arc<dyn ContentFilter>,
+    
+    /// 服务启动时间
+    pub started_at: Instant,
+    
+    /// 已处理的链上事件数
+    pub blockchain_events_processed: AtomicU64,
+    
+    /// Token门禁索引：门禁合约地址（小写） -> 本实例中以其为门禁的房间
+    pub gated_rooms: RwLock<HashMap<String, HashSet<String>>>,
+    
+    /// 关闭信号，后台任务订阅后在关闭时退出
+    shutdown: watch::Sender<bool>,
 }
 
 impl AppState {
     /**
      * 创建新的应用状态实例
      */
-    pub fn new(redis_pool: Pool<RedisConnectionManager>, auth_service: AuthService) -> Self {
-        let (global_sender, _) = broadcast::channel(1000);
+    pub fn new(
+        redis_pool: Pool<RedisConnectionManager>,
+        auth_service: AuthService,
+        config: Config,
+        content_filter: Arc<dyn ContentFilter>,
+    ) -> Self {
+        let (global_sender, _) = broadcast::channel(config.global_broadcast_capacity);
+        let (shutdown, _) = watch::channel(false);
         
+        // 创建启动时配置的房间，默认房间总是存在
         let mut rooms = HashMap::new();
-        // 创建默认房间
-        rooms.insert(
-            "general".to_string(),
-            Room {
-                name: "general".to_string(),
-                users: HashSet::new(),
-                message_history: Vec::new(),
-                max_history: 100,
-            },
-        );
+        let mut gated_rooms: HashMap<String, HashSet<String>> = HashMap::new();
+        for startup_room in &config.startup_rooms {
+            if let Some(contract) = startup_room.config.as_ref().and_then(gated_contract) {
+                gated_rooms.entry(contract).or_default().insert(startup_room.name.clone());
+            }
+            rooms.insert(
+                startup_room.name.clone(),
+                Room::new(&startup_room.name, startup_room.config.clone(), config.default_max_history),
+            );
+        }
+        rooms
+            .entry(config.default_room.clone())
+            .or_insert_with(|| Room::new(&config.default_room, None, config.default_max_history));
         
         Self {
             redis_pool,
             auth_service,
+            runtime_config: RwLock::new(config.runtime_config()),
+            config,
             clients: RwLock::new(HashMap::new()),
             rooms: RwLock::new(rooms),
             user_auth_cache: RwLock::new(HashMap::new()),
             global_sender,
+            instance_id: Uuid::new_v4().to_string(),
+            content_filter,
+            started_at: Instant::now(),
+            blockchain_events_processed: AtomicU64::new(0),
+            gated_rooms: RwLock::new(gated_rooms),
+            shutdown,
         }
     }
     
     /**
-     * 添加客户端连接 - 优化版本
+     * 添加客户端连接
+     * 地址已有连接时复用其客户端条目，超过每个地址的连接上限时拒绝
      */
-    pub async fn add_client(&self, user_address: String, ens_name: Option<String>) -> String {
+    pub async fn add_client(&self, user_address: String, ens_name: Option<String>) -> Result<String> {
+        let mut clients = self.clients.write().await;
+        
+        if let Some(client) = clients.get_mut(&user_address) {
+            let max_connections = self.config.max_connections_per_address;
+            if max_connections > 0 && client.connections >= max_connections {
+                return Err(AppError::TooManyConnections(format!(
+                    "At most {} connections are allowed per address",
+                    max_connections
+                )));
+            }
+            
+            client.connections += 1;
+            client.last_activity = Instant::now();
+            if ens_name.is_some() {
+                client.ens_name = ens_name;
+            }
+            
+            tracing::info!(
+                "Client {} opened connection {} for {}",
+                client.id, client.connections, user_address
+            );
+            return Ok(client.id.clone());
+        }
+        
         let client_id = Uuid::new_v4().to_string();
-        let (sender, _) = broadcast::channel(128); // 增加缓冲区大小
+        let (sender, _) = broadcast::channel(self.config.client_broadcast_capacity);
         
         let client = Client {
             id: client_id.clone(),
@@ -95,62 +186,175 @@ impl AppState {
             ens_name,
             current_rooms: HashSet::new(),
             sender,
+            last_activity: Instant::now(),
+            connections: 1,
+            blocked: HashSet::new(),
         };
         
-        let mut clients = self.clients.write().await;
-        clients.insert(user_address, client);
+        clients.insert(user_address.clone(), client);
         
         tracing::info!("Client added: {} (total: {})", client_id, clients.len());
-        client_id
+        drop(clients); // 清理前释放锁，保持先房间后客户端的加锁顺序
+        
+        // 上一次会话可能残留房间成员关系，新会话从空的房间列表开始
+        self.reconcile_client(&user_address).await;
+        
+        Ok(client_id)
+    }
+    
+    /**
+     * 使房间成员列表与客户端的current_rooms保持一致
+     * 移除地址在未加入的房间中残留的成员关系，并通知这些房间
+     */
+    pub async fn reconcile_client(&self, user_address: &str) {
+        let stale_rooms: Vec<String> = {
+            let mut rooms = self.rooms.write().await;
+            let clients = self.clients.read().await;
+            let current_rooms = clients.get(user_address).map(|client| &client.current_rooms);
+            
+            rooms
+                .values_mut()
+                .filter(|room| room.users.contains(user_address))
+                .filter(|room| !current_rooms.is_some_and(|current| current.contains(&room.name)))
+                .map(|room| {
+                    room.users.remove(user_address);
+                    room.name.clone()
+                })
+                .collect()
+        };
+        
+        for room_name in stale_rooms {
+            tracing::info!("Removed stale membership of {} in room {}", user_address, room_name);
+            self.broadcast_user_left(&room_name, user_address).await;
+        }
     }
 
     /**
      * 更新客户端活动时间
      */
     pub async fn update_client_activity(&self, user_address: &str) {
-        // 这个函数现在是空的，因为Client结构没有活动时间字段
-        // 但我们保留接口以备将来扩展
+        // 距上次记录超过刷新间隔时同步最后在线时间，避免每条消息都写Redis
+        let stale_rooms = {
+            let mut clients = self.clients.write().await;
+            match clients.get_mut(user_address) {
+                Some(client) => {
+                    let stale = client.last_activity.elapsed() >= LAST_SEEN_REFRESH;
+                    client.last_activity = Instant::now();
+                    stale.then(|| client.current_rooms.iter().cloned().collect::<Vec<_>>())
+                }
+                None => None,
+            }
+        };
+        
+        if let Some(rooms) = stale_rooms {
+            if let Err(e) = self.record_last_seen(user_address, &rooms).await {
+                tracing::warn!("Failed to record last seen for {}: {}", user_address, e);
+            }
+        }
         tracing::trace!("Activity updated for client: {}", user_address);
     }
     
     /**
-     * 移除客户端连接
+     * 移除客户端连接，该地址的最后一个连接断开时才离开所有房间
      */
     pub async fn remove_client(&self, user_address: &str) {
+        {
+            let mut clients = self.clients.write().await;
+            match clients.get_mut(user_address) {
+                Some(client) if client.connections > 1 => {
+                    client.connections -= 1;
+                    return;
+                }
+                Some(_) => {}
+                None => return,
+            }
+        }
+        
+        self.evict_client(user_address).await;
+    }
+    
+    /**
+     * 移除地址的客户端条目（包括所有连接），并离开所有房间
+     */
+    pub async fn evict_client(&self, user_address: &str) {
         let mut clients = self.clients.write().await;
         if let Some(client) = clients.remove(user_address) {
             // 从所有房间中移除用户
             let rooms_to_leave: Vec<String> = client.current_rooms.into_iter().collect();
             drop(clients); // 释放锁
             
+            // 记录最后在线时间，房间成员列表中显示为离开而不是直接消失
+            if let Err(e) = self.record_last_seen(user_address, &rooms_to_leave).await {
+                tracing::warn!("Failed to record last seen for {}: {}", user_address, e);
+            }
+            
             for room_name in rooms_to_leave {
-                self.leave_room(user_address, &room_name).await;
+                if self.leave_room(user_address, &room_name).await {
+                    self.broadcast_user_left(&room_name, user_address).await;
+                }
             }
         }
     }
     
     /**
      * 用户加入房间
+     * 房间不存在时会在同一次加锁中创建，并附加已存储的房间配置
      */
     pub async fn join_room(&self, user_address: &str, room_name: &str) -> bool {
+        // 在获取写锁之前加载已存储的配置，避免持锁期间访问Redis
+        let room_exists = self.rooms.read().await.contains_key(room_name);
+        let stored_config = if room_exists {
+            None
+        } else {
+            self.load_room_config(room_name).await.unwrap_or_else(|e| {
+                tracing::warn!("Failed to load config for room {}: {}", room_name, e);
+                None
+            })
+        };
+        if let Some(config) = &stored_config {
+            self.index_gated_room(config).await;
+        }
+        
         let mut rooms = self.rooms.write().await;
         let mut clients = self.clients.write().await;
         
-        // 确保房间存在
-        if !rooms.contains_key(room_name) {
-            rooms.insert(
-                room_name.to_string(),
-                Room {
-                    name: room_name.to_string(),
-                    users: HashSet::new(),
-                    message_history: Vec::new(),
-                    max_history: 100,
-                },
-            );
+        // 超过每个用户的房间上限时拒绝加入，默认房间不受限，已在房间内的用户可重复加入
+        if let Some(client) = clients.get(user_address) {
+            if !self.is_default_room(room_name)
+                && !client.current_rooms.contains(room_name)
+                && self.room_limit_reached(client)
+            {
+                return false;
+            }
+        }
+        
+        // 确保房间存在，配置与房间的创建在同一把锁内完成
+        match rooms.get_mut(room_name) {
+            Some(room) => {
+                if room.config.is_none() {
+                    if let Some(config) = stored_config {
+                        room.attach_config(config);
+                    }
+                }
+            }
+            None => {
+                if stored_config.is_none() && !self.config.auto_create_rooms {
+                    return false;
+                }
+                rooms.insert(room_name.to_string(), Room::new(room_name, stored_config, self.config.default_max_history));
+            }
         }
         
-        // 添加用户到房间
+        // 没有客户端条目的地址不能加入房间，避免留下无人接收的成员
+        if !clients.contains_key(user_address) {
+            return false;
+        }
+        
+        // 添加用户到房间，已在房间内的用户可重复加入
         if let Some(room) = rooms.get_mut(room_name) {
+            if !room.users.contains(user_address) && room.is_full(self.config.default_room_max_users) {
+                return false;
+            }
             room.users.insert(user_address.to_string());
         }
         
@@ -163,140 +367,1451 @@ impl AppState {
         false
     }
     
+    /**
+     * 用户加入的房间数是否已达上限，默认房间不计入，上限为0时不限制
+     */
+    pub fn room_limit_reached(&self, client: &Client) -> bool {
+        let joined = client
+            .current_rooms
+            .iter()
+            .filter(|room| !self.is_default_room(room))
+            .count();
+        self.config.max_rooms_per_user > 0 && joined >= self.config.max_rooms_per_user
+    }
+    
+    /**
+     * 注册带配置的房间
+     * 若同名的隐式房间已存在则在同一把锁内为其附加配置，已配置的房间返回false
+     */
+    pub async fn register_room(&self, config: RoomConfig) -> bool {
+        self.index_gated_room(&config).await;
+        let mut rooms = self.rooms.write().await;
+        
+        match rooms.get_mut(&config.name) {
+            Some(room) if room.config.is_some() => false,
+            Some(room) => {
+                room.attach_config(config);
+                true
+            }
+            None => {
+                let name = config.name.clone();
+                rooms.insert(name.clone(), Room::new(&name, Some(config), self.config.default_max_history));
+                true
+            }
+        }
+    }
+    
+    /**
+     * 创建带配置的房间
+     * 配置以SET NX写入Redis，保证多个实例间房间名唯一；房间已存在时返回false
+     */
+    pub async fn create_room(&self, config: RoomConfig) -> Result<bool> {
+        let mut conn = self.redis_pool.get().await?;
+        
+        let stored: Option<String> = redis::cmd("SET")
+            .arg(room_config_key(&config.name))
+            .arg(serde_json::to_string(&config)?)
+            .arg("NX")
+            .query_async(&mut *conn)
+            .await?;
+        
+        if stored.is_none() {
+            return Ok(false);
+        }
+        
+        Ok(self.register_room(config).await)
+    }
+    
+    /**
+     * 获取房间的Token门禁，优先使用内存中的配置
+     */
+    pub async fn get_room_token_gate(&self, room_name: &str) -> Result<Option<TokenGate>> {
+        {
+            let rooms = self.rooms.read().await;
+            if let Some(config) = rooms.get(room_name).and_then(|room| room.config.as_ref()) {
+                return Ok(config.token_gate.clone());
+            }
+        }
+        
+        Ok(self.load_room_config(room_name).await?.and_then(|config| config.token_gate))
+    }
+    
+    /**
+     * 检查用户是否满足房间的Token门禁
+     * 成功的检查结果按(用户, 房间)缓存一段时间，避免每次加入都请求RPC
+     */
+    pub async fn check_room_token_gate(&self, user_address: &str, room_name: &str) -> Result<()> {
+        let gate = match self.get_room_token_gate(room_name).await? {
+            Some(gate) => gate,
+            None => return Ok(()),
+        };
+        
+        let cache_key = format!("gate:{}:{}", room_name, user_address.to_lowercase());
+        
+        {
+            let mut conn = self.redis_pool.get().await?;
+            let cached: bool = conn.exists(&cache_key).await?;
+            if cached {
+                return Ok(());
+            }
+        }
+        
+        // 优先使用登录时缓存的认证地址
+        let address = self.get_cached_user_auth(user_address).await
+            .map(|auth| auth.address)
+            .unwrap_or_else(|| user_address.to_string());
+        let address = Address::from_str(&address)
+            .map_err(|e| AppError::InvalidRequest(e.to_string()))?;
+        
+        let allowed = self.auth_service.check_token_gate(&address, &gate).await?;
+        audit::log_event(
+            &self.redis_pool,
+            "token_gate",
+            user_address,
+            if allowed { "success" } else { "denied" },
+            serde_json::json!({ "room": room_name, "contract": gate.contract_address, "chain_id": gate.chain_id }),
+        ).await;
+        
+        if !allowed {
+            return Err(AppError::TokenGateFailed(format!(
+                "Access to room {} requires holding {}",
+                room_name, gate.contract_address
+            )));
+        }
+        
+        let mut conn = self.redis_pool.get().await?;
+        let _: () = redis::cmd("SETEX")
+            .arg(&cache_key)
+            .arg(self.config.token_gate_cache_ttl_secs)
+            .arg("1")
+            .query_async(&mut *conn)
+            .await?;
+        
+        Ok(())
+    }
+    
+    /**
+     * 列出所有已知房间：本实例内存中的房间及Redis中存有配置的房间
+     */
+    pub async fn list_room_names(&self) -> Result<Vec<String>> {
+        let mut names: HashSet<String> = self.rooms.read().await.keys().cloned().collect();
+        
+        let mut conn = self.redis_pool.get().await?;
+        let pattern = room_config_key("*");
+        let mut cursor: u64 = 0;
+        loop {
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(ROOM_SCAN_BATCH)
+                .query_async(&mut *conn)
+                .await?;
+            
+            names.extend(keys.iter().filter_map(|key| {
+                key.strip_prefix("room:")?.strip_suffix(":config").map(str::to_string)
+            }));
+            
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        
+        let mut names: Vec<String> = names.into_iter().collect();
+        names.sort();
+        Ok(names)
+    }
+    
+    /**
+     * 从Redis加载已存储的房间配置
+     */
+    pub async fn load_room_config(&self, room_name: &str) -> Result<Option<RoomConfig>> {
+        let mut conn = self.redis_pool.get().await?;
+        
+        let raw: Option<String> = conn.get(room_config_key(room_name)).await?;
+        
+        match raw {
+            Some(raw) => Ok(Some(serde_json::from_str(&raw)?)),
+            None => Ok(None),
+        }
+    }
+    
     /**
      * 用户离开房间
      */
-    pub async fn leave_room(&self, user_address: &str, room_name: &str) {
+    pub async fn leave_room(&self, user_address: &str, room_name: &str) -> bool {
         let mut rooms = self.rooms.write().await;
         let mut clients = self.clients.write().await;
         
         // 从房间中移除用户
+        let mut was_member = false;
+        let mut reaped = false;
         if let Some(room) = rooms.get_mut(room_name) {
-            room.users.remove(user_address);
+            was_member = room.users.remove(user_address);
+            
+            // 回收已无人的房间，默认房间始终保留
+            if room.users.is_empty() && !self.is_default_room(room_name) {
+                rooms.remove(room_name);
+                reaped = true;
+                tracing::info!("Removed empty room: {}", room_name);
+            }
         }
         
         // 更新客户端状态
         if let Some(client) = clients.get_mut(user_address) {
-            client.current_rooms.remove(room_name);
+            was_member |= client.current_rooms.remove(room_name);
+        }
+        
+        drop(clients);
+        drop(rooms);
+        if reaped {
+            self.unindex_gated_room(room_name).await;
         }
+        
+        was_member
     }
     
     /**
-     * 获取房间用户列表
+     * 服务运行状态快照，只统计数量，不遍历客户端
      */
-    pub async fn get_room_users(&self, room_name: &str) -> Vec<String> {
-        let rooms = self.rooms.read().await;
-        if let Some(room) = rooms.get(room_name) {
-            room.users.iter().cloned().collect()
-        } else {
-            Vec::new()
+    pub async fn stats(&self) -> ServerStats {
+        let total_clients = self.clients.read().await.len();
+        let rooms: Vec<RoomStats> = {
+            let rooms = self.rooms.read().await;
+            rooms
+                .values()
+                .map(|room| RoomStats {
+                    name: room.name.clone(),
+                    user_count: room.users.len(),
+                })
+                .collect()
+        };
+        
+        ServerStats {
+            total_clients,
+            total_rooms: rooms.len(),
+            rooms,
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            blockchain_events_processed: self.blockchain_events_processed.load(Ordering::Relaxed),
         }
     }
     
     /**
-     * 向房间广播消息
+     * 记录一个已处理的链上事件
      */
-    pub async fn broadcast_to_room(&self, room_name: &str, message: ServerMessage) {
+    pub fn record_blockchain_event(&self) {
+        self.blockchain_events_processed.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /**
+     * 判断用户当前是否在房间中
+     */
+    pub async fn is_room_member(&self, user_address: &str, room_name: &str) -> bool {
         let clients = self.clients.read().await;
-        let mut rooms = self.rooms.write().await;
+        clients
+            .get(user_address)
+            .is_some_and(|client| client.current_rooms.contains(room_name))
+    }
+    
+    /**
+     * 判断是否为默认房间，默认房间不会被删除或回收
+     */
+    pub fn is_default_room(&self, room_name: &str) -> bool {
+        room_name == self.config.default_room
+    }
+    
+    /**
+     * 删除房间，仅房间创建者、房间管理员或全局管理员可操作
+     * 同时删除Redis中存储的房间配置，并通知其他实例
+     */
+    pub async fn delete_room(&self, room_name: &str, requester: &str) -> Result<()> {
+        if self.is_default_room(room_name) {
+            return Err(AppError::InvalidRequest("The default room cannot be deleted".to_string()));
+        }
         
-        // 添加消息到房间历史
-        if let Some(room) = rooms.get_mut(room_name) {
-            room.message_history.push(message.clone());
-            if room.message_history.len() > room.max_history {
-                room.message_history.remove(0);
+        let stored_config = self.load_room_config(room_name).await?;
+        
+        let (exists, is_moderator) = {
+            let rooms = self.rooms.read().await;
+            match rooms.get(room_name) {
+                Some(room) => (true, room.is_moderator(requester)),
+                None => (false, false),
             }
-            
-            // 向房间内所有用户发送消息
-            for user_address in &room.users {
-                if let Some(client) = clients.get(user_address) {
+        };
+        
+        if !exists && stored_config.is_none() {
+            return Err(AppError::NotFound(format!("Room not found: {}", room_name)));
+        }
+        
+        let is_creator = stored_config
+            .as_ref()
+            .is_some_and(|config| config.created_by.eq_ignore_ascii_case(requester));
+        
+        if !is_moderator && !is_creator && !self.config.is_admin(requester) {
+            return Err(AppError::AuthorizationFailed(
+                "Only the room creator or a moderator can delete this room".to_string(),
+            ));
+        }
+        
+        if stored_config.is_some() {
+            let mut conn = self.redis_pool.get().await?;
+            let _: () = conn.del(room_config_key(room_name)).await?;
+        }
+        
+        self.remove_room(room_name).await;
+        self.publish(
+            &pubsub::room_channel(room_name),
+            ServerMessage::RoomDeleted { room: room_name.to_string() },
+        ).await;
+        
+        tracing::info!("Room {} deleted by {}", room_name, requester);
+        
+        Ok(())
+    }
+    
+    /**
+     * 从本实例移除房间，通知房间内的用户并更新其房间列表
+     */
+    pub async fn remove_room(&self, room_name: &str) {
+        let mut rooms = self.rooms.write().await;
+        let mut clients = self.clients.write().await;
+        
+        if let Some(room) = rooms.remove(room_name) {
+            let message = ServerMessage::RoomDeleted { room: room_name.to_string() };
+            for user in &room.users {
+                if let Some(client) = clients.get_mut(user) {
+                    client.current_rooms.remove(room_name);
                     let _ = client.sender.send(message.clone());
                 }
             }
         }
+        
+        drop(clients);
+        drop(rooms);
+        self.unindex_gated_room(room_name).await;
     }
     
     /**
-     * 向所有客户端广播消息
+     * 将带有主网Token门禁的房间加入门禁索引，链上监听器只监听主网
      */
-    pub async fn broadcast_global(&self, message: ServerMessage) {
-        let _ = self.global_sender.send(message);
+    async fn index_gated_room(&self, config: &RoomConfig) {
+        let contract = match gated_contract(config) {
+            Some(contract) => contract,
+            None => return,
+        };
+        
+        self.gated_rooms
+            .write()
+            .await
+            .entry(contract)
+            .or_default()
+            .insert(config.name.clone());
     }
     
     /**
-     * 获取客户端信息
+     * 从门禁索引中移除房间
      */
-    pub async fn get_client(&self, user_address: &str) -> Option<Client> {
-        let clients = self.clients.read().await;
-        clients.get(user_address).cloned()
+    async fn unindex_gated_room(&self, room_name: &str) {
+        let mut gated_rooms = self.gated_rooms.write().await;
+        for rooms in gated_rooms.values_mut() {
+            rooms.remove(room_name);
+        }
+        gated_rooms.retain(|_, rooms| !rooms.is_empty());
     }
     
     /**
-     * 缓存用户认证信息
+     * 查找以任一token为门禁的房间
      */
-    pub async fn cache_user_auth(&self, user_address: String, auth: UserAuth) {
-        let mut cache = self.user_auth_cache.write().await;
-        cache.insert(user_address, auth);
+    pub async fn gated_rooms_for(&self, tokens: &[String]) -> Vec<String> {
+        let gated_rooms = self.gated_rooms.read().await;
+        let mut rooms: Vec<String> = tokens
+            .iter()
+            .filter_map(|token| gated_rooms.get(&token.to_lowercase()))
+            .flatten()
+            .cloned()
+            .collect();
+        rooms.sort();
+        rooms.dedup();
+        rooms
     }
     
     /**
-     * 获取缓存的用户认证信息
+     * 获取房间用户列表
      */
-    pub async fn get_cached_user_auth(&self, user_address: &str) -> Option<UserAuth> {
-        let cache = self.user_auth_cache.read().await;
-        cache.get(user_address).cloned()
+    pub async fn get_room_users(&self, room_name: &str) -> Vec<String> {
+        let rooms = self.rooms.read().await;
+        if let Some(room) = rooms.get(room_name) {
+            room.users.iter().cloned().collect()
+        } else {
+            Vec::new()
+        }
     }
-}
-
-impl Room {
+    
     /**
-     * 获取房间最近的消息历史
+     * 房间最近的消息历史及当前话题，房间不存在时返回None
      */
-    pub fn get_recent_messages(&self, limit: usize) -> Vec<ServerMessage> {
-        let start = if self.message_history.len() > limit {
-            self.message_history.len() - limit
-        } else {
-            0
-        };
-        self.message_history[start..].to_vec()
+    pub async fn room_history(&self, room_name: &str) -> Option<ServerMessage> {
+        let rooms = self.rooms.read().await;
+        rooms.get(room_name).map(|room| ServerMessage::History {
+            room: room_name.to_string(),
+            messages: room.get_recent_messages(self.config.history_limit),
+            topic: room.topic.clone(),
+        })
     }
-}
-
-impl AppState {
+    
     /**
-     * 获取房间在线用户详细信息
+     * 向指定用户的所有连接发送房间最近的消息历史
      */
-    pub async fn get_online_users(&self, room_name: &str) -> Vec<crate::models::OnlineUser> {
-        let rooms = self.rooms.read().await;
-        let clients = self.clients.read().await;
+    pub async fn send_room_history(&self, user_address: &str, room_name: &str) {
+        let history = match self.room_history(room_name).await {
+            Some(history) => history,
+            None => return,
+        };
         
-        if let Some(room) = rooms.get(room_name) {
-            room.users.iter()
-                .filter_map(|addr| {
-                    clients.get(addr).map(|client| crate::models::OnlineUser {
-                        address: addr.clone(),
-                        ens_name: client.ens_name.clone(),
-                    })
-                })
-                .collect()
-        } else {
-            Vec::new()
+        if let Some(client) = self.get_client(user_address).await {
+            let _ = client.sender.send(history);
         }
     }
-
+    
     /**
-     * 广播用户加入房间
+     * 构造让落后的连接重新同步状态的消息
+     * 依次为Resync通知、当前运行时配置，以及用户所在每个房间的消息历史和在线用户列表
      */
-    pub async fn broadcast_user_joined(&self, room_name: &str, user_address: &str) {
-        let client_ens = {
-            let clients = self.clients.read().await;
-            clients.get(user_address).and_then(|c| c.ens_name.clone())
+    pub async fn resync_messages(&self, user_address: Option<&str>, reason: &str) -> Vec<ServerMessage> {
+        let mut messages = vec![
+            ServerMessage::Resync {
+                reason: reason.to_string(),
+            },
+            ServerMessage::ConfigUpdated(self.get_runtime_config().await),
+        ];
+        
+        let room_names: Vec<String> = match user_address {
+            Some(address) => match self.get_client(address).await {
+                Some(client) => client.current_rooms.into_iter().collect(),
+                None => Vec::new(),
+            },
+            None => Vec::new(),
+        };
+        
+        for room_name in room_names {
+            let (history, topic) = {
+                let rooms = self.rooms.read().await;
+                match rooms.get(&room_name) {
+                    Some(room) => (room.get_recent_messages(self.config.history_limit), room.topic.clone()),
+                    None => continue,
+                }
+            };
+            
+            messages.push(ServerMessage::History {
+                room: room_name.clone(),
+                messages: history,
+                topic,
+            });
+            messages.push(ServerMessage::OnlineUsers {
+                users: self.get_online_users(&room_name).await,
+                room: room_name,
+            });
+        }
+        
+        messages
+    }
+    
+    /**
+     * 获取房间的聊天消息历史，支持以消息id为游标向前翻页
+     * 请求的条数会被限制在房间的历史上限和HISTORY_PAGE_MAX以内，超出时按上限返回
+     * 房间不存在时返回None
+     */
+    pub async fn get_room_messages(
+        &self,
+        room_name: &str,
+        limit: usize,
+        before: Option<&str>,
+    ) -> Option<Vec<ServerMessage>> {
+        let rooms = self.rooms.read().await;
+        rooms.get(room_name).map(|room| {
+            let limit = limit
+                .min(room.max_history)
+                .min(self.config.history_page_max)
+                .max(1);
+            room.messages_before(before, limit)
+        })
+    }
+    
+    /**
+     * 向房间广播消息，并通过Redis发布给其他实例
+     */
+    pub async fn broadcast_to_room(&self, room_name: &str, message: ServerMessage) {
+        self.deliver_to_room(room_name, message.clone()).await;
+        self.publish(&pubsub::room_channel(room_name), message).await;
+    }
+    
+    /**
+     * 仅向本实例中房间内的用户投递消息
+     */
+    pub async fn deliver_to_room(&self, room_name: &str, message: ServerMessage) {
+        // 与join_room等保持先rooms后clients的加锁顺序，避免与加入房间互相等待
+        let mut rooms = self.rooms.write().await;
+        let clients = self.clients.read().await;
+        
+        // 添加消息到房间历史
+        if let Some(room) = rooms.get_mut(room_name) {
+            // 其他实例上删除或编辑的消息同样同步到本地历史
+            match &message {
+                ServerMessage::MessageDeleted { message_id, .. } => room.remove_message(message_id),
+                ServerMessage::MessageEdited { message_id, new_text, edited_at, .. } => {
+                    room.edit_message(message_id, new_text, *edited_at);
+                }
+                ServerMessage::TopicChanged { topic, .. } => room.topic = topic.clone(),
+                _ => {}
+            }
+            
+            // 记录作者，其他实例发来的消息在本实例同样可以被编辑和删除
+            if let (Some(id), Some(author)) = (message.chat_message_id(), message.chat_author()) {
+                room.message_authors.insert(id.to_string(), author.to_string());
+            }
+            room.push_history(message.clone());
+            
+            // 聊天消息不发送给屏蔽了作者的用户
+            let author = message.chat_author().map(|author| author.to_lowercase());
+            
+            // 向房间内所有用户发送消息
+            for user_address in &room.users {
+                if let Some(client) = clients.get(user_address) {
+                    if author.as_ref().is_some_and(|author| client.blocked.contains(author)) {
+                        continue;
+                    }
+                    let _ = client.sender.send(message.clone());
+                }
+            }
+        }
+    }
+    
+    /**
+     * 仅将消息写入房间历史而不推送给在线用户
+     * 房间不存在时按加入房间的规则创建，用于回填启动前的链上事件
+     */
+    pub async fn record_room_history(&self, room_name: &str, message: ServerMessage) {
+        let room_exists = self.rooms.read().await.contains_key(room_name);
+        let stored_config = if room_exists {
+            None
+        } else {
+            self.load_room_config(room_name).await.unwrap_or_else(|e| {
+                tracing::warn!("Failed to load config for room {}: {}", room_name, e);
+                None
+            })
+        };
+        if let Some(config) = &stored_config {
+            self.index_gated_room(config).await;
+        }
+        
+        let mut rooms = self.rooms.write().await;
+        if !rooms.contains_key(room_name) {
+            if stored_config.is_none() && !self.config.auto_create_rooms {
+                return;
+            }
+            rooms.insert(room_name.to_string(), Room::new(room_name, stored_config, self.config.default_max_history));
+        }
+        
+        if let Some(room) = rooms.get_mut(room_name) {
+            room.push_history(message);
+        }
+    }
+    
+    /**
+     * 向房间广播聊天消息，并记录消息作者以便后续删除
+     */
+    pub async fn broadcast_chat_message(&self, room_name: &str, author: &str, message: ServerMessage) {
+        if let Some(id) = message.chat_message_id() {
+            let mut rooms = self.rooms.write().await;
+            if let Some(room) = rooms.get_mut(room_name) {
+                room.message_authors.insert(id.to_string(), author.to_string());
+            }
+        }
+        
+        self.broadcast_to_room(room_name, message).await;
+    }
+    
+    /**
+     * 编辑房间中的消息，只有作者可以编辑，返回编辑时间
+     */
+    pub async fn edit_message(
+        &self,
+        room_name: &str,
+        message_id: &str,
+        requester: &str,
+        new_text: &str,
+    ) -> Result<chrono::DateTime<chrono::Utc>> {
+        let mut rooms = self.rooms.write().await;
+        let room = rooms.get_mut(room_name)
+            .ok_or_else(|| AppError::InvalidRequest(format!("Room not found: {}", room_name)))?;
+        
+        let author = room.message_authors.get(message_id)
+            .ok_or_else(|| AppError::InvalidRequest("Message not found".to_string()))?;
+        
+        if !author.eq_ignore_ascii_case(requester) {
+            return Err(AppError::AuthorizationFailed(
+                "Only the author can edit this message".to_string(),
+            ));
+        }
+        
+        let edited_at = chrono::Utc::now();
+        room.edit_message(message_id, new_text, edited_at);
+        
+        Ok(edited_at)
+    }
+    
+    /**
+     * 删除房间中的消息，只有作者或房间管理员可以删除
+     */
+    pub async fn delete_message(&self, room_name: &str, message_id: &str, requester: &str) -> Result<()> {
+        let mut rooms = self.rooms.write().await;
+        let room = rooms.get_mut(room_name)
+            .ok_or_else(|| AppError::InvalidRequest(format!("Room not found: {}", room_name)))?;
+        
+        let author = room.message_authors.get(message_id)
+            .ok_or_else(|| AppError::InvalidRequest("Message not found".to_string()))?;
+        
+        if !author.eq_ignore_ascii_case(requester)
+            && !room.is_moderator(requester)
+            && !self.config.is_admin(requester)
+        {
+            return Err(AppError::AuthorizationFailed(
+                "Only the author or a moderator can delete this message".to_string(),
+            ));
+        }
+        
+        room.remove_message(message_id);
+        
+        Ok(())
+    }
+    
+    /**
+     * 判断用户是否可以管理房间（房间管理员或全局管理员）
+     */
+    pub async fn is_room_moderator(&self, room_name: &str, user_address: &str) -> bool {
+        if self.config.is_admin(user_address) {
+            return true;
+        }
+        
+        let rooms = self.rooms.read().await;
+        rooms.get(room_name).is_some_and(|room| room.is_moderator(user_address))
+    }
+    
+    /**
+     * 在房间内禁言用户一段时间
+     */
+    pub async fn mute_user(&self, room_name: &str, user_address: &str, duration_secs: u64) -> Result<()> {
+        let mut conn = self.redis_pool.get().await?;
+        
+        let _: () = redis::cmd("SETEX")
+            .arg(mute_key(room_name, user_address))
+            .arg(duration_secs)
+            .arg("1")
+            .query_async(&mut *conn)
+            .await?;
+        
+        Ok(())
+    }
+    
+    /**
+     * 记录地址的最后在线时间，并更新其所在房间的最近成员
+     * 每个房间只保留最近的RECENT_MEMBERS_LIMIT个成员
+     */
+    pub async fn record_last_seen(&self, user_address: &str, rooms: &[String]) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        let mut conn = self.redis_pool.get().await?;
+        
+        let mut pipe = redis::pipe();
+        pipe.cmd("SETEX").arg(last_seen_key(user_address)).arg(LAST_SEEN_TTL_SECS).arg(now).ignore();
+        for room_name in rooms {
+            let key = recent_members_key(room_name);
+            pipe.zadd(&key, user_address, now).ignore()
+                .zremrangebyrank(&key, 0, -(RECENT_MEMBERS_LIMIT + 1)).ignore()
+                .cmd("EXPIRE").arg(&key).arg(LAST_SEEN_TTL_SECS).ignore();
+        }
+        let _: () = pipe.query_async(&mut *conn).await?;
+        
+        Ok(())
+    }
+    
+    /**
+     * 获取地址的最后在线时间，没有记录或已过期时返回None
+     */
+    pub async fn get_last_seen(&self, user_address: &str) -> Result<Option<chrono::DateTime<chrono::Utc>>> {
+        let mut conn = self.redis_pool.get().await?;
+        let timestamp: Option<i64> = conn.get(last_seen_key(user_address)).await?;
+        
+        Ok(timestamp.and_then(|ts| chrono::DateTime::from_timestamp(ts, 0)))
+    }
+    
+    /**
+     * 获取房间成员：在线用户在前，其后是最近离开的成员（按最后在线时间从近到远）
+     */
+    pub async fn get_room_members(&self, room_name: &str) -> Result<Vec<RoomMember>> {
+        let online = self.get_online_users(room_name).await;
+        let online_addresses: HashSet<String> = online.iter().map(|user| user.address.to_lowercase()).collect();
+        
+        let recent: Vec<(String, i64)> = {
+            let mut conn = self.redis_pool.get().await?;
+            conn.zrevrange_withscores(recent_members_key(room_name), 0, RECENT_MEMBERS_LIMIT - 1).await?
+        };
+        
+        let mut members: Vec<RoomMember> = online
+            .into_iter()
+            .map(|user| RoomMember {
+                address: user.address,
+                ens_name: user.ens_name,
+                avatar: user.avatar,
+                avatar_seed: user.avatar_seed,
+                online: true,
+                last_seen: None,
+            })
+            .collect();
+        
+        members.extend(
+            recent
+                .into_iter()
+                .filter(|(address, _)| !online_addresses.contains(&address.to_lowercase()))
+                .map(|(address, last_seen)| RoomMember {
+                    ens_name: None,
+                    avatar: self.config.default_avatar(&address),
+                    avatar_seed: crate::models::avatar_seed(&address),
+                    online: false,
+                    last_seen: chrono::DateTime::from_timestamp(last_seen, 0),
+                    address,
+                }),
+        );
+        
+        Ok(members)
+    }
+    
+    /**
+     * 判断用户在房间内是否被禁言
+     */
+    pub async fn is_muted(&self, room_name: &str, user_address: &str) -> Result<bool> {
+        let mut conn = self.redis_pool.get().await?;
+        
+        Ok(conn.exists(mute_key(room_name, user_address)).await?)
+    }
+    
+    /**
+     * 屏蔽或取消屏蔽地址，保存到Redis并同步到在线的客户端条目
+     * 返回更新后的屏蔽列表
+     */
+    pub async fn set_blocked(&self, user_address: &str, target: &str, blocked: bool) -> Result<Vec<String>> {
+        let key = blocks_key(user_address);
+        let target = target.to_lowercase();
+        
+        let mut conn = self.redis_pool.get().await?;
+        
+        let _: i64 = if blocked {
+            conn.sadd(&key, &target).await?
+        } else {
+            conn.srem(&key, &target).await?
+        };
+        
+        let list: Vec<String> = conn.smembers(&key).await?;
+        
+        let mut clients = self.clients.write().await;
+        if let Some(client) = clients.get_mut(user_address) {
+            client.blocked = list.iter().cloned().collect();
+        }
+        
+        Ok(list)
+    }
+    
+    /**
+     * 从Redis加载用户的屏蔽列表到客户端条目，认证后调用
+     */
+    pub async fn load_blocked(&self, user_address: &str) -> Result<()> {
+        let mut conn = self.redis_pool.get().await?;
+        let list: Vec<String> = conn.smembers(blocks_key(user_address)).await?;
+        
+        let mut clients = self.clients.write().await;
+        if let Some(client) = clients.get_mut(user_address) {
+            client.blocked = list.into_iter().collect();
+        }
+        
+        Ok(())
+    }
+    
+    /**
+     * 设置房间的慢速模式，0表示关闭
+     * 已存储配置的房间同时更新Redis中的配置
+     */
+    pub async fn set_slow_mode(&self, room_name: &str, secs: u64) -> Result<()> {
+        let stored_config = {
+            let mut rooms = self.rooms.write().await;
+            let room = rooms.get_mut(room_name)
+                .ok_or_else(|| AppError::InvalidRequest(format!("Room not found: {}", room_name)))?;
+            
+            room.slow_mode_secs = secs;
+            room.config.as_mut().map(|config| {
+                config.slow_mode_secs = Some(secs);
+                config.clone()
+            })
+        };
+        
+        if let Some(config) = stored_config {
+            let mut conn = self.redis_pool.get().await?;
+            let _: () = conn.set(room_config_key(room_name), serde_json::to_string(&config)?).await?;
+        }
+        
+        Ok(())
+    }
+    
+    /**
+     * 设置房间话题，None表示清除
+     * 已存储配置的房间同时更新Redis中配置的描述
+     */
+    pub async fn set_topic(&self, room_name: &str, topic: Option<String>) -> Result<()> {
+        let stored_config = {
+            let mut rooms = self.rooms.write().await;
+            let room = rooms.get_mut(room_name)
+                .ok_or_else(|| AppError::InvalidRequest(format!("Room not found: {}", room_name)))?;
+            
+            room.topic = topic.clone();
+            room.config.as_mut().map(|config| {
+                config.description = topic;
+                config.clone()
+            })
+        };
+        
+        if let Some(config) = stored_config {
+            let mut conn = self.redis_pool.get().await?;
+            let _: () = conn.set(room_config_key(room_name), serde_json::to_string(&config)?).await?;
+        }
+        
+        Ok(())
+    }
+    
+    /**
+     * 获取房间当前的话题
+     */
+    pub async fn get_room_topic(&self, room_name: &str) -> Option<String> {
+        let rooms = self.rooms.read().await;
+        rooms.get(room_name).and_then(|room| room.topic.clone())
+    }
+    
+    /**
+     * 检查慢速模式冷却时间，冷却中时返回剩余秒数的错误
+     * 通过后开始新的冷却周期
+     */
+    pub async fn check_slow_mode(&self, room_name: &str, user_address: &str) -> Result<()> {
+        let secs = {
+            let rooms = self.rooms.read().await;
+            rooms.get(room_name).map_or(0, |room| room.slow_mode_secs)
+        };
+        
+        if secs == 0 {
+            return Ok(());
+        }
+        
+        let mut conn = self.redis_pool.get().await?;
+        
+        let key = slow_mode_key(room_name, user_address);
+        let started: Option<String> = redis::cmd("SET")
+            .arg(&key)
+            .arg("1")
+            .arg("NX")
+            .arg("EX")
+            .arg(secs)
+            .query_async(&mut *conn)
+            .await?;
+        
+        if started.is_some() {
+            return Ok(());
+        }
+        
+        let ttl: i64 = conn.ttl(&key).await?;
+        Err(AppError::InvalidRequest(format!(
+            "Slow mode is enabled, please wait {} more seconds",
+            slow_mode_remaining(ttl, secs)
+        )))
+    }
+    
+    /**
+     * 检查是否为重复提交的消息：同一用户在DUPLICATE_MESSAGE_WINDOW_MS内向同一房间发送了相同内容
+     * 不是重复消息时记录本条消息，窗口为0时不检查
+     */
+    pub async fn is_duplicate_message(&self, room_name: &str, user_address: &str, text: &str) -> Result<bool> {
+        let window_ms = self.config.duplicate_message_window_ms;
+        if window_ms == 0 {
+            return Ok(false);
+        }
+        
+        let mut conn = self.redis_pool.get().await?;
+        
+        let recorded: Option<String> = redis::cmd("SET")
+            .arg(duplicate_message_key(room_name, user_address, text))
+            .arg("1")
+            .arg("NX")
+            .arg("PX")
+            .arg(window_ms)
+            .query_async(&mut *conn)
+            .await?;
+        
+        Ok(recorded.is_none())
+    }
+    
+    /**
+     * 向房间广播系统通知
+     */
+    pub async fn broadcast_system_notice(&self, room_name: &str, text: String) {
+        let notice = ServerMessage::new_text("System".to_string(), String::new(), text, room_name.to_string(), None);
+        self.broadcast_to_room(room_name, notice).await;
+    }
+    
+    /**
+     * 订阅关闭信号
+     */
+    pub fn subscribe_shutdown(&self) -> watch::Receiver<bool> {
+        self.shutdown.subscribe()
+    }
+    
+    /**
+     * 触发关闭信号，通知所有订阅者退出
+     */
+    pub fn trigger_shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+    
+    /**
+     * 关闭服务：通知所有客户端后清理在线状态，并通知各后台任务退出
+     */
+    pub async fn shutdown(&self) {
+        // 只通知本实例的客户端，其他实例仍在运行
+        self.deliver_global(ServerMessage::ServerShutdown {
+            message: "Server is shutting down, please reconnect".to_string(),
+        }).await;
+        
+        let user_addresses: Vec<String> = self.clients.read().await.keys().cloned().collect();
+        for user_address in &user_addresses {
+            self.evict_client(user_address).await;
+        }
+        
+        tracing::info!("Removed {} clients during shutdown", user_addresses.len());
+        
+        self.trigger_shutdown();
+    }
+    
+    /**
+     * 向所有客户端广播消息
+     */
+    pub async fn broadcast_global(&self, message: ServerMessage) {
+        self.deliver_global(message.clone()).await;
+        self.publish(pubsub::GLOBAL_CHANNEL, message).await;
+    }
+    
+    /**
+     * 仅向本实例的所有客户端投递消息
+     * 其他实例更新的运行时配置同步到本实例
+     */
+    pub async fn deliver_global(&self, message: ServerMessage) {
+        if let ServerMessage::ConfigUpdated(config) = &message {
+            *self.runtime_config.write().await = config.clone();
+        }
+        let _ = self.global_sender.send(message);
+    }
+    
+    /**
+     * 将广播消息发布到Redis，失败时只记录日志
+     */
+    async fn publish(&self, channel: &str, message: ServerMessage) {
+        if let Err(e) = pubsub::publish(&self.redis_pool, channel, &self.instance_id, message).await {
+            tracing::warn!("Failed to publish message to {}: {}", channel, e);
+        }
+    }
+    
+    /**
+     * 获取当前运行时配置
+     */
+    pub async fn get_runtime_config(&self) -> RuntimeConfig {
+        self.runtime_config.read().await.clone()
+    }
+    
+    /**
+     * 更新运行时配置并通知所有客户端
+     */
+    pub async fn update_runtime_config(&self, update: RuntimeConfigUpdate) -> RuntimeConfig {
+        let updated = {
+            let mut runtime_config = self.runtime_config.write().await;
+            runtime_config.apply(update);
+            runtime_config.clone()
+        };
+        
+        self.broadcast_global(ServerMessage::ConfigUpdated(updated.clone())).await;
+        updated
+    }
+    
+    /**
+     * 切换用户对消息的表情回应，返回回应后该表情的计数
+     * 同一用户再次使用相同表情即取消回应
+     */
+    pub async fn toggle_reaction(
+        &self,
+        message_id: &str,
+        emoji: &str,
+        user_address: &str,
+    ) -> Result<u64> {
+        let tally_key = format!("msg:{}:reactions", message_id);
+        let users_key = format!("msg:{}:reactions:{}", message_id, emoji);
+        let member = user_address.to_lowercase();
+        
+        let mut conn = self.redis_pool.get().await?;
+        
+        let added: i64 = conn.sadd(&users_key, &member).await?;
+        let delta: i64 = if added == 1 {
+            1
+        } else {
+            let _: i64 = conn.srem(&users_key, &member).await?;
+            -1
+        };
+        
+        let count: i64 = conn.hincr(&tally_key, emoji, delta).await?;
+        if count <= 0 {
+            let _: i64 = conn.hdel(&tally_key, emoji).await?;
+        }
+        
+        let _: () = redis::pipe()
+            .cmd("EXPIRE").arg(&tally_key).arg(REACTION_TTL_SECS).ignore()
+            .cmd("EXPIRE").arg(&users_key).arg(REACTION_TTL_SECS).ignore()
+            .query_async(&mut *conn)
+            .await?;
+        
+        Ok(count.max(0) as u64)
+    }
+    
+    /**
+     * 判断消息是否存在于房间的历史记录中
+     */
+    pub async fn room_has_message(&self, room_name: &str, message_id: &str) -> bool {
+        let rooms = self.rooms.read().await;
+        rooms.get(room_name).is_some_and(|room| {
+            room.message_history
+                .iter()
+                .any(|message| message.chat_message_id() == Some(message_id))
+        })
+    }
+    
+    /**
+     * 获取客户端信息
+     */
+    pub async fn get_client(&self, user_address: &str) -> Option<Client> {
+        let clients = self.clients.read().await;
+        clients.get(user_address).cloned()
+    }
+    
+    /**
+     * 向被@提及且已连接的房间成员发送提及通知
+     * 提及可以是地址或ENS名称，均忽略大小写；作者提及自己时不通知
+     * 不在房间中的用户收不到通知，避免泄露受门禁保护房间内的活动
+     */
+    pub async fn notify_mentions(
+        &self,
+        mentions: &[String],
+        room_name: &str,
+        message_id: &str,
+        from: &str,
+        author: &str,
+    ) {
+        if mentions.is_empty() {
+            return;
+        }
+        
+        let rooms = self.rooms.read().await;
+        let clients = self.clients.read().await;
+        let members = match rooms.get(room_name) {
+            Some(room) => &room.users,
+            None => return,
+        };
+        
+        // 与房间消息一致，屏蔽了作者的用户不会收到提及通知
+        let author_key = author.to_lowercase();
+        for client in members.iter().filter_map(|address| clients.get(address)) {
+            if client.user_address.eq_ignore_ascii_case(author) || client.blocked.contains(&author_key) {
+                continue;
+            }
+            
+            let mentioned = mentions.iter().any(|mention| {
+                client.user_address.eq_ignore_ascii_case(mention)
+                    || client.ens_name.as_deref().is_some_and(|ens| ens.eq_ignore_ascii_case(mention))
+            });
+            
+            if mentioned {
+                let _ = client.sender.send(ServerMessage::Mention {
+                    room: room_name.to_string(),
+                    message_id: message_id.to_string(),
+                    from: from.to_string(),
+                });
+            }
+        }
+    }
+    
+    /**
+     * 缓存用户认证信息
+     */
+    pub async fn cache_user_auth(&self, user_address: String, auth: UserAuth) {
+        // 同时写入Redis，重启或其他实例上也能读取
+        if let Err(e) = self.store_user_auth(&user_address, &auth).await {
+            tracing::warn!("Failed to persist auth cache for {}: {}", user_address, e);
+        }
+        
+        let mut cache = self.user_auth_cache.write().await;
+        cache.insert(user_address, auth);
+    }
+    
+    /**
+     * 获取缓存的用户认证信息
+     * 内存中未命中时从Redis读取，并回填到内存
+     */
+    pub async fn get_cached_user_auth(&self, user_address: &str) -> Option<UserAuth> {
+        {
+            let cache = self.user_auth_cache.read().await;
+            if let Some(auth) = cache.get(user_address) {
+                return Some(auth.clone());
+            }
+        }
+        
+        let auth = match self.load_user_auth(user_address).await {
+            Ok(auth) => auth?,
+            Err(e) => {
+                tracing::warn!("Failed to load auth cache for {}: {}", user_address, e);
+                return None;
+            }
+        };
+        
+        let mut cache = self.user_auth_cache.write().await;
+        cache.insert(user_address.to_string(), auth.clone());
+        Some(auth)
+    }
+    
+    /**
+     * 将用户认证信息写入Redis，有效期与JWT相同
+     */
+    async fn store_user_auth(&self, user_address: &str, auth: &UserAuth) -> Result<()> {
+        let mut conn = self.redis_pool.get().await?;
+        let _: () = redis::cmd("SETEX")
+            .arg(user_auth_key(user_address))
+            .arg(self.config.jwt_expiry_hours * 3600)
+            .arg(serde_json::to_string(auth)?)
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+    
+    /**
+     * 从Redis读取用户认证信息
+     */
+    async fn load_user_auth(&self, user_address: &str) -> Result<Option<UserAuth>> {
+        let mut conn = self.redis_pool.get().await?;
+        let raw: Option<String> = conn.get(user_auth_key(user_address)).await?;
+        
+        match raw {
+            Some(raw) => Ok(Some(serde_json::from_str(&raw)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// 表情回应数据的保留时间（秒）
+const REACTION_TTL_SECS: u64 = 7 * 24 * 3600;
+
+/**
+ * 用户认证信息在Redis中的键
+ */
+fn user_auth_key(user_address: &str) -> String {
+    format!("auth:{}", user_address.to_lowercase())
+}
+
+/**
+ * 禁言记录在Redis中的键
+ */
+fn mute_key(room_name: &str, user_address: &str) -> String {
+    format!("mute:{}:{}", room_name, user_address.to_lowercase())
+}
+
+/**
+ * 房间在门禁索引中的键：主网Token门禁的合约地址（小写），其他链的门禁不索引
+ */
+fn gated_contract(config: &RoomConfig) -> Option<String> {
+    config
+        .token_gate
+        .as_ref()
+        .filter(|gate| gate.chain_id == MAINNET_CHAIN_ID)
+        .map(|gate| gate.contract_address.to_lowercase())
+}
+
+/// 每个房间保留的最近成员数
+const RECENT_MEMBERS_LIMIT: isize = 50;
+
+/// 最后在线记录的保留时间（秒）
+const LAST_SEEN_TTL_SECS: u64 = 30 * 24 * 3600;
+
+/// 客户端活动时刷新最后在线时间的最小间隔
+const LAST_SEEN_REFRESH: Duration = Duration::from_secs(60);
+
+/**
+ * 最后在线时间在Redis中的键
+ */
+fn last_seen_key(user_address: &str) -> String {
+    format!("lastseen:{}", user_address.to_lowercase())
+}
+
+/**
+ * 房间最近成员（按最后在线时间排序的有序集合）在Redis中的键
+ */
+fn recent_members_key(room_name: &str) -> String {
+    format!("room:{}:recent", room_name)
+}
+
+/**
+ * 用户屏蔽列表在Redis中的键
+ */
+fn blocks_key(user_address: &str) -> String {
+    format!("blocks:{}", user_address.to_lowercase())
+}
+
+/**
+ * 慢速模式冷却记录在Redis中的键
+ */
+fn slow_mode_key(room_name: &str, user_address: &str) -> String {
+    format!("slowmode:{}:{}", room_name, user_address.to_lowercase())
+}
+
+/**
+ * 重复消息检查在Redis中的键，用户、房间和内容一起哈希，避免长文本出现在键中
+ */
+fn duplicate_message_key(room_name: &str, user_address: &str, text: &str) -> String {
+    let digest = ethers::utils::keccak256(format!("{}\n{}\n{}", user_address.to_lowercase(), room_name, text));
+    format!("dedupe:{}", hex::encode(&digest[..16]))
+}
+
+/**
+ * 根据冷却键的TTL计算剩余等待秒数
+ * 键刚好过期（-2）或没有过期时间（-1）时按1秒处理，结果不超过冷却时长
+ */
+fn slow_mode_remaining(ttl: i64, secs: u64) -> u64 {
+    if ttl <= 0 {
+        return 1;
+    }
+    (ttl as u64).min(secs)
+}
+
+/// 扫描房间配置时每批的键数
+const ROOM_SCAN_BATCH: usize = 200;
+
+/**
+ * 房间配置在Redis中的键
+ */
+pub fn room_config_key(room_name: &str) -> String {
+    format!("room:{}:config", room_name)
+}
+
+impl Room {
+    /**
+     * 创建新房间，房间配置中的max_history优先于默认的历史条数
+     */
+    pub fn new(name: &str, config: Option<RoomConfig>, default_max_history: usize) -> Self {
+        let mut room = Self {
+            name: name.to_string(),
+            users: HashSet::new(),
+            message_history: Vec::new(),
+            max_history: default_max_history,
+            message_authors: HashMap::new(),
+            moderators: HashSet::new(),
+            config: None,
+            slow_mode_secs: 0,
+            topic: None,
+        };
+        
+        if let Some(config) = config {
+            room.attach_config(config);
+        }
+        room
+    }
+    
+    /**
+     * 附加房间配置，房间创建者自动成为管理员
+     */
+    pub fn attach_config(&mut self, config: RoomConfig) {
+        self.moderators.insert(config.created_by.to_lowercase());
+        if let Some(max_history) = config.max_history {
+            self.max_history = max_history;
+            self.trim_history();
+        }
+        if let Some(slow_mode_secs) = config.slow_mode_secs {
+            self.slow_mode_secs = slow_mode_secs;
+        }
+        self.topic = config.description.clone();
+        self.config = Some(config);
+    }
+    
+    /**
+     * 获取早于指定消息的聊天消息，按时间正序返回
+     * 游标消息已不在历史中时返回空列表
+     */
+    pub fn messages_before(&self, before: Option<&str>, limit: usize) -> Vec<ServerMessage> {
+        let texts: Vec<&ServerMessage> = self.message_history
+            .iter()
+            .filter(|message| message.chat_message_id().is_some())
+            .collect();
+        
+        let end = match before {
+            Some(before) => texts
+                .iter()
+                .position(|message| message.chat_message_id() == Some(before))
+                .unwrap_or(0),
+            None => texts.len(),
+        };
+        let start = end.saturating_sub(limit);
+        
+        texts[start..end].iter().map(|message| (*message).clone()).collect()
+    }
+    
+    /**
+     * 修改历史记录中聊天消息的内容，并标记编辑时间
+     */
+    pub fn edit_message(&mut self, message_id: &str, new_text: &str, edited: chrono::DateTime<chrono::Utc>) {
+        for message in &mut self.message_history {
+            if let ServerMessage::NewText { id, text, edited_at, .. } = message {
+                if id == message_id {
+                    *text = new_text.to_string();
+                    *edited_at = Some(edited);
+                }
+            }
+        }
+    }
+    
+    /**
+     * 从历史记录中移除指定的聊天消息
+     */
+    pub fn remove_message(&mut self, message_id: &str) {
+        self.message_history
+            .retain(|message| message.chat_message_id() != Some(message_id));
+        self.message_authors.remove(message_id);
+    }
+    
+    /**
+     * 追加消息到历史记录，超出上限时丢弃最早的消息
+     * 不属于历史的消息（状态变化、删除和编辑通知等）直接忽略
+     */
+    pub fn push_history(&mut self, message: ServerMessage) {
+        if !message.is_history_entry() {
+            return;
+        }
+        self.message_history.push(message);
+        self.trim_history();
+    }
+    
+    /**
+     * 丢弃超出历史上限的最早消息
+     */
+    fn trim_history(&mut self) {
+        while self.message_history.len() > self.max_history {
+            let message = self.message_history.remove(0);
+            if let Some(id) = message.chat_message_id() {
+                self.message_authors.remove(id);
+            }
+        }
+    }
+    
+    /**
+     * 房间的JSON表示
+     */
+    pub fn to_json(&self, default_max_users: Option<usize>) -> serde_json::Value {
+        let config = self.config.as_ref();
+        serde_json::json!({
+            "name": self.name,
+            "description": config.and_then(|c| c.description.clone()),
+            "token_gate": config.and_then(|c| c.token_gate.clone()),
+            "created_by": config.map(|c| c.created_by.clone()),
+            "created_at": config.map(|c| c.created_at),
+            "user_count": self.users.len(),
+            "max_users": self.capacity(default_max_users),
+            "max_history": self.max_history,
+            "users": self.users.iter().collect::<Vec<_>>()
+        })
+    }
+    
+    /**
+     * 判断用户是否为房间管理员
+     */
+    pub fn is_moderator(&self, user_address: &str) -> bool {
+        self.moderators.contains(&user_address.to_lowercase())
+    }
+    
+    /**
+     * 获取房间人数上限，房间配置优先于全局默认值
+     */
+    pub fn capacity(&self, default_max_users: Option<usize>) -> Option<usize> {
+        self.config
+            .as_ref()
+            .and_then(|config| config.max_users)
+            .or(default_max_users)
+    }
+    
+    /**
+     * 判断房间是否已满
+     */
+    pub fn is_full(&self, default_max_users: Option<usize>) -> bool {
+        self.capacity(default_max_users)
+            .is_some_and(|max_users| self.users.len() >= max_users)
+    }
+    
+    /**
+     * 获取房间最近的消息历史
+     */
+    pub fn get_recent_messages(&self, limit: usize) -> Vec<ServerMessage> {
+        let start = if self.message_history.len() > limit {
+            self.message_history.len() - limit
+        } else {
+            0
+        };
+        self.message_history[start..].to_vec()
+    }
+}
+
+impl AppState {
+    /**
+     * 获取房间在线用户详细信息
+     */
+    pub async fn get_online_users(&self, room_name: &str) -> Vec<crate::models::OnlineUser> {
+        let rooms = self.rooms.read().await;
+        let clients = self.clients.read().await;
+        
+        if let Some(room) = rooms.get(room_name) {
+            room.users.iter()
+                .filter_map(|addr| {
+                    clients.get(addr).map(|client| crate::models::OnlineUser {
+                        address: addr.clone(),
+                        ens_name: client.ens_name.clone(),
+                        avatar: self.config.default_avatar(addr),
+                        avatar_seed: crate::models::avatar_seed(addr),
+                    })
+                })
+                .collect()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /**
+     * 广播用户加入房间
+     */
+    pub async fn broadcast_user_joined(&self, room_name: &str, user_address: &str) {
+        let client_ens = {
+            let clients = self.clients.read().await;
+            clients.get(user_address).and_then(|c| c.ens_name.clone())
         };
 
         let message = ServerMessage::UserJoined {
             user: user_address.to_string(),
             room: room_name.to_string(),
             timestamp: chrono::Utc::now(),
+            display_name: self.config.display_name(user_address, client_ens.as_deref()),
             ens_name: client_ens,
         };
 
@@ -319,6 +1834,7 @@ impl AppState {
             user: user_address.to_string(),
             room: room_name.to_string(),
             timestamp: chrono::Utc::now(),
+            display_name: self.config.display_name(user_address, client_ens.as_deref()),
             ens_name: client_ens,
         };
 
@@ -339,6 +1855,566 @@ impl AppState {
             room: room_name.to_string(),
         };
 
-        self.broadcast_to_room(room_name, message).await;
+        // 在线用户列表只包含本实例的连接，不发布给其他实例
+        self.deliver_to_room(room_name, message).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::StartupRoom;
+    use crate::error::AppError;
+    use crate::models::{RoomConfig, ServerMessage, UserAuth};
+    use crate::pubsub;
+    use crate::test_support::TestHarness;
+    use std::time::Duration;
+    
+    /**
+     * 等待订阅者订阅房间频道，之前发布的消息不会被收到
+     */
+    async fn wait_for_subscriber(harness: &TestHarness) {
+        let mut conn = harness.redis_pool.get().await.unwrap();
+        for _ in 0..50 {
+            let patterns: i64 = redis::cmd("PUBSUB").arg("NUMPAT").query_async(&mut *conn).await.unwrap();
+            if patterns > 0 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("Redis subscriber did not subscribe in time");
+    }
+    
+    #[tokio::test]
+    async fn disconnect_records_last_seen_until_reconnect() {
+        let harness = TestHarness::start().await;
+        let state = harness.app_state();
+        let address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        let room = state.config.default_room.clone();
+        
+        state.add_client(address.to_string(), None).await.unwrap();
+        assert!(state.join_room(address, &room).await);
+        state.remove_client(address).await;
+        
+        assert!(state.get_last_seen(address).await.unwrap().is_some());
+        let members = state.get_room_members(&room).await.unwrap();
+        let member = members.iter().find(|member| member.address == address).unwrap();
+        assert!(!member.online);
+        assert!(member.last_seen.is_some());
+        
+        // 重新连接后显示为在线，不再带有最后在线时间
+        state.add_client(address.to_string(), None).await.unwrap();
+        assert!(state.join_room(address, &room).await);
+        
+        let members = state.get_room_members(&room).await.unwrap();
+        let matching: Vec<_> = members.iter().filter(|member| member.address == address).collect();
+        assert_eq!(matching.len(), 1);
+        assert!(matching[0].online);
+        assert!(matching[0].last_seen.is_none());
+    }
+    
+    #[tokio::test]
+    async fn startup_rooms_exist_after_construction() {
+        let mut harness = TestHarness::start().await;
+        harness.config.startup_rooms = vec![
+            StartupRoom { name: "trading".to_string(), config: None },
+            StartupRoom { name: "nfts".to_string(), config: None },
+        ];
+        let state = harness.app_state();
+        
+        let rooms = state.rooms.read().await;
+        assert!(rooms.contains_key("trading"));
+        assert!(rooms.contains_key("nfts"));
+        // 列表中没有默认房间时仍会创建
+        assert!(rooms.contains_key(&state.config.default_room));
+    }
+    
+    #[tokio::test]
+    async fn rapid_duplicate_message_is_suppressed() {
+        let mut harness = TestHarness::start().await;
+        harness.config.duplicate_message_window_ms = 2000;
+        let state = harness.app_state();
+        let address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        
+        assert!(!state.is_duplicate_message("general", address, "gm").await.unwrap());
+        assert!(state.is_duplicate_message("general", address, "gm").await.unwrap());
+        // 内容或房间不同的消息不受影响
+        assert!(!state.is_duplicate_message("general", address, "gm gm").await.unwrap());
+        assert!(!state.is_duplicate_message("trading", address, "gm").await.unwrap());
+    }
+    
+    #[tokio::test]
+    async fn room_messages_reach_other_instances() {
+        let mut harness = TestHarness::start().await;
+        let author = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        let moderator = "0xFFcf8FDEE72ac11b5c542428B35EEF5769C409f0";
+        harness.config.admin_addresses = vec![moderator.to_lowercase()];
+        
+        // 两个实例共享同一个Redis，只有B运行订阅者
+        let instance_a = harness.app_state();
+        let instance_b = harness.app_state();
+        tokio::spawn(pubsub::run_subscriber(instance_b.clone()));
+        wait_for_subscriber(&harness).await;
+        
+        let room = instance_a.config.default_room.clone();
+        instance_a.add_client(author.to_string(), None).await.unwrap();
+        assert!(instance_a.join_room(author, &room).await);
+        instance_b.add_client(moderator.to_string(), None).await.unwrap();
+        assert!(instance_b.join_room(moderator, &room).await);
+        let mut receiver = instance_b.get_client(moderator).await.unwrap().sender.subscribe();
+        
+        let message = ServerMessage::new_text(
+            "spammer".to_string(),
+            author.to_string(),
+            "buy my token".to_string(),
+            room.clone(),
+            None,
+        );
+        let message_id = message.chat_message_id().unwrap().to_string();
+        instance_a.broadcast_chat_message(&room, author, message).await;
+        
+        let delivered = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let ServerMessage::NewText { id, .. } = receiver.recv().await.unwrap() {
+                    return id;
+                }
+            }
+        })
+        .await
+        .expect("message was not delivered to the other instance");
+        assert_eq!(delivered, message_id);
+        
+        // 其他实例上的管理员可以删除该消息
+        instance_b.delete_message(&room, &message_id, moderator).await.unwrap();
+        assert!(!instance_b.room_has_message(&room, &message_id).await);
+    }
+    
+    #[test]
+    fn slow_mode_remaining_is_clamped_to_interval() {
+        assert_eq!(super::slow_mode_remaining(12, 30), 12);
+        assert_eq!(super::slow_mode_remaining(45, 30), 30);
+        // 键刚好过期或没有过期时间时至少等待1秒
+        assert_eq!(super::slow_mode_remaining(0, 30), 1);
+        assert_eq!(super::slow_mode_remaining(-2, 30), 1);
+    }
+    
+    #[tokio::test]
+    async fn slow_mode_rejects_messages_within_cooldown() {
+        let harness = TestHarness::start().await;
+        let state = harness.app_state();
+        let address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        let room = state.config.default_room.clone();
+        
+        state.set_slow_mode(&room, 30).await.unwrap();
+        state.check_slow_mode(&room, address).await.unwrap();
+        let error = state.check_slow_mode(&room, address).await.unwrap_err();
+        assert!(matches!(
+            error,
+            AppError::InvalidRequest(ref message) if message.starts_with("Slow mode is enabled")
+        ));
+        
+        // 冷却按用户计算，关闭后不再限制
+        state.check_slow_mode(&room, "0xFFcf8FDEE72ac11b5c542428B35EEF5769C409f0").await.unwrap();
+        state.set_slow_mode(&room, 0).await.unwrap();
+        state.check_slow_mode(&room, address).await.unwrap();
+    }
+    
+    #[test]
+    fn small_room_history_evicts_oldest_messages() {
+        let mut room = super::Room::new("ephemeral", None, 2);
+        let messages: Vec<ServerMessage> = ["one", "two", "three"]
+            .iter()
+            .map(|text| ServerMessage::new_text("alice".to_string(), String::new(), text.to_string(), "ephemeral".to_string(), None))
+            .collect();
+        let ids: Vec<String> = messages.iter().map(|m| m.chat_message_id().unwrap().to_string()).collect();
+        
+        for (message, id) in messages.into_iter().zip(&ids) {
+            room.message_authors.insert(id.clone(), "0x90f8bf6a479f320ead074411a4b0e7944ea8c9c1".to_string());
+            room.push_history(message);
+        }
+        
+        let kept: Vec<&str> = room.message_history.iter().filter_map(|m| m.chat_message_id()).collect();
+        assert_eq!(kept, vec![ids[1].as_str(), ids[2].as_str()]);
+        // 被丢弃的消息同时移除作者记录
+        assert!(!room.message_authors.contains_key(&ids[0]));
+        assert!(room.message_authors.contains_key(&ids[2]));
+    }
+    
+    #[test]
+    fn history_pages_backward_from_cursor() {
+        let mut room = super::Room::new("general", None, 100);
+        let mut ids = Vec::new();
+        for i in 0..5 {
+            let message = ServerMessage::new_text("alice".to_string(), String::new(), format!("message {}", i), "general".to_string(), None);
+            ids.push(message.chat_message_id().unwrap().to_string());
+            room.push_history(message);
+        }
+        let page_ids = |page: Vec<ServerMessage>| -> Vec<String> {
+            page.iter().map(|m| m.chat_message_id().unwrap().to_string()).collect()
+        };
+        
+        let latest = page_ids(room.messages_before(None, 2));
+        assert_eq!(latest, ids[3..5]);
+        let older = page_ids(room.messages_before(Some(&latest[0]), 2));
+        assert_eq!(older, ids[1..3]);
+        let oldest = page_ids(room.messages_before(Some(&older[0]), 2));
+        assert_eq!(oldest, ids[0..1]);
+        assert!(room.messages_before(Some(&ids[0]), 2).is_empty());
+        
+        // 游标已不在历史中时返回空列表
+        assert!(room.messages_before(Some("missing"), 2).is_empty());
+    }
+    
+    #[tokio::test]
+    async fn oversized_history_request_is_clamped() {
+        let mut harness = TestHarness::start().await;
+        harness.config.default_max_history = 20;
+        harness.config.history_page_max = 5;
+        let state = harness.app_state();
+        let room = state.config.default_room.clone();
+        
+        for i in 0..10 {
+            let message = ServerMessage::new_text("alice".to_string(), String::new(), format!("message {}", i), room.clone(), None);
+            state.deliver_to_room(&room, message).await;
+        }
+        
+        // 超大的请求按HISTORY_PAGE_MAX返回最新的消息
+        let page = state.get_room_messages(&room, 1_000_000, None).await.unwrap();
+        assert_eq!(page.len(), 5);
+        
+        // 房间历史上限更小时以房间上限为准
+        state.rooms.write().await.get_mut(&room).unwrap().max_history = 3;
+        assert_eq!(state.get_room_messages(&room, 1_000_000, None).await.unwrap().len(), 3);
+        
+        assert!(state.get_room_messages("missing", 10, None).await.is_none());
+    }
+    
+    #[tokio::test]
+    async fn second_connection_shares_client_until_last_disconnects() {
+        let mut harness = TestHarness::start().await;
+        harness.config.max_connections_per_address = 2;
+        let state = harness.app_state();
+        let room = state.config.default_room.clone();
+        let address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        
+        let first = state.add_client(address.to_string(), None).await.unwrap();
+        assert!(state.join_room(address, &room).await);
+        let second = state.add_client(address.to_string(), None).await.unwrap();
+        assert_eq!(first, second);
+        assert_eq!(state.get_client(address).await.unwrap().connections, 2);
+        
+        // 超过每个地址的连接上限时拒绝
+        assert!(matches!(
+            state.add_client(address.to_string(), None).await,
+            Err(AppError::TooManyConnections(_))
+        ));
+        
+        // 断开一个连接不影响房间成员关系
+        state.remove_client(address).await;
+        assert_eq!(state.get_client(address).await.unwrap().connections, 1);
+        assert!(state.is_room_member(address, &room).await);
+        
+        state.remove_client(address).await;
+        assert!(state.get_client(address).await.is_none());
+        assert!(!state.get_room_users(&room).await.contains(&address.to_string()));
+    }
+    
+    #[tokio::test]
+    async fn reconnect_clears_stale_room_membership() {
+        let mut harness = TestHarness::start().await;
+        harness.config.auto_create_rooms = true;
+        let state = harness.app_state();
+        let room = state.config.default_room.clone();
+        let address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        let witness = "0xFFcf8FDEE72ac11b5c542428B35EEF5769C409f0";
+        
+        state.add_client(witness.to_string(), None).await.unwrap();
+        assert!(state.join_room(witness, "trading").await);
+        state.add_client(address.to_string(), None).await.unwrap();
+        assert!(state.join_room(address, &room).await);
+        assert!(state.join_room(address, "trading").await);
+        
+        // 上一次会话的客户端条目丢失，但房间中仍残留成员关系
+        state.clients.write().await.remove(address);
+        let mut receiver = state.get_client(witness).await.unwrap().sender.subscribe();
+        
+        state.add_client(address.to_string(), None).await.unwrap();
+        assert!(!state.get_room_users("trading").await.contains(&address.to_string()));
+        assert!(!state.get_room_users(&room).await.contains(&address.to_string()));
+        match receiver.try_recv().unwrap() {
+            ServerMessage::UserLeft { user, room, .. } => {
+                assert_eq!(user, address);
+                assert_eq!(room, "trading");
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+        
+        // 重新加入后只出现一次
+        assert!(state.join_room(address, &room).await);
+        let users = state.get_room_users(&room).await;
+        assert_eq!(users.iter().filter(|user| *user == address).count(), 1);
+        assert_eq!(state.get_client(address).await.unwrap().current_rooms.len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn empty_custom_rooms_are_reaped_but_default_room_is_kept() {
+        let mut harness = TestHarness::start().await;
+        harness.config.auto_create_rooms = true;
+        let state = harness.app_state();
+        let room = state.config.default_room.clone();
+        let address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        
+        state.add_client(address.to_string(), None).await.unwrap();
+        assert!(state.join_room(address, &room).await);
+        assert!(state.join_room(address, "afterparty").await);
+        
+        assert!(state.leave_room(address, "afterparty").await);
+        assert!(state.leave_room(address, &room).await);
+        
+        let rooms = state.rooms.read().await;
+        assert!(!rooms.contains_key("afterparty"));
+        assert!(rooms.contains_key(&room));
+    }
+    
+    #[tokio::test]
+    async fn room_cap_refuses_extra_joins_until_a_slot_is_freed() {
+        let mut harness = TestHarness::start().await;
+        harness.config.auto_create_rooms = true;
+        harness.config.max_rooms_per_user = 2;
+        let state = harness.app_state();
+        let address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        
+        state.add_client(address.to_string(), None).await.unwrap();
+        assert!(state.join_room(address, "alpha").await);
+        assert!(state.join_room(address, "beta").await);
+        assert!(!state.join_room(address, "gamma").await);
+        
+        // 默认房间不计入上限，已加入的房间可重复加入
+        assert!(state.join_room(address, &state.config.default_room).await);
+        assert!(state.join_room(address, "alpha").await);
+        
+        assert!(state.leave_room(address, "alpha").await);
+        assert!(state.join_room(address, "gamma").await);
+        assert!(!state.is_room_member(address, "alpha").await);
+    }
+    
+    #[tokio::test]
+    async fn blocked_author_does_not_reach_blocker() {
+        let harness = TestHarness::start().await;
+        let state = harness.app_state();
+        let room = state.config.default_room.clone();
+        let author = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        let blocker = "0xFFcf8FDEE72ac11b5c542428B35EEF5769C409f0";
+        let bystander = "0x22d491Bde2303f2f43325b2108D26f1eAbA1e32b";
+        
+        for address in [author, blocker, bystander] {
+            state.add_client(address.to_string(), None).await.unwrap();
+            assert!(state.join_room(address, &room).await);
+        }
+        // 屏蔽列表不区分地址大小写
+        let blocked = state.set_blocked(blocker, &author.to_uppercase().replace("0X", "0x"), true).await.unwrap();
+        assert_eq!(blocked, vec![author.to_lowercase()]);
+        
+        let mut blocker_rx = state.get_client(blocker).await.unwrap().sender.subscribe();
+        let mut bystander_rx = state.get_client(bystander).await.unwrap().sender.subscribe();
+        
+        let message = ServerMessage::new_text("spammer".to_string(), author.to_string(), "gm".to_string(), room.clone(), None);
+        state.deliver_to_room(&room, message).await;
+        
+        assert!(matches!(bystander_rx.try_recv().unwrap(), ServerMessage::NewText { .. }));
+        assert!(blocker_rx.try_recv().is_err());
+        
+        // 被屏蔽的作者提及屏蔽者时同样不通知
+        let mentions = vec![blocker.to_string(), bystander.to_string()];
+        state.notify_mentions(&mentions, &room, "message-1", "spammer", author).await;
+        assert!(matches!(bystander_rx.try_recv().unwrap(), ServerMessage::Mention { .. }));
+        assert!(blocker_rx.try_recv().is_err());
+        
+        // 取消屏蔽后恢复接收
+        state.set_blocked(blocker, author, false).await.unwrap();
+        let message = ServerMessage::new_text("spammer".to_string(), author.to_string(), "gm again".to_string(), room.clone(), None);
+        state.deliver_to_room(&room, message).await;
+        assert!(matches!(blocker_rx.try_recv().unwrap(), ServerMessage::NewText { .. }));
+    }
+    
+    #[tokio::test]
+    async fn room_history_keeps_only_chat_and_chain_events() {
+        let mut harness = TestHarness::start().await;
+        harness.config.default_max_history = 2;
+        let state = harness.app_state();
+        let room = state.config.default_room.clone();
+        let address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        
+        let message = ServerMessage::new_text("alice".to_string(), address.to_string(), "gm".to_string(), room.clone(), None);
+        let message_id = message.chat_message_id().unwrap().to_string();
+        state.deliver_to_room(&room, message).await;
+        
+        // 加入离开的频繁变化不会挤掉聊天记录
+        state.add_client(address.to_string(), None).await.unwrap();
+        for _ in 0..3 {
+            assert!(state.join_room(address, &room).await);
+            state.broadcast_user_joined(&room, address).await;
+            assert!(state.leave_room(address, &room).await);
+            state.broadcast_user_left(&room, address).await;
+        }
+        
+        let history = state.rooms.read().await[&room].message_history.clone();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].chat_message_id(), Some(message_id.as_str()));
+        
+        // 删除通知只移除原消息，本身不写入历史
+        state.deliver_to_room(&room, ServerMessage::MessageDeleted { room: room.clone(), message_id }).await;
+        assert!(state.rooms.read().await[&room].message_history.is_empty());
     }
-}
\ No newline at end of file
+    
+    #[tokio::test]
+    async fn only_creators_moderators_and_admins_can_delete_rooms() {
+        let mut harness = TestHarness::start().await;
+        let admin = "0x22d491Bde2303f2f43325b2108D26f1eAbA1e32b";
+        harness.config.admin_addresses = vec![admin.to_lowercase()];
+        let state = harness.app_state();
+        let owner = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        let member = "0xFFcf8FDEE72ac11b5c542428B35EEF5769C409f0";
+        let room_config = |name: &str| RoomConfig {
+            name: name.to_string(),
+            description: None,
+            token_gate: None,
+            max_users: None,
+            max_history: None,
+            slow_mode_secs: None,
+            created_at: chrono::Utc::now(),
+            created_by: owner.to_string(),
+        };
+        
+        // 已存储配置的房间：创建者可以删除
+        assert!(state.create_room(room_config("stored")).await.unwrap());
+        // 仅存在于内存中的房间：创建者作为房间管理员可以删除
+        assert!(state.register_room(room_config("moderated")).await);
+        assert!(state.register_room(room_config("administered")).await);
+        
+        for room in ["stored", "moderated", "administered"] {
+            assert!(matches!(
+                state.delete_room(room, member).await,
+                Err(AppError::AuthorizationFailed(_))
+            ));
+        }
+        
+        state.delete_room("stored", &owner.to_lowercase()).await.unwrap();
+        assert!(state.load_room_config("stored").await.unwrap().is_none());
+        state.delete_room("moderated", owner).await.unwrap();
+        state.delete_room("administered", admin).await.unwrap();
+        
+        let rooms = state.rooms.read().await;
+        for room in ["stored", "moderated", "administered"] {
+            assert!(!rooms.contains_key(room));
+        }
+    }
+    
+    #[tokio::test]
+    async fn lagged_receiver_is_resynced_with_room_state() {
+        let mut harness = TestHarness::start().await;
+        harness.config.client_broadcast_capacity = 2;
+        let state = harness.app_state();
+        let room = state.config.default_room.clone();
+        let address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        
+        state.add_client(address.to_string(), None).await.unwrap();
+        assert!(state.join_room(address, &room).await);
+        let mut receiver = state.get_client(address).await.unwrap().sender.subscribe();
+        for i in 0..5 {
+            let message = ServerMessage::new_text(address.to_string(), address.to_string(), format!("gm {}", i), room.clone(), None);
+            state.broadcast_chat_message(&room, address, message).await;
+        }
+        assert!(matches!(
+            receiver.recv().await,
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(3))
+        ));
+        
+        let messages = state.resync_messages(Some(address), "Missed 3 messages").await;
+        assert!(matches!(&messages[0], ServerMessage::Resync { reason } if reason == "Missed 3 messages"));
+        let history = messages.iter().find_map(|message| match message {
+            ServerMessage::History { room: history_room, messages, .. } if *history_room == room => Some(messages),
+            _ => None,
+        });
+        assert_eq!(history.expect("resync should include room history").len(), 5);
+    }
+    
+    #[tokio::test]
+    async fn stats_count_clients_rooms_and_chain_events() {
+        let harness = TestHarness::start().await;
+        let state = harness.app_state();
+        let room = state.config.default_room.clone();
+        let initial_rooms = state.stats().await.total_rooms;
+        let alice = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        let bob = "0xFFcf8FDEE72ac11b5c542428B35EEF5769C409f0";
+        
+        for address in [alice, bob] {
+            state.add_client(address.to_string(), None).await.unwrap();
+            assert!(state.join_room(address, &room).await);
+        }
+        assert!(state.join_room(alice, "trading").await);
+        state.record_blockchain_event();
+        state.record_blockchain_event();
+        
+        let stats = state.stats().await;
+        assert_eq!(stats.total_clients, 2);
+        assert_eq!(stats.total_rooms, initial_rooms + 1);
+        assert_eq!(stats.rooms.len(), stats.total_rooms);
+        let user_count = |name: &str| stats.rooms.iter().find(|room| room.name == name).map(|room| room.user_count);
+        assert_eq!(user_count(&room), Some(2));
+        assert_eq!(user_count("trading"), Some(1));
+        assert_eq!(stats.blockchain_events_processed, 2);
+    }
+    
+    #[tokio::test]
+    async fn auth_cache_is_written_through_and_read_through_redis() {
+        let harness = TestHarness::start().await;
+        let instance_a = harness.app_state();
+        let instance_b = harness.app_state();
+        let address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        let auth = UserAuth {
+            address: address.to_string(),
+            ens_name: Some("alice.eth".to_string()),
+            token_holdings: std::collections::HashMap::new(),
+            nft_holdings: Vec::new(),
+        };
+        
+        instance_a.cache_user_auth(address.to_string(), auth).await;
+        
+        // 写入Redis的记录与JWT同时过期
+        let mut conn = harness.redis_pool.get().await.unwrap();
+        let ttl: i64 = redis::cmd("TTL").arg(super::user_auth_key(address)).query_async(&mut *conn).await.unwrap();
+        assert!(ttl > 0 && ttl <= harness.config.jwt_expiry_hours * 3600);
+        
+        // 其他实例内存未命中时从Redis读取并回填
+        assert!(!instance_b.user_auth_cache.read().await.contains_key(address));
+        let loaded = instance_b.get_cached_user_auth(address).await.unwrap();
+        assert_eq!(loaded.ens_name.as_deref(), Some("alice.eth"));
+        assert!(instance_b.user_auth_cache.read().await.contains_key(address));
+    }
+    
+    #[tokio::test]
+    async fn broadcast_channels_use_configured_capacities() {
+        let mut harness = TestHarness::start().await;
+        harness.config.global_broadcast_capacity = 4;
+        harness.config.client_broadcast_capacity = 2;
+        let state = harness.app_state();
+        let address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        
+        // 容量满后最旧的消息被丢弃，落后的接收端先收到Lagged，再从保留的消息继续
+        let mut global = state.global_sender.subscribe();
+        for _ in 0..6 {
+            state.deliver_global(ServerMessage::Pong).await;
+        }
+        assert!(matches!(global.recv().await, Err(tokio::sync::broadcast::error::RecvError::Lagged(2))));
+        assert_eq!(std::iter::from_fn(|| global.try_recv().ok()).count(), 4);
+        
+        state.add_client(address.to_string(), None).await.unwrap();
+        let sender = state.get_client(address).await.unwrap().sender;
+        let mut client = sender.subscribe();
+        for _ in 0..3 {
+            sender.send(ServerMessage::Pong).unwrap();
+        }
+        assert!(matches!(client.recv().await, Err(tokio::sync::broadcast::error::RecvError::Lagged(1))));
+        assert_eq!(std::iter::from_fn(|| client.try_recv().ok()).count(), 2);
+    }
+}
@@ -0,0 +1,75 @@
+use crate::error::{AppError, Result};
+use metrics::{counter, gauge};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use std::sync::OnceLock;
+
+/**
+ * Prometheus指标
+ *
+ * 指标名称：
+ * - chaintalk_connected_clients            (gauge)   当前WebSocket连接数
+ * - chaintalk_messages_sent_total          (counter) 发送的聊天消息数，标签room
+ * - chaintalk_auth_attempts_total          (counter) 认证次数，标签method(siwe/simple/jwt)、result(success/failure)
+ * - chaintalk_nonces_generated_total       (counter) 生成的认证nonce数
+ * - chaintalk_broadcast_lagged_total       (counter) 广播接收端落后的次数，标签channel(global/client)
+ * - chaintalk_blockchain_events_total      (counter) 处理的链上事件数，标签event(swap/erc20_transfer)、source(live/backfill)
+ *
+ * 未启用导出器时，记录指标的调用不产生任何效果
+ */
+pub const CONNECTED_CLIENTS: &str = "chaintalk_connected_clients";
+pub const MESSAGES_SENT: &str = "chaintalk_messages_sent_total";
+pub const AUTH_ATTEMPTS: &str = "chaintalk_auth_attempts_total";
+pub const NONCES_GENERATED: &str = "chaintalk_nonces_generated_total";
+pub const BROADCAST_LAGGED: &str = "chaintalk_broadcast_lagged_total";
+pub const BLOCKCHAIN_EVENTS: &str = "chaintalk_blockchain_events_total";
+
+static PROMETHEUS_HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/**
+ * 安装Prometheus指标记录器，只需在启动时调用一次
+ */
+pub fn install() -> Result<()> {
+    let handle = PrometheusBuilder::new()
+        .install_recorder()
+        .map_err(|e| AppError::InternalError(format!("Failed to install metrics recorder: {}", e)))?;
+
+    let _ = PROMETHEUS_HANDLE.set(handle);
+    Ok(())
+}
+
+/**
+ * 以Prometheus文本格式输出当前指标，未启用时返回None
+ */
+pub fn render() -> Option<String> {
+    PROMETHEUS_HANDLE.get().map(|handle| handle.render())
+}
+
+pub fn client_connected() {
+    gauge!(CONNECTED_CLIENTS).increment(1.0);
+}
+
+pub fn client_disconnected() {
+    gauge!(CONNECTED_CLIENTS).decrement(1.0);
+}
+
+pub fn record_message_sent(room: &str) {
+    counter!(MESSAGES_SENT, "room" => room.to_string()).increment(1);
+}
+
+pub fn record_auth(method: &'static str, success: bool) {
+    let result = if success { "success" } else { "failure" };
+    counter!(AUTH_ATTEMPTS, "method" => method, "result" => result).increment(1);
+}
+
+pub fn record_nonce_generated() {
+    counter!(NONCES_GENERATED).increment(1);
+}
+
+pub fn record_broadcast_lag(channel: &'static str) {
+    counter!(BROADCAST_LAGGED, "channel" => channel).increment(1);
+}
+
+pub fn record_blockchain_event(event: &'static str, live: bool) {
+    let source = if live { "live" } else { "backfill" };
+    counter!(BLOCKCHAIN_EVENTS, "event" => event, "source" => source).increment(1);
+}
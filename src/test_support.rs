@@ -0,0 +1,222 @@
+use crate::audit::AuditLayer;
+use crate::auth::AuthService;
+use crate::config::Config;
+use crate::content_filter::WordListFilter;
+use crate::state::AppState;
+use bb8::Pool;
+use bb8_redis::RedisConnectionManager;
+use ethers::providers::{MockProvider, Provider};
+use ethers::types::Bytes;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use tracing::subscriber::DefaultGuard;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::prelude::*;
+use testcontainers::{clients::Cli, Container};
+use testcontainers_modules::redis::Redis;
+
+/// 测试中使用的RPC地址，没有节点监听，需要链上数据的调用会立即失败
+pub const UNREACHABLE_RPC_URL: &str = "http://127.0.0.1:1";
+
+/// 测试钱包的私钥，仅用于本地签名
+pub const TEST_PRIVATE_KEY: &str = "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+
+/**
+ * 集成测试环境
+ * 每个实例启动独立的Redis容器（需要本机可用的Docker），容器随实例一起销毁
+ */
+pub struct TestHarness {
+    pub config: Config,
+    pub redis_pool: Pool<RedisConnectionManager>,
+    _redis: Container<'static, Redis>,
+}
+
+impl TestHarness {
+    /**
+     * 启动Redis容器并创建连接池
+     */
+    pub async fn start() -> Self {
+        // 容器借用Docker客户端，测试进程内泄漏一个客户端以获得'static生命周期
+        let docker: &'static Cli = Box::leak(Box::new(Cli::default()));
+        let redis = docker.run(Redis::default());
+        let redis_url = format!("redis://127.0.0.1:{}", redis.get_host_port_ipv4(6379));
+        
+        let mut config = test_config();
+        config.redis_url = redis_url.clone();
+        
+        let manager = RedisConnectionManager::new(redis_url.as_str()).expect("invalid Redis URL");
+        let redis_pool = Pool::builder()
+            .build(manager)
+            .await
+            .expect("failed to build Redis pool");
+        
+        Self {
+            config,
+            redis_pool,
+            _redis: redis,
+        }
+    }
+    
+    /**
+     * 使用测试配置和Redis创建认证服务
+     */
+    pub fn auth_service(&self) -> AuthService {
+        AuthService::new(&self.config, self.redis_pool.clone()).expect("failed to create AuthService")
+    }
+    
+    /**
+     * 使用测试配置和Redis创建应用状态
+     */
+    pub fn app_state(&self) -> Arc<AppState> {
+        let content_filter = Arc::new(
+            WordListFilter::new(&self.config.blocklist, self.config.filter_mode).expect("failed to create content filter"),
+        );
+        Arc::new(AppState::new(self.redis_pool.clone(), self.auth_service(), self.config.clone(), content_filter))
+    }
+    
+    /**
+     * 创建使用MockProvider的认证服务
+     * 返回的MockProvider与服务共享响应队列，push的响应按后进先出的顺序被RPC调用取走
+     */
+    pub fn mocked_auth_service(&self) -> (AuthService<MockProvider>, MockProvider) {
+        let (provider, mock) = Provider::mocked();
+        let auth_service = AuthService::with_providers(&self.config, self.redis_pool.clone(), provider, HashMap::new())
+            .expect("failed to create AuthService");
+        (auth_service, mock)
+    }
+}
+
+/**
+ * 测试用的配置：必需的环境变量使用固定的测试值，其余使用默认值
+ * 所有测试设置相同的值，并行测试之间不会互相影响
+ */
+pub fn test_config() -> Config {
+    std::env::set_var("ETHEREUM_WS_URL", "ws://127.0.0.1:1");
+    std::env::set_var("ETHEREUM_HTTP_URL", UNREACHABLE_RPC_URL);
+    std::env::set_var("JWT_SECRET", "test-secret");
+    
+    let mut config = Config::from_env().expect("failed to load test config");
+    config.siwe_domain = "localhost:3000".to_string();
+    config.siwe_scheme = "http".to_string();
+    config.rpc_urls.clear();
+    config
+}
+
+/**
+ * 收集审计日志输出的内存缓冲区
+ */
+#[derive(Clone, Default)]
+pub struct AuditCapture(Arc<Mutex<Vec<u8>>>);
+
+impl AuditCapture {
+    /**
+     * 解析已收集的审计记录
+     */
+    pub fn records(&self) -> Vec<serde_json::Value> {
+        let buffer = self.0.lock().unwrap();
+        String::from_utf8_lossy(&buffer)
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("audit record is not valid JSON"))
+            .collect()
+    }
+}
+
+impl Write for AuditCapture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for AuditCapture {
+    type Writer = Self;
+    
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+/**
+ * 在当前线程上收集审计日志，返回的guard销毁前有效
+ * 需要在单线程运行时（#[tokio::test]默认）中使用
+ */
+pub fn capture_audit_log() -> (AuditCapture, DefaultGuard) {
+    let capture = AuditCapture::default();
+    let subscriber = tracing_subscriber::registry().with(AuditLayer::new(capture.clone()));
+    (capture, tracing::subscriber::set_default(subscriber))
+}
+
+/**
+ * 收集格式化日志输出的内存缓冲区
+ */
+#[derive(Clone, Default)]
+pub struct LogCapture(Arc<Mutex<Vec<u8>>>);
+
+impl LogCapture {
+    /**
+     * 已收集的日志行
+     */
+    pub fn lines(&self) -> Vec<String> {
+        let buffer = self.0.lock().unwrap();
+        String::from_utf8_lossy(&buffer).lines().map(str::to_string).collect()
+    }
+}
+
+impl Write for LogCapture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogCapture {
+    type Writer = Self;
+    
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+/**
+ * 在当前线程上按文本格式收集日志（包含span字段），返回的guard销毁前有效
+ * 需要在单线程运行时（#[tokio::test]默认）中使用
+ */
+pub fn capture_log() -> (LogCapture, DefaultGuard) {
+    let capture = LogCapture::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(capture.clone())
+        .with_ansi(false)
+        .finish();
+    (capture, tracing::subscriber::set_default(subscriber))
+}
+
+/**
+ * 启动对所有JSON-RPC请求返回固定结果的本地节点，返回其HTTP地址
+ * 用于需要真实Provider<Http>的链上读取，例如对balanceOf返回固定余额
+ */
+pub async fn serve_rpc_result(result: Bytes) -> String {
+    let app = axum::Router::new().route(
+        "/",
+        axum::routing::post(move |axum::Json(request): axum::Json<serde_json::Value>| {
+            let result = result.clone();
+            async move {
+                axum::Json(serde_json::json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }))
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("failed to bind RPC stub");
+    let addr = listener.local_addr().expect("RPC stub has no local address");
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.expect("RPC stub failed");
+    });
+    format!("http://{}", addr)
+}
@@ -1,58 +1,288 @@
+use crate::audit;
 use crate::auth::extract_user_from_token;
 use crate::error::{AppError, Result};
-use crate::models::{ClientMessage, ServerMessage};
+use crate::models::{Claims, ClientMessage, ServerMessage, CLIENT_MESSAGE_TYPES, MAINNET_CHAIN_ID};
+use crate::rate_limit;
 use crate::state::AppState;
-use axum::extract::ws::{Message, WebSocket};
+use crate::telemetry;
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
+use axum::http::{header, HeaderMap};
+use ethers::types::Address;
+use ethers::utils::to_checksum;
 use futures_util::{SinkExt, StreamExt};
 use redis::AsyncCommands;
-use std::sync::Arc;
-use tokio::sync::broadcast;
-use tracing::{error, info, warn};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use regex::Regex;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, error, info, warn, Instrument};
+use unicode_segmentation::UnicodeSegmentation;
+
+/**
+ * 当前连接专用的回复通道
+ * 同一地址的多个连接共享房间消息，但Pong、Ack、AuthSuccess、分页历史等请求的回复只发给发起请求的连接
+ */
+type ReplySender = mpsc::UnboundedSender<ServerMessage>;
+
+/// 禁言的最长时间（秒）
+const MAX_MUTE_SECS: u64 = 7 * 24 * 3600;
+
+/// 慢速模式的最长间隔（秒）
+const MAX_SLOW_MODE_SECS: u64 = 6 * 3600;
+
+/// 房间话题的最大长度（字符数）
+const MAX_TOPIC_LENGTH: usize = 200;
+
+/// 附件URL的最大长度
+const MAX_ATTACHMENT_URL_LENGTH: usize = 2048;
+
+/// 附件的频率限制时间窗口（毫秒）
+const ATTACHMENT_RATE_LIMIT_WINDOW_MS: u64 = 60_000;
+
+/// 服务端校验附件内容类型时的请求超时
+const ATTACHMENT_FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 允许的附件内容类型前缀
+const ALLOWED_ATTACHMENT_TYPES: [&str; 3] = ["image/", "video/", "audio/"];
+
+/// 当前的WebSocket协议版本，通过Sec-WebSocket-Protocol协商
+pub const PROTOCOL_VERSION: &str = "chaintalk.v1";
+
+/// 服务端支持的协议版本
+pub const SUPPORTED_PROTOCOLS: [&str; 1] = [PROTOCOL_VERSION];
+
+/**
+ * 判断客户端请求的子协议是否可用
+ * 未声明子协议的旧客户端按当前版本处理，声明了子协议时至少要有一个受支持
+ */
+pub fn protocol_supported(requested: Option<&str>) -> bool {
+    match requested {
+        None => true,
+        Some(requested) => requested
+            .split(',')
+            .any(|protocol| SUPPORTED_PROTOCOLS.contains(&protocol.trim())),
+    }
+}
+
+/**
+ * 拒绝不支持的协议版本，以协议错误关闭连接
+ */
+pub async fn reject_unsupported_protocol(mut socket: WebSocket) {
+    warn!("Rejecting WebSocket connection with unsupported subprotocol");
+    let close_frame = CloseFrame {
+        code: close_code::PROTOCOL,
+        reason: format!("Unsupported protocol, expected {}", PROTOCOL_VERSION).into(),
+    };
+    let _ = socket.send(Message::Close(Some(close_frame))).await;
+}
+
+/**
+ * 校验升级请求携带的JWT，token可以放在?token=查询参数或Authorization: Bearer头中
+ * 没有携带token时返回None，连接建立后使用消息内认证；token无效时返回错误，调用方拒绝升级
+ */
+pub async fn authenticate_upgrade(
+    state: &AppState,
+    headers: &HeaderMap,
+    query_token: Option<&str>,
+) -> Result<Option<Claims>> {
+    let header_token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let token = match query_token.filter(|token| !token.is_empty()).or(header_token) {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+    
+    let result = state.auth_service.verify_jwt(token).await;
+    telemetry::record_auth("jwt_upgrade", result.is_ok());
+    
+    match result {
+        Ok(claims) => {
+            audit::log_event(
+                &state.redis_pool,
+                "auth",
+                &claims.sub,
+                "success",
+                serde_json::json!({ "method": "jwt_upgrade" }),
+            ).await;
+            Ok(Some(claims))
+        }
+        Err(e) => {
+            warn!("Rejecting WebSocket upgrade with invalid token: {}", e);
+            audit::log_event(
+                &state.redis_pool,
+                "auth",
+                "unknown",
+                "failure",
+                serde_json::json!({ "method": "jwt_upgrade", "error": e.to_string() }),
+            ).await;
+            Err(AppError::AuthenticationFailed(format!("Invalid token: {}", e)))
+        }
+    }
+}
 
 /**
  * 处理WebSocket连接
+ * 连接内的所有日志都带有连接id，认证后再记录用户地址
+ * claims为升级时已通过校验的JWT声明，连接建立后直接完成认证
+ */
+pub async fn handle_connection(
+    socket: WebSocket,
+    state: Arc<AppState>,
+    connection_id: String,
+    claims: Option<Claims>,
+) {
+    let span = tracing::info_span!("connection", id = %connection_id, user = tracing::field::Empty);
+    run_connection(socket, state, claims).instrument(span).await
+}
+
+/**
  * 管理客户端连接的整个生命周期，包括认证、消息处理和断开连接
  */
-pub async fn handle_connection(socket: WebSocket, state: Arc<AppState>) {
+async fn run_connection(socket: WebSocket, state: Arc<AppState>, upgrade_claims: Option<Claims>) {
     let (mut sender, mut receiver) = socket.split();
     let mut user_address: Option<String> = None;
     let mut authenticated = false;
     let mut global_receiver = state.global_sender.subscribe();
     let mut client_receiver: Option<broadcast::Receiver<ServerMessage>> = None;
+    let (reply_sender, mut reply_receiver) = mpsc::unbounded_channel::<ServerMessage>();
+    // 连接认证时使用的JWT声明，登出时一并吊销；签名认证的连接没有JWT
+    let mut session_claims: Option<Claims> = None;
+    let mut shutdown = state.subscribe_shutdown();
+    
+    // 空闲超时检测
+    let idle_timeout = Duration::from_secs(state.config.idle_timeout_secs);
+    let mut idle_check = tokio::time::interval(idle_check_period(idle_timeout));
+    let mut last_activity = Instant::now();
+    
+    // 协议层心跳：定期发送Ping帧，连续多次未收到Pong则断开
+    let ping_interval = Duration::from_secs(state.config.ws_ping_interval_secs.max(1));
+    let mut ping_timer = tokio::time::interval_at(
+        tokio::time::Instant::now() + ping_interval,
+        ping_interval,
+    );
+    let mut awaiting_pong = false;
+    let mut missed_pongs: u32 = 0;
+    
+    // 帧级别的洪水防护，与消息内容的限流相互独立
+    let mut frame_window_start = Instant::now();
+    let mut frames_in_window: u32 = 0;
     
     info!("New WebSocket connection established");
+    telemetry::client_connected();
     
-    // 发送欢迎消息
-    let welcome_msg = ServerMessage::NewText {
-        id: uuid::Uuid::new_v4().to_string(),
-        from: "System".to_string(),
-        text: "Welcome to ChainTalk! Please authenticate to start chatting.".to_string(),
-        room: "system".to_string(),
-        timestamp: chrono::Utc::now(),
-    };
+    // 升级时已认证的连接直接注册，超过连接上限等失败时关闭连接
+    if let Some(claims) = upgrade_claims {
+        if let Err(e) = register_authenticated_client(
+            &state,
+            &claims.sub,
+            claims.ens.clone(),
+            &mut user_address,
+            &mut authenticated,
+            &mut client_receiver,
+            &reply_sender,
+        ).await {
+            warn!("Rejecting pre-authenticated connection: {}", e);
+            let close_frame = CloseFrame {
+                code: close_code::POLICY,
+                reason: e.to_string().into(),
+            };
+            let _ = sender.send(Message::Close(Some(close_frame))).await;
+            telemetry::client_disconnected();
+            return;
+        }
+        info!("✅ User authenticated via upgrade JWT and joined default room: {}", claims.sub);
+        session_claims = Some(claims);
+    }
     
-    if let Err(e) = send_message(&mut sender, &welcome_msg).await {
-        error!("Failed to send welcome message: {}", e);
-        return;
+    // 发送欢迎消息，WELCOME_MESSAGE为空或连接已认证时不发送
+    if let Some(text) = state.config.welcome_text().filter(|_| !authenticated) {
+        let welcome_msg = ServerMessage::NewText {
+            id: uuid::Uuid::new_v4().to_string(),
+            from: "System".to_string(),
+            from_address: String::new(),
+            text,
+            room: "system".to_string(),
+            timestamp: chrono::Utc::now(),
+            edited_at: None,
+            reply_to: None,
+        };
+        
+        if let Err(e) = send_message(&mut sender, &welcome_msg).await {
+            error!("Failed to send welcome message: {}", e);
+            telemetry::client_disconnected();
+            return;
+        }
     }
     
     loop {
         tokio::select! {
             // 处理来自客户端的消息
             msg = receiver.next() => {
+                // 心跳帧不计入活动时间，否则空闲连接永远不会超时
+                if matches!(msg, Some(Ok(Message::Text(_) | Message::Binary(_)))) {
+                    last_activity = Instant::now();
+                    if let Some(addr) = &user_address {
+                        state.update_client_activity(addr).await;
+                    }
+                }
+                
+                if matches!(msg, Some(Ok(_))) {
+                    if frame_window_start.elapsed() >= Duration::from_secs(1) {
+                        frame_window_start = Instant::now();
+                        frames_in_window = 0;
+                    }
+                    frames_in_window += 1;
+                    
+                    let max_frames = state.config.ws_max_frames_per_sec;
+                    if max_frames > 0 && frames_in_window > max_frames {
+                        warn!("Closing connection after {} frames in one second", frames_in_window);
+                        let close_frame = CloseFrame {
+                            code: close_code::POLICY,
+                            reason: "Too many frames".into(),
+                        };
+                        let _ = sender.send(Message::Close(Some(close_frame))).await;
+                        break;
+                    }
+                }
+                
                 match msg {
+                    Some(Ok(Message::Text(text))) if text.len() > state.config.ws_max_frame_bytes => {
+                        warn!("Closing connection after oversized message ({} bytes)", text.len());
+                        let close_frame = CloseFrame {
+                            code: close_code::SIZE,
+                            reason: "Message too large".into(),
+                        };
+                        let _ = sender.send(Message::Close(Some(close_frame))).await;
+                        break;
+                    }
                     Some(Ok(Message::Text(text))) => {
-                        match handle_client_message(&text, &state, &mut user_address, &mut authenticated, &mut client_receiver).await {
+                        match handle_client_message(&text, &state, &mut user_address, &mut authenticated, &mut client_receiver, &reply_sender, &mut session_claims).await {
                             Ok(should_continue) => {
                                 if !should_continue {
+                                    let close_frame = CloseFrame {
+                                        code: close_code::NORMAL,
+                                        reason: "Logged out".into(),
+                                    };
+                                    let _ = sender.send(Message::Close(Some(close_frame))).await;
                                     break;
                                 }
                             }
+                            Err(AppError::TooManyConnections(reason)) => {
+                                warn!("Rejecting connection: {}", reason);
+                                let close_frame = CloseFrame {
+                                    code: close_code::POLICY,
+                                    reason: reason.into(),
+                                };
+                                let _ = sender.send(Message::Close(Some(close_frame))).await;
+                                break;
+                            }
                             Err(e) => {
                                 error!("Error handling client message: {}", e);
-                                let error_msg = ServerMessage::Error {
-                                    message: e.to_string(),
-                                };
+                                let error_msg = error_message(&e);
                                 if let Err(send_err) = send_message(&mut sender, &error_msg).await {
                                     error!("Failed to send error message: {}", send_err);
                                     break;
@@ -60,6 +290,10 @@ pub async fn handle_connection(socket: WebSocket, state: Arc<AppState>) {
                             }
                         }
                     }
+                    Some(Ok(Message::Pong(_))) => {
+                        awaiting_pong = false;
+                        missed_pongs = 0;
+                    }
                     Some(Ok(Message::Close(_))) => {
                         info!("Client requested connection close");
                         break;
@@ -89,8 +323,13 @@ pub async fn handle_connection(socket: WebSocket, state: Arc<AppState>) {
                         warn!("Global broadcast channel closed");
                         break;
                     }
-                    Err(broadcast::error::RecvError::Lagged(_)) => {
-                        warn!("Global broadcast receiver lagged");
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Global broadcast receiver lagged by {} messages, resyncing", skipped);
+                        telemetry::record_broadcast_lag("global");
+                        if let Err(e) = send_resync(&mut sender, &state, user_address.as_deref(), skipped).await {
+                            error!("Failed to resync client: {}", e);
+                            break;
+                        }
                     }
                 }
             }
@@ -111,23 +350,163 @@ pub async fn handle_connection(socket: WebSocket, state: Arc<AppState>) {
                         }
                     }
                     Err(broadcast::error::RecvError::Closed) => {
-                        warn!("Client broadcast channel closed");
+                        // 客户端条目已被移除（同一地址的其他连接登出），本连接随之结束且不再重复清理
+                        info!("Client session ended, closing connection");
+                        let close_frame = CloseFrame {
+                            code: close_code::NORMAL,
+                            reason: "Logged out".into(),
+                        };
+                        let _ = sender.send(Message::Close(Some(close_frame))).await;
+                        user_address = None;
+                        break;
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Client broadcast receiver lagged by {} messages, resyncing", skipped);
+                        telemetry::record_broadcast_lag("client");
+                        if let Err(e) = send_resync(&mut sender, &state, user_address.as_deref(), skipped).await {
+                            error!("Failed to resync client: {}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+            
+            // 发送只属于当前连接的回复
+            Some(message) = reply_receiver.recv() => {
+                if let Err(e) = send_message(&mut sender, &message).await {
+                    error!("Failed to send reply: {}", e);
+                    break;
+                }
+            }
+            
+            // 发送心跳Ping帧
+            _ = ping_timer.tick() => {
+                if awaiting_pong {
+                    missed_pongs += 1;
+                    if missed_pongs >= state.config.ws_max_missed_pongs {
+                        warn!("Closing connection after {} unanswered pings", missed_pongs);
+                        break;
                     }
-                    Err(broadcast::error::RecvError::Lagged(_)) => {
-                        warn!("Client broadcast receiver lagged");
+                }
+                
+                if let Err(e) = sender.send(Message::Ping(Vec::new())).await {
+                    error!("Failed to send ping: {}", e);
+                    break;
+                }
+                awaiting_pong = true;
+            }
+            
+            // 服务关闭：先转发尚未发送的全局消息（包括关闭通知），再关闭连接
+            _ = shutdown.changed() => {
+                while let Ok(message) = global_receiver.try_recv() {
+                    if send_message(&mut sender, &message).await.is_err() {
+                        break;
                     }
                 }
+                
+                let close_frame = CloseFrame {
+                    code: close_code::AWAY,
+                    reason: "Server shutting down".into(),
+                };
+                let _ = sender.send(Message::Close(Some(close_frame))).await;
+                break;
+            }
+            
+            // 断开长时间无活动的连接
+            _ = idle_check.tick() => {
+                if idle_timeout.is_zero() || last_activity.elapsed() < idle_timeout {
+                    continue;
+                }
+                
+                info!("Closing idle connection after {:?}", last_activity.elapsed());
+                let close_frame = CloseFrame {
+                    code: close_code::AWAY,
+                    reason: "Idle timeout".into(),
+                };
+                let _ = sender.send(Message::Close(Some(close_frame))).await;
+                break;
             }
         }
     }
     
     // 清理连接
+    telemetry::client_disconnected();
     if let Some(addr) = user_address {
         state.remove_client(&addr).await;
         info!("Cleaned up connection for user: {}", addr);
     }
 }
 
+/**
+ * 接收端落后丢失消息后，直接向该连接发送当前状态使其重新同步
+ */
+async fn send_resync(
+    sender: &mut futures_util::stream::SplitSink<WebSocket, Message>,
+    state: &AppState,
+    user_address: Option<&str>,
+    skipped: u64,
+) -> Result<()> {
+    let reason = format!("Missed {} messages", skipped);
+    for message in state.resync_messages(user_address, &reason).await {
+        send_message(sender, &message).await?;
+    }
+    Ok(())
+}
+
+/**
+ * 将错误转换为发给客户端的消息
+ * 认证类错误使用AuthFailed，便于客户端提示重新认证
+ */
+fn error_message(error: &AppError) -> ServerMessage {
+    match error {
+        AppError::AuthenticationFailed(_) | AppError::InvalidSignature | AppError::InvalidNonce => {
+            ServerMessage::AuthFailed {
+                error: error.to_string(),
+            }
+        }
+        _ => ServerMessage::Error {
+            message: error.to_string(),
+        },
+    }
+}
+
+/**
+ * 空闲检测的周期，取超时时间的一半并限制在1到30秒之间
+ */
+fn idle_check_period(idle_timeout: Duration) -> Duration {
+    (idle_timeout / 2).clamp(Duration::from_secs(1), Duration::from_secs(30))
+}
+
+/**
+ * 消息的type不是已知的ClientMessage类型时，返回列出可用类型的错误
+ */
+fn unknown_message_type(text: &str) -> Option<AppError> {
+    let value: serde_json::Value = serde_json::from_str(text).ok()?;
+    let message_type = value.get("type")?.as_str()?;
+    if CLIENT_MESSAGE_TYPES.contains(&message_type) {
+        return None;
+    }
+    
+    Some(AppError::InvalidRequest(format!(
+        "Unknown message type: {}; expected one of {}",
+        message_type,
+        CLIENT_MESSAGE_TYPES.join(", ")
+    )))
+}
+
+/**
+ * 向已认证的连接重新发送AuthSuccess
+ */
+async fn resend_auth_success(state: &Arc<AppState>, address: &str, reply: &ReplySender) {
+    if let Some(client) = state.get_client(address).await {
+        let _ = reply.send(ServerMessage::AuthSuccess {
+            user_address: address.to_string(),
+            ens_name: client.ens_name,
+            protocol_version: PROTOCOL_VERSION.to_string(),
+        });
+    }
+}
+
 /**
  * 处理来自客户端的消息
  */
@@ -137,34 +516,45 @@ async fn handle_client_message(
     user_address: &mut Option<String>,
     authenticated: &mut bool,
     client_receiver: &mut Option<broadcast::Receiver<ServerMessage>>,
+    reply: &ReplySender,
+    session_claims: &mut Option<Claims>,
 ) -> Result<bool> {
-    // 记录接收到的原始消息
-    info!("📨 Received client message: {}", text);
+    // 原始消息可能包含JWT（TokenAuth、Logout）和签名，只记录长度
+    info!("📨 Received client message ({} bytes)", text.len());
     
     // 解析客户端消息
     let client_msg: ClientMessage = serde_json::from_str(text)
         .map_err(|e| {
             error!("❌ Failed to parse client message: {}", e);
-            error!("❌ Raw message was: {}", text);
-            AppError::SerializationError(e.to_string())
+            unknown_message_type(text).unwrap_or_else(|| AppError::SerializationError(e.to_string()))
         })?;
     
     info!("✅ Successfully parsed client message type: {:?}", std::mem::discriminant(&client_msg));
     
     match client_msg {
-        ClientMessage::Authenticate { message, signature } => {
-            if !*authenticated {
-                return handle_siwe_authentication(&message, &signature, state, user_address, authenticated, client_receiver).await;
-            } else {
-                return Err(AppError::AuthenticationFailed("Already authenticated".to_string()));
+        // 已认证的连接再次认证不算失败：不重新校验凭证，直接回送当前身份的AuthSuccess，连接保持不变
+        ClientMessage::Authenticate { .. } | ClientMessage::SimpleAuth { .. } | ClientMessage::TokenAuth { .. }
+            if *authenticated =>
+        {
+            if let Some(address) = user_address.as_deref() {
+                resend_auth_success(state, address, reply).await;
             }
+            return Ok(true);
+        }
+        ClientMessage::Authenticate { message, signature } => {
+            return handle_siwe_authentication(&message, &signature, state, user_address, authenticated, client_receiver, reply).await;
         }
         ClientMessage::SimpleAuth { address, message, signature, nonce } => {
-            if !*authenticated {
-                return handle_simple_authentication(&address, &message, &signature, &nonce, state, user_address, authenticated, client_receiver).await;
-            } else {
-                return Err(AppError::AuthenticationFailed("Already authenticated".to_string()));
-            }
+            let result = handle_simple_authentication(&address, &message, &signature, &nonce, state, user_address, authenticated, client_receiver, reply).await;
+            telemetry::record_auth("simple", result.is_ok());
+            audit_auth(state, "simple", user_address.as_deref().unwrap_or(&address), &result).await;
+            return result;
+        }
+        ClientMessage::TokenAuth { token } => {
+            let result = handle_token_authentication(&token, state, user_address, authenticated, client_receiver, reply, session_claims).await;
+            telemetry::record_auth("jwt", result.is_ok());
+            audit_auth(state, "jwt", user_address.as_deref().unwrap_or("unknown"), &result).await;
+            return result;
         }
         _ => {
             if !*authenticated {
@@ -175,9 +565,6 @@ async fn handle_client_message(
     
     let user_addr = user_address.as_ref().unwrap();
     
-    // 更新客户端活动时间
-    state.update_client_activity(user_addr).await;
-    
     match client_msg {
         ClientMessage::Authenticate { .. } => {
             // Already handled above
@@ -185,8 +572,11 @@ async fn handle_client_message(
         ClientMessage::SimpleAuth { .. } => {
             // Already handled above
         }
-        ClientMessage::SendText { room, text } => {
-            handle_send_text(state, user_addr, &room, &text).await?;
+        ClientMessage::TokenAuth { .. } => {
+            // Already handled above
+        }
+        ClientMessage::SendText { room, text, client_msg_id, reply_to } => {
+            handle_send_text(state, user_addr, &room, &text, client_msg_id, reply_to, reply).await?;
         }
         ClientMessage::JoinRoom { room } => {
             handle_join_room(state, user_addr, &room).await?;
@@ -194,11 +584,53 @@ async fn handle_client_message(
         ClientMessage::LeaveRoom { room } => {
             handle_leave_room(state, user_addr, &room).await?;
         }
+        ClientMessage::DirectMessage { to, text } => {
+            handle_direct_message(state, user_addr, &to, &text).await?;
+        }
+        ClientMessage::React { room, message_id, emoji } => {
+            handle_react(state, user_addr, &room, &message_id, &emoji).await?;
+        }
+        ClientMessage::DeleteMessage { room, message_id } => {
+            handle_delete_message(state, user_addr, &room, &message_id).await?;
+        }
+        ClientMessage::EditMessage { room, message_id, new_text } => {
+            handle_edit_message(state, user_addr, &room, &message_id, &new_text).await?;
+        }
+        ClientMessage::Kick { room, target } => {
+            handle_kick(state, user_addr, &room, &target).await?;
+        }
+        ClientMessage::Mute { room, target, duration_secs } => {
+            handle_mute(state, user_addr, &room, &target, duration_secs).await?;
+        }
+        ClientMessage::SetSlowMode { room, secs } => {
+            handle_set_slow_mode(state, user_addr, &room, secs).await?;
+        }
+        ClientMessage::SetTopic { room, topic } => {
+            handle_set_topic(state, user_addr, &room, &topic).await?;
+        }
+        ClientMessage::BlockUser { address } => {
+            handle_set_blocked(state, user_addr, &address, true, reply).await?;
+        }
+        ClientMessage::UnblockUser { address } => {
+            handle_set_blocked(state, user_addr, &address, false, reply).await?;
+        }
+        ClientMessage::FetchHistory { room, before_id, limit } => {
+            handle_fetch_history(state, user_addr, &room, before_id.as_deref(), limit, reply).await?;
+        }
+        ClientMessage::SendAttachment { room, url, mime_hint } => {
+            handle_send_attachment(state, user_addr, &room, &url, mime_hint).await?;
+        }
+        ClientMessage::Logout { token } => {
+            handle_logout(state, user_addr, token.as_deref(), session_claims.as_ref()).await?;
+            // 连接已在此处清理，结束时不再重复移除
+            *user_address = None;
+            *authenticated = false;
+            *session_claims = None;
+            return Ok(false);
+        }
         ClientMessage::Ping => {
             // 响应ping消息
-            if let Some(client) = state.get_client(user_addr).await {
-                let _ = client.sender.send(ServerMessage::Pong);
-            }
+            let _ = reply.send(ServerMessage::Pong);
         }
     }
     
@@ -215,11 +647,11 @@ async fn handle_siwe_authentication(
     user_address: &mut Option<String>,
     authenticated: &mut bool,
     client_receiver: &mut Option<broadcast::Receiver<ServerMessage>>,
+    reply: &ReplySender,
 ) -> Result<bool> {
+    // 消息和签名属于用户凭证，只记录长度
     info!("🔐 Starting SIWE authentication process");
-    info!("📝 SIWE message from client: {}", message);
-    info!("✍️ Signature from client: {}", signature);
-    info!("📏 Message length: {} chars, Signature length: {} chars", message.len(), signature.len());
+    debug!("📏 Message length: {} chars, Signature length: {} chars", message.len(), signature.len());
     
     // 验证SIWE消息和签名
     let user_auth = state.auth_service.verify_siwe_message(message, signature).await
@@ -230,37 +662,132 @@ async fn handle_siwe_authentication(
     
     info!("✅ SIWE authentication successful for address: {}", user_auth.address);
     
+    register_authenticated_client(
+        state,
+        &user_auth.address,
+        user_auth.ens_name.clone(),
+        user_address,
+        authenticated,
+        client_receiver,
+        reply,
+    ).await?;
+    
+    info!("User authenticated via SIWE and joined default room: {}", user_auth.address);
+    
+    Ok(true)
+}
+
+/**
+ * 记录WebSocket认证结果的审计事件，失败时附带错误原因
+ */
+async fn audit_auth(state: &AppState, method: &str, actor: &str, result: &Result<bool>) {
+    match result {
+        Ok(_) => audit::log_event(
+            &state.redis_pool,
+            "auth",
+            actor,
+            "success",
+            serde_json::json!({ "method": method }),
+        ).await,
+        Err(e) => audit::log_event(
+            &state.redis_pool,
+            "auth",
+            actor,
+            "failure",
+            serde_json::json!({ "method": method, "error": e.to_string() }),
+        ).await,
+    }
+}
+
+/**
+ * 处理JWT认证 - 复用HTTP登录获得的token，无需再次签名
+ */
+async fn handle_token_authentication(
+    token: &str,
+    state: &Arc<AppState>,
+    user_address: &mut Option<String>,
+    authenticated: &mut bool,
+    client_receiver: &mut Option<broadcast::Receiver<ServerMessage>>,
+    reply: &ReplySender,
+    session_claims: &mut Option<Claims>,
+) -> Result<bool> {
+    info!("🔐 Starting JWT authentication process");
+    
+    // 过期或格式错误的token都视为认证失败
+    let claims = state.auth_service.verify_jwt(token).await
+        .map_err(|e| {
+            error!("❌ JWT verification failed in websocket handler: {}", e);
+            AppError::AuthenticationFailed(format!("Invalid token: {}", e))
+        })?;
+    
+    register_authenticated_client(
+        state,
+        &claims.sub,
+        claims.ens.clone(),
+        user_address,
+        authenticated,
+        client_receiver,
+        reply,
+    ).await?;
+    
+    info!("✅ User authenticated via JWT and joined default room: {}", claims.sub);
+    *session_claims = Some(claims);
+    
+    Ok(true)
+}
+
+/**
+ * 认证成功后注册客户端
+ * 订阅客户端消息、发送认证成功消息并自动加入默认房间
+ * 超过每个地址的连接上限时返回错误，连接保持未认证状态
+ */
+async fn register_authenticated_client(
+    state: &Arc<AppState>,
+    address: &str,
+    ens_name: Option<String>,
+    user_address: &mut Option<String>,
+    authenticated: &mut bool,
+    client_receiver: &mut Option<broadcast::Receiver<ServerMessage>>,
+    reply: &ReplySender,
+) -> Result<()> {
     // 将客户端添加到状态管理
-    let _client_id = state.add_client(user_auth.address.clone(), user_auth.ens_name.clone()).await;
+    let _client_id = state.add_client(address.to_string(), ens_name.clone()).await?;
     
     // 获取客户端的消息接收器
-    if let Some(client) = state.get_client(&user_auth.address).await {
+    if let Some(client) = state.get_client(address).await {
         *client_receiver = Some(client.sender.subscribe());
     }
     
+    if let Err(e) = state.load_blocked(address).await {
+        warn!("Failed to load block list for {}: {}", address, e);
+    }
+    
     // 更新认证状态
-    *user_address = Some(user_auth.address.clone());
+    tracing::Span::current().record("user", address);
+    *user_address = Some(address.to_string());
     *authenticated = true;
     
     // 发送认证成功消息
-    if let Some(client) = state.get_client(&user_auth.address).await {
-        let auth_success_msg = ServerMessage::AuthSuccess {
-            user_address: user_auth.address.clone(),
-            ens_name: user_auth.ens_name.clone(),
-        };
-        let _ = client.sender.send(auth_success_msg);
-    }
-    
-    // 自动加入默认房间
-    state.join_room(&user_auth.address, "general").await;
+    let _ = reply.send(ServerMessage::AuthSuccess {
+        user_address: address.to_string(),
+        ens_name,
+        protocol_version: PROTOCOL_VERSION.to_string(),
+    });
     
-    // 广播用户加入消息
-    let join_message = ServerMessage::user_joined(user_auth.address.clone(), "general".to_string());
-    state.broadcast_to_room("general", join_message).await;
+    // 自动加入默认房间，同一地址的其他连接已在房间中时不重复广播加入，历史消息只发给新连接
+    let default_room = state.config.default_room.as_str();
+    let already_joined = state.is_room_member(address, default_room).await;
+    let joined = state.join_room(address, default_room).await;
+    if let Some(history) = state.room_history(default_room).await {
+        let _ = reply.send(history);
+    }
     
-    info!("User authenticated via SIWE and joined general room: {}", user_auth.address);
+    // 广播用户加入消息及在线用户列表
+    if joined && !already_joined {
+        state.broadcast_user_joined(default_room, address).await;
+    }
     
-    Ok(true)
+    Ok(())
 }
 
 /**
@@ -275,23 +802,21 @@ async fn handle_simple_authentication(
     user_address: &mut Option<String>,
     authenticated: &mut bool,
     client_receiver: &mut Option<broadcast::Receiver<ServerMessage>>,
+    reply: &ReplySender,
 ) -> Result<bool> {
-    info!("🔐 Starting simple authentication process");
-    info!("📝 Address from client: {}", address);
-    info!("📝 Message from client: {}", message);
-    info!("✍️ Signature from client: {}", signature);
-    info!("🎲 Nonce from client: {}", nonce);
+    // 消息、签名和nonce属于用户凭证，只记录地址和长度
+    info!("🔐 Starting simple authentication process for {}", address);
+    debug!("📏 Message length: {} chars, Signature length: {} chars", message.len(), signature.len());
     
     // 验证nonce是否存在且有效
-    let mut conn = state.redis_pool.get().await
-        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    let mut conn = state.redis_pool.get().await?;
     
     let nonce_key = format!("nonce:{}", nonce);
     let nonce_exists: bool = conn.exists(&nonce_key).await
         .map_err(|e| AppError::DatabaseError(e.to_string()))?;
     
     if !nonce_exists {
-        error!("❌ Nonce not found or expired: {}", nonce);
+        error!("❌ Nonce not found or expired");
         return Err(AppError::InvalidNonce);
     }
     
@@ -300,71 +825,66 @@ async fn handle_simple_authentication(
     // 删除已使用的nonce
     let _: () = conn.del(&nonce_key).await
         .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    audit::log_event(
+        &state.redis_pool,
+        "nonce_consumed",
+        address,
+        "success",
+        serde_json::json!({ "nonce": nonce }),
+    ).await;
     
     // 使用ethers进行简化签名验证
     use ethers::utils::hash_message;
     use ethers::types::{RecoveryMessage, Signature};
-    use ethers::utils::to_checksum;
-    use std::str::FromStr;
     
-    // 解析签名
-    let sig = Signature::from_str(signature.trim_start_matches("0x"))
-        .map_err(|e| {
-            error!("❌ Failed to parse signature: {}", e);
-            AppError::InvalidSignature
-        })?;
-    
-    // 恢复地址
+    let expected_address = Address::from_str(address)
+        .map_err(|e| AppError::InvalidRequest(e.to_string()))?;
+    let expected_checksum = to_checksum(&expected_address, None);
     let message_hash = hash_message(message.as_bytes());
-    let recovered_address = sig.recover(RecoveryMessage::Hash(message_hash))
-        .map_err(|e| {
-            error!("❌ Failed to recover address from signature: {}", e);
-            AppError::InvalidSignature
-        })?;
-    
-    // 转换为checksummed地址进行比较
-    let recovered_checksum = to_checksum(&recovered_address, None);
-    let expected_checksum = to_checksum(&ethers::types::Address::from_str(address)
-        .map_err(|e| AppError::InvalidRequest(e.to_string()))?, None);
-    
-    if recovered_checksum.to_lowercase() != expected_checksum.to_lowercase() {
-        error!("❌ Address verification failed:");
-        error!("   Expected: {}", expected_checksum);
-        error!("   Recovered: {}", recovered_checksum);
-        return Err(AppError::InvalidSignature);
-    }
     
-    info!("✅ Simple signature verification passed for address: {}", recovered_checksum);
+    // 解析签名并恢复地址
+    let recovered_address = Signature::from_str(signature.trim_start_matches("0x"))
+        .map_err(|e| error!("❌ Failed to parse signature: {}", e))
+        .ok()
+        .and_then(|sig| {
+            sig.recover(RecoveryMessage::Hash(message_hash))
+                .map_err(|e| error!("❌ Failed to recover address from signature: {}", e))
+                .ok()
+        });
     
-    // 将客户端添加到状态管理
-    let _client_id = state.add_client(recovered_checksum.clone(), None).await;
-    
-    // 获取客户端的消息接收器
-    if let Some(client) = state.get_client(&recovered_checksum).await {
-        *client_receiver = Some(client.sender.subscribe());
+    if recovered_address != Some(expected_address) {
+        // ECDSA恢复失败或地址不一致时，按智能合约钱包尝试EIP-1271校验
+        let signature_bytes = hex::decode(signature.trim_start_matches("0x"))
+            .map_err(|_| AppError::InvalidSignature)?;
+        
+        if !state.auth_service.verify_contract_signature(MAINNET_CHAIN_ID, expected_address, message_hash, signature_bytes).await? {
+            error!("❌ Address verification failed:");
+            error!("   Expected: {}", expected_checksum);
+            error!("   Recovered: {:?}", recovered_address);
+            return Err(AppError::InvalidSignature);
+        }
+        
+        info!("✅ EIP-1271 contract signature verification passed");
     }
     
-    // 更新认证状态
-    *user_address = Some(recovered_checksum.clone());
-    *authenticated = true;
-    
-    // 发送认证成功消息
-    if let Some(client) = state.get_client(&recovered_checksum).await {
-        let auth_success_msg = ServerMessage::AuthSuccess {
-            user_address: recovered_checksum.clone(),
-            ens_name: None,
-        };
-        let _ = client.sender.send(auth_success_msg);
-    }
+    info!("✅ Simple signature verification passed for address: {}", expected_checksum);
     
-    // 自动加入默认房间
-    state.join_room(&recovered_checksum, "general").await;
+    let ens_name = state.auth_service.resolve_ens(&expected_address).await.unwrap_or_else(|e| {
+        warn!("Failed to resolve ENS name for {}: {}", expected_checksum, e);
+        None
+    });
     
-    // 广播用户加入消息
-    let join_message = ServerMessage::user_joined(recovered_checksum.clone(), "general".to_string());
-    state.broadcast_to_room("general", join_message).await;
+    register_authenticated_client(
+        state,
+        &expected_checksum,
+        ens_name,
+        user_address,
+        authenticated,
+        client_receiver,
+        reply,
+    ).await?;
     
-    info!("✅ User authenticated via simple auth and joined general room: {}", recovered_checksum);
+    info!("✅ User authenticated via simple auth and joined default room: {}", expected_checksum);
     
     Ok(true)
 }
@@ -377,15 +897,12 @@ async fn handle_send_text(
     user_address: &str,
     room: &str,
     text: &str,
+    client_msg_id: Option<String>,
+    reply_to: Option<String>,
+    reply: &ReplySender,
 ) -> Result<()> {
-    // 输入验证
-    if text.trim().is_empty() {
-        return Err(AppError::InvalidRequest("Message cannot be empty".to_string()));
-    }
-    
-    if text.len() > 1000 {
-        return Err(AppError::InvalidRequest("Message too long (max 1000 characters)".to_string()));
-    }
+    validate_outgoing_text(state, user_address, text).await?;
+    let text = state.content_filter.filter(text)?;
     
     // 检查用户是否在房间中
     let client = state.get_client(user_address).await
@@ -395,87 +912,815 @@ async fn handle_send_text(
         return Err(AppError::AuthorizationFailed("User not in room".to_string()));
     }
     
-    // 创建消息
-    let display_name = client.ens_name.unwrap_or_else(|| {
-        // 缩短地址显示
-        let addr = user_address.to_string();
-        if addr.len() > 10 {
-            format!("{}...{}", &addr[..6], &addr[addr.len()-4..])
-        } else {
-            addr
+    if state.is_muted(room, user_address).await? {
+        return Err(AppError::AuthorizationFailed("You are muted in this room".to_string()));
+    }
+    
+    // 先检查慢速模式，被冷却拒绝的消息不会被记为已发送，冷却结束后可以原样重发
+    state.check_slow_mode(room, user_address).await?;
+    
+    // 连续误触发送的相同内容不再广播
+    if state.is_duplicate_message(room, user_address, &text).await? {
+        return Err(AppError::InvalidRequest("Duplicate message ignored".to_string()));
+    }
+    
+    // 回复的消息已不在房间历史中时去掉引用，消息按普通消息发送
+    let reply_to = match reply_to {
+        Some(id) if state.room_has_message(room, &id).await => Some(id),
+        Some(id) => {
+            warn!("Dropping reply reference to unknown message {} in room {}", id, room);
+            None
         }
-    });
+        None => None,
+    };
+    
+    // 创建消息
+    let display_name = state.config.display_name(user_address, client.ens_name.as_deref());
+    let mentions = parse_mentions(&text);
+    
+    let message = ServerMessage::new_text(
+        display_name.clone(),
+        user_address.to_string(),
+        text,
+        room.to_string(),
+        reply_to,
+    );
+    let message_id = match &message {
+        ServerMessage::NewText { id, .. } => id.clone(),
+        _ => String::new(),
+    };
     
-    let message = ServerMessage::new_text(display_name, text.to_string(), room.to_string());
+    // 广播成功后的确认消息，携带服务端分配的消息id
+    let ack = match (&message, client_msg_id) {
+        (ServerMessage::NewText { id, timestamp, .. }, Some(client_msg_id)) => Some(ServerMessage::Ack {
+            client_msg_id,
+            server_id: id.clone(),
+            timestamp: *timestamp,
+        }),
+        _ => None,
+    };
     
     // 异步广播到房间（避免阻塞）
     let state_clone = Arc::clone(state);
     let room_name = room.to_string();
+    let author = user_address.to_string();
+    let reply = reply.clone();
     tokio::spawn(async move {
-        state_clone.broadcast_to_room(&room_name, message).await;
-    });
+        state_clone.broadcast_chat_message(&room_name, &author, message).await;
+        telemetry::record_message_sent(&room_name);
+        
+        state_clone.notify_mentions(&mentions, &room_name, &message_id, &display_name, &author).await;
+        
+        if let Some(ack) = ack {
+            let _ = reply.send(ack);
+        }
+    }.in_current_span());
     
     Ok(())
 }
 
 /**
- * 处理加入房间
+ * 处理私信 - 只发送给接收者，并回显给发送者
  */
-async fn handle_join_room(
+async fn handle_direct_message(
     state: &Arc<AppState>,
     user_address: &str,
-    room: &str,
+    to: &str,
+    text: &str,
 ) -> Result<()> {
-    let success = state.join_room(user_address, room).await;
+    validate_outgoing_text(state, user_address, text).await?;
+    let text = state.content_filter.filter(text)?;
     
-    if success {
-        // 广播用户加入消息
-        let client = state.get_client(user_address).await.unwrap();
-        let display_name = client.ens_name.unwrap_or_else(|| user_address.to_string());
-        let join_msg = ServerMessage::user_joined(display_name, room.to_string());
-        state.broadcast_to_room(room, join_msg).await;
-        
-        // 发送房间用户列表给新用户
-        let users = state.get_room_users(room).await;
-        let users_msg = ServerMessage::RoomUsers {
-            room: room.to_string(),
-            users,
-        };
-        
-        if let Some(client) = state.get_client(user_address).await {
-            let _ = client.sender.send(users_msg);
-        }
+    // 客户端以校验和地址为键
+    let recipient_address = Address::from_str(to)
+        .map(|addr| to_checksum(&addr, None))
+        .map_err(|e| AppError::InvalidRequest(format!("Invalid recipient address: {}", e)))?;
+    
+    let sender_client = state.get_client(user_address).await
+        .ok_or_else(|| AppError::AuthenticationFailed("Client not found".to_string()))?;
+    
+    let recipient = state.get_client(&recipient_address).await
+        .ok_or_else(|| AppError::InvalidRequest("Recipient is not online".to_string()))?;
+    
+    let message = ServerMessage::DirectMessage {
+        id: uuid::Uuid::new_v4().to_string(),
+        from: state.config.display_name(user_address, sender_client.ens_name.as_deref()),
+        to: recipient_address,
+        text,
+        timestamp: chrono::Utc::now(),
+    };
+    
+    let _ = recipient.sender.send(message.clone());
+    
+    // 回显给发送者（给自己发私信时无需重复发送）
+    if recipient.user_address != sender_client.user_address {
+        let _ = sender_client.sender.send(message);
     }
     
     Ok(())
 }
 
 /**
- * 处理离开房间
+ * 处理表情回应 - 切换回应状态并向房间广播最新计数
  */
-async fn handle_leave_room(
+async fn handle_react(
     state: &Arc<AppState>,
     user_address: &str,
     room: &str,
+    message_id: &str,
+    emoji: &str,
 ) -> Result<()> {
+    if !is_valid_emoji(emoji) {
+        return Err(AppError::InvalidRequest("Invalid emoji".to_string()));
+    }
+    
     let client = state.get_client(user_address).await
         .ok_or_else(|| AppError::AuthenticationFailed("Client not found".to_string()))?;
     
-    let display_name = client.ens_name.unwrap_or_else(|| user_address.to_string());
+    if !client.current_rooms.contains(room) {
+        return Err(AppError::AuthorizationFailed("User not in room".to_string()));
+    }
+    
+    if !state.room_has_message(room, message_id).await {
+        return Err(AppError::InvalidRequest("Message not found".to_string()));
+    }
     
-    state.leave_room(user_address, room).await;
+    let count = state.toggle_reaction(message_id, emoji, user_address).await?;
     
-    // 广播用户离开消息
-    let leave_msg = ServerMessage::user_left(display_name, room.to_string());
-    state.broadcast_to_room(room, leave_msg).await;
+    let reaction_msg = ServerMessage::Reaction {
+        room: room.to_string(),
+        message_id: message_id.to_string(),
+        emoji: emoji.to_string(),
+        user: user_address.to_string(),
+        count,
+    };
+    state.broadcast_to_room(room, reaction_msg).await;
     
     Ok(())
 }
 
 /**
- * 发送消息到WebSocket
+ * 处理删除消息 - 删除后通知房间内所有客户端
  */
-async fn send_message(
+async fn handle_delete_message(
+    state: &Arc<AppState>,
+    user_address: &str,
+    room: &str,
+    message_id: &str,
+) -> Result<()> {
+    state.delete_message(room, message_id, user_address).await?;
+    
+    let deleted_msg = ServerMessage::MessageDeleted {
+        room: room.to_string(),
+        message_id: message_id.to_string(),
+    };
+    state.broadcast_to_room(room, deleted_msg).await;
+    
+    info!("Message {} in room {} deleted by {}", message_id, room, user_address);
+    
+    Ok(())
+}
+
+/**
+ * 处理编辑消息 - 仅作者可编辑，新内容同样经过长度、限流和内容过滤
+ */
+async fn handle_edit_message(
+    state: &Arc<AppState>,
+    user_address: &str,
+    room: &str,
+    message_id: &str,
+    new_text: &str,
+) -> Result<()> {
+    validate_outgoing_text(state, user_address, new_text).await?;
+    let new_text = state.content_filter.filter(new_text)?;
+    
+    if state.is_muted(room, user_address).await? {
+        return Err(AppError::AuthorizationFailed("You are muted in this room".to_string()));
+    }
+    
+    let edited_at = state.edit_message(room, message_id, user_address, &new_text).await?;
+    
+    let edited_msg = ServerMessage::MessageEdited {
+        room: room.to_string(),
+        message_id: message_id.to_string(),
+        new_text,
+        edited_at,
+    };
+    state.broadcast_to_room(room, edited_msg).await;
+    
+    info!("Message {} in room {} edited by {}", message_id, room, user_address);
+    
+    Ok(())
+}
+
+/**
+ * 处理发送附件：只接受https和ipfs链接，与文本消息一样写入房间历史
+ */
+async fn handle_send_attachment(
+    state: &Arc<AppState>,
+    user_address: &str,
+    room: &str,
+    url: &str,
+    mime_hint: Option<String>,
+) -> Result<()> {
+    if state.get_runtime_config().await.maintenance_mode {
+        return Err(AppError::InvalidRequest("Server is in maintenance mode".to_string()));
+    }
+    
+    validate_attachment_url(url)?;
+    
+    let client = state.get_client(user_address).await
+        .ok_or_else(|| AppError::AuthenticationFailed("Client not found".to_string()))?;
+    
+    if !client.current_rooms.contains(room) {
+        return Err(AppError::AuthorizationFailed("User not in room".to_string()));
+    }
+    
+    if state.is_muted(room, user_address).await? {
+        return Err(AppError::AuthorizationFailed("You are muted in this room".to_string()));
+    }
+    
+    let allowed = rate_limit::check_rate_limit(
+        &state.redis_pool,
+        "send_attachment",
+        user_address,
+        state.config.attachment_rate_limit_per_minute,
+        ATTACHMENT_RATE_LIMIT_WINDOW_MS,
+    ).await?;
+    if !allowed {
+        return Err(AppError::TooManyRequests("Attachment rate limit exceeded".to_string()));
+    }
+    
+    state.check_slow_mode(room, user_address).await?;
+    
+    // 开启服务端校验时以实际的Content-Type为准，否则使用客户端的声明
+    let mime = if state.config.attachment_verify_content_type && url.to_ascii_lowercase().starts_with("https://") {
+        Some(fetch_attachment_type(url).await?)
+    } else {
+        mime_hint
+            .map(|mime| mime.trim().to_ascii_lowercase())
+            .filter(|mime| is_valid_mime(mime))
+    };
+    
+    let message = ServerMessage::Attachment {
+        id: uuid::Uuid::new_v4().to_string(),
+        from: state.config.display_name(user_address, client.ens_name.as_deref()),
+        from_address: user_address.to_string(),
+        room: room.to_string(),
+        url: url.to_string(),
+        mime,
+        timestamp: chrono::Utc::now(),
+    };
+    
+    state.broadcast_chat_message(room, user_address, message).await;
+    telemetry::record_message_sent(room);
+    
+    Ok(())
+}
+
+/**
+ * 校验附件URL：只允许https和ipfs协议，拒绝javascript:、data:等可能导致XSS的地址
+ */
+fn validate_attachment_url(url: &str) -> Result<()> {
+    if url.len() > MAX_ATTACHMENT_URL_LENGTH {
+        return Err(AppError::InvalidRequest(format!(
+            "Attachment URL too long (max {} characters)",
+            MAX_ATTACHMENT_URL_LENGTH
+        )));
+    }
+    
+    if url.chars().any(|c| c.is_whitespace() || c.is_control() || matches!(c, '"' | '\'' | '<' | '>')) {
+        return Err(AppError::InvalidRequest("Attachment URL contains invalid characters".to_string()));
+    }
+    
+    let lower = url.to_ascii_lowercase();
+    match lower.strip_prefix("https://").or_else(|| lower.strip_prefix("ipfs://")) {
+        Some(rest) if !rest.is_empty() => Ok(()),
+        _ => Err(AppError::InvalidRequest("Attachment URL must use https:// or ipfs://".to_string())),
+    }
+}
+
+/**
+ * 判断内容类型是否为允许的附件类型
+ */
+fn is_valid_mime(mime: &str) -> bool {
+    let mime = mime.trim().to_ascii_lowercase();
+    ALLOWED_ATTACHMENT_TYPES.iter().any(|prefix| mime.starts_with(prefix))
+        && mime.len() <= 100
+        && mime.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '+' | '-' | '.'))
+}
+
+/**
+ * 请求附件地址并读取Content-Type，不是允许的附件类型时拒绝
+ * 只请求解析到公网地址的主机，且不跟随重定向，避免被用来访问内网和云元数据服务
+ */
+async fn fetch_attachment_type(url: &str) -> Result<String> {
+    let parsed = reqwest::Url::parse(url)
+        .map_err(|e| AppError::InvalidRequest(format!("Invalid attachment URL: {}", e)))?;
+    let host = parsed.host_str()
+        .ok_or_else(|| AppError::InvalidRequest("Attachment URL has no host".to_string()))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = parsed.port_or_known_default().unwrap_or(443);
+    
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), port)).await
+        .map_err(|e| AppError::InvalidRequest(format!("Failed to resolve attachment host: {}", e)))?
+        .collect();
+    if addrs.is_empty() || !addrs.iter().all(|addr| is_public_ip(addr.ip())) {
+        return Err(AppError::InvalidRequest("Attachment host is not a public address".to_string()));
+    }
+    
+    // 固定使用已检查过的地址，防止请求时重新解析到内网地址
+    let client = reqwest::Client::builder()
+        .timeout(ATTACHMENT_FETCH_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve(&host, addrs[0])
+        .build()
+        .map_err(|e| AppError::InternalError(e.to_string()))?;
+    
+    let response = client.head(parsed).send().await
+        .map_err(|e| AppError::InvalidRequest(format!("Failed to fetch attachment: {}", e)))?;
+    
+    let mime = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    
+    if !is_valid_mime(&mime) {
+        return Err(AppError::InvalidRequest(format!("Unsupported attachment type: {}", mime)));
+    }
+    
+    Ok(mime)
+}
+
+/**
+ * 判断地址是否为公网地址，排除回环、私有、链路本地、运营商NAT等地址
+ */
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let octets = v4.octets();
+            !(v4.is_loopback()
+                || v4.is_private()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                || v4.is_documentation()
+                || v4.is_multicast()
+                || octets[0] == 0
+                || (octets[0] == 100 && (octets[1] & 0xc0) == 64))
+        }
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(v4));
+            }
+            let first = v6.segments()[0];
+            !(v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/**
+ * 处理登出：吊销客户端提供的JWT和本连接认证时使用的JWT，并移除该地址的所有连接
+ * 离开所有房间并广播离开消息，同一地址的其他连接随客户端条目的移除而关闭
+ */
+async fn handle_logout(
+    state: &Arc<AppState>,
+    user_address: &str,
+    token: Option<&str>,
+    session_claims: Option<&Claims>,
+) -> Result<()> {
+    if let Some(token) = token {
+        let user = extract_user_from_token(token, &state.config.jwt_secret)?;
+        if !user.address.eq_ignore_ascii_case(user_address) {
+            return Err(AppError::AuthorizationFailed("Token belongs to another address".to_string()));
+        }
+        state.auth_service.revoke_jwt(token).await?;
+    }
+    
+    // 没有jti的旧token无法吊销，不影响登出
+    if let Some(claims) = session_claims.filter(|claims| !claims.jti.is_empty()) {
+        state.auth_service.revoke_claims(claims).await?;
+    }
+    
+    state.evict_client(user_address).await;
+    info!("User {} logged out", user_address);
+    
+    Ok(())
+}
+
+/**
+ * 处理历史消息翻页请求，结果只发送给请求者
+ */
+async fn handle_fetch_history(
+    state: &Arc<AppState>,
+    user_address: &str,
+    room: &str,
+    before_id: Option<&str>,
+    limit: usize,
+    reply: &ReplySender,
+) -> Result<()> {
+    if !state.is_room_member(user_address, room).await {
+        return Err(AppError::AuthorizationFailed("User not in room".to_string()));
+    }
+    
+    // 条数上限由get_room_messages统一限制
+    let limit = if limit == 0 { state.config.history_limit } else { limit };
+    let messages = state
+        .get_room_messages(room, limit, before_id)
+        .await
+        .ok_or_else(|| AppError::NotFound(format!("Room not found: {}", room)))?;
+    
+    let _ = reply.send(ServerMessage::History {
+        room: room.to_string(),
+        messages,
+        topic: None,
+    });
+    
+    Ok(())
+}
+
+/**
+ * 处理踢出用户 - 仅房间管理员可操作
+ */
+async fn handle_kick(
+    state: &Arc<AppState>,
+    user_address: &str,
+    room: &str,
+    target: &str,
+) -> Result<()> {
+    let target_address = authorize_moderation(state, user_address, room, target).await?;
+    
+    let target_client = state.get_client(&target_address).await
+        .filter(|client| client.current_rooms.contains(room))
+        .ok_or_else(|| AppError::InvalidRequest("Target is not in the room".to_string()))?;
+    
+    state.leave_room(&target_address, room).await;
+    
+    let _ = target_client.sender.send(ServerMessage::Kicked {
+        room: room.to_string(),
+        by: user_address.to_string(),
+    });
+    
+    state.broadcast_user_left(room, &target_address).await;
+    
+    state.broadcast_system_notice(
+        room,
+        format!(
+            "{} was kicked by {}",
+            state.config.display_name(&target_address, target_client.ens_name.as_deref()),
+            user_address
+        ),
+    ).await;
+    
+    info!("User {} kicked from room {} by {}", target_address, room, user_address);
+    audit::log_event(
+        &state.redis_pool,
+        "kick",
+        user_address,
+        "success",
+        serde_json::json!({ "room": room, "target": target_address }),
+    ).await;
+    
+    Ok(())
+}
+
+/**
+ * 处理禁言用户 - 仅房间管理员可操作
+ */
+async fn handle_mute(
+    state: &Arc<AppState>,
+    user_address: &str,
+    room: &str,
+    target: &str,
+    duration_secs: u64,
+) -> Result<()> {
+    if duration_secs == 0 || duration_secs > MAX_MUTE_SECS {
+        return Err(AppError::InvalidRequest(format!(
+            "Mute duration must be between 1 and {} seconds",
+            MAX_MUTE_SECS
+        )));
+    }
+    
+    let target_address = authorize_moderation(state, user_address, room, target).await?;
+    
+    state.mute_user(room, &target_address, duration_secs).await?;
+    
+    state.broadcast_system_notice(
+        room,
+        format!("{} was muted for {} seconds by {}", target_address, duration_secs, user_address),
+    ).await;
+    
+    info!("User {} muted in room {} for {}s by {}", target_address, room, duration_secs, user_address);
+    audit::log_event(
+        &state.redis_pool,
+        "mute",
+        user_address,
+        "success",
+        serde_json::json!({ "room": room, "target": target_address, "duration_secs": duration_secs }),
+    ).await;
+    
+    Ok(())
+}
+
+/**
+ * 处理屏蔽/取消屏蔽用户，被屏蔽地址的房间消息不再发送给当前用户
+ */
+async fn handle_set_blocked(
+    state: &Arc<AppState>,
+    user_address: &str,
+    target: &str,
+    blocked: bool,
+    reply: &ReplySender,
+) -> Result<()> {
+    let target_address = Address::from_str(target)
+        .map(|addr| to_checksum(&addr, None))
+        .map_err(|e| AppError::InvalidRequest(format!("Invalid address: {}", e)))?;
+    
+    if target_address.eq_ignore_ascii_case(user_address) {
+        return Err(AppError::InvalidRequest("Cannot block yourself".to_string()));
+    }
+    
+    let list = state.set_blocked(user_address, &target_address, blocked).await?;
+    
+    let _ = reply.send(ServerMessage::BlockListUpdated { blocked: list });
+    
+    info!("User {} {} {}", user_address, if blocked { "blocked" } else { "unblocked" }, target_address);
+    
+    Ok(())
+}
+
+/**
+ * 处理设置慢速模式 - 仅房间管理员可操作，secs为0时关闭
+ */
+async fn handle_set_slow_mode(
+    state: &Arc<AppState>,
+    user_address: &str,
+    room: &str,
+    secs: u64,
+) -> Result<()> {
+    if secs > MAX_SLOW_MODE_SECS {
+        return Err(AppError::InvalidRequest(format!(
+            "Slow mode interval must be at most {} seconds",
+            MAX_SLOW_MODE_SECS
+        )));
+    }
+    
+    if !state.is_room_moderator(room, user_address).await {
+        return Err(AppError::AuthorizationFailed("Moderator privileges required".to_string()));
+    }
+    
+    state.set_slow_mode(room, secs).await?;
+    
+    let notice = if secs == 0 {
+        format!("Slow mode disabled by {}", user_address)
+    } else {
+        format!("Slow mode enabled by {}: one message every {} seconds", user_address, secs)
+    };
+    state.broadcast_system_notice(room, notice).await;
+    
+    info!("Slow mode in room {} set to {}s by {}", room, secs, user_address);
+    audit::log_event(
+        &state.redis_pool,
+        "slow_mode",
+        user_address,
+        "success",
+        serde_json::json!({ "room": room, "secs": secs }),
+    ).await;
+    
+    Ok(())
+}
+
+/**
+ * 处理设置房间话题 - 仅房间管理员可操作，空话题表示清除
+ */
+async fn handle_set_topic(
+    state: &Arc<AppState>,
+    user_address: &str,
+    room: &str,
+    topic: &str,
+) -> Result<()> {
+    let topic = topic.trim();
+    if topic.chars().count() > MAX_TOPIC_LENGTH {
+        return Err(AppError::InvalidRequest(format!(
+            "Topic must be at most {} characters",
+            MAX_TOPIC_LENGTH
+        )));
+    }
+    
+    if !state.is_room_moderator(room, user_address).await {
+        return Err(AppError::AuthorizationFailed("Moderator privileges required".to_string()));
+    }
+    
+    let topic = Some(topic.to_string()).filter(|topic| !topic.is_empty());
+    state.set_topic(room, topic.clone()).await?;
+    
+    state.broadcast_to_room(room, ServerMessage::TopicChanged {
+        room: room.to_string(),
+        topic,
+        by: user_address.to_string(),
+    }).await;
+    
+    info!("Topic of room {} changed by {}", room, user_address);
+    audit::log_event(
+        &state.redis_pool,
+        "set_topic",
+        user_address,
+        "success",
+        serde_json::json!({ "room": room }),
+    ).await;
+    
+    Ok(())
+}
+
+/**
+ * 校验管理操作权限，返回目标用户的校验和地址
+ * 管理员不能对自己或其他管理员执行操作
+ */
+async fn authorize_moderation(
+    state: &Arc<AppState>,
+    user_address: &str,
+    room: &str,
+    target: &str,
+) -> Result<String> {
+    if !state.is_room_moderator(room, user_address).await {
+        return Err(AppError::AuthorizationFailed("Moderator privileges required".to_string()));
+    }
+    
+    let target_address = Address::from_str(target)
+        .map(|addr| to_checksum(&addr, None))
+        .map_err(|e| AppError::InvalidRequest(format!("Invalid target address: {}", e)))?;
+    
+    if target_address.eq_ignore_ascii_case(user_address) {
+        return Err(AppError::InvalidRequest("Cannot moderate yourself".to_string()));
+    }
+    
+    if state.is_room_moderator(room, &target_address).await {
+        return Err(AppError::AuthorizationFailed("Cannot moderate another moderator".to_string()));
+    }
+    
+    Ok(target_address)
+}
+
+/**
+ * 校验表情：非空、长度较短且不包含普通文字或空白
+ */
+fn is_valid_emoji(emoji: &str) -> bool {
+    let char_count = emoji.chars().count();
+    char_count > 0
+        && char_count <= 8
+        && !emoji.chars().any(|c| c.is_ascii_alphanumeric() || c.is_whitespace() || c.is_control())
+}
+
+/**
+ * 校验待发送的文本消息：维护模式、内容长度及发送频率
+ */
+async fn validate_outgoing_text(
+    state: &Arc<AppState>,
+    user_address: &str,
+    text: &str,
+) -> Result<()> {
+    let runtime_config = state.get_runtime_config().await;
+    
+    // 维护模式下禁止发送消息
+    if runtime_config.maintenance_mode {
+        return Err(AppError::InvalidRequest("Server is in maintenance mode".to_string()));
+    }
+    
+    // 输入验证
+    if text.trim().is_empty() {
+        return Err(AppError::InvalidRequest("Message cannot be empty".to_string()));
+    }
+    
+    // 按用户感知的字符（字素簇）计算长度，emoji和中文不会因多字节编码被提前截断
+    let max_length = state.config.max_message_length;
+    if text.graphemes(true).count() > max_length {
+        return Err(AppError::InvalidRequest(format!(
+            "Message too long (max {} characters)",
+            max_length
+        )));
+    }
+    
+    // 按用户地址限流，计数保存在Redis中以便多实例共享
+    let allowed = rate_limit::check_rate_limit(
+        &state.redis_pool,
+        "send_text",
+        user_address,
+        runtime_config.rate_limit_messages,
+        runtime_config.rate_limit_window_ms,
+    ).await?;
+    
+    if !allowed {
+        return Err(AppError::InvalidRequest("Rate limit exceeded".to_string()));
+    }
+    
+    Ok(())
+}
+
+/**
+ * 解析消息中的@提及，支持 @0x地址 和 @name.eth 两种形式
+ */
+fn parse_mentions(text: &str) -> Vec<String> {
+    static MENTION_REGEX: OnceLock<Regex> = OnceLock::new();
+    let regex = MENTION_REGEX.get_or_init(|| {
+        Regex::new(r"@(0x[0-9a-fA-F]{40}|[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.eth)\b").unwrap()
+    });
+    
+    let mut mentions: Vec<String> = regex
+        .captures_iter(text)
+        .map(|caps| caps[1].to_string())
+        .collect();
+    mentions.sort();
+    mentions.dedup();
+    mentions
+}
+
+/**
+ * 处理加入房间
+ */
+async fn handle_join_room(
+    state: &Arc<AppState>,
+    user_address: &str,
+    room: &str,
+) -> Result<()> {
+    // 检查房间的Token门禁
+    state.check_room_token_gate(user_address, room).await?;
+    
+    // 已在房间中时只补发历史消息，不重复广播加入
+    if state.is_room_member(user_address, room).await {
+        state.send_room_history(user_address, room).await;
+        return Ok(());
+    }
+    
+    let success = state.join_room(user_address, room).await;
+    
+    if success {
+        // 先向新用户发送历史消息，再广播加入消息
+        state.send_room_history(user_address, room).await;
+        
+        // 广播用户加入消息及在线用户列表（新用户同样会收到）
+        state.broadcast_user_joined(room, user_address).await;
+    } else {
+        if !state.is_default_room(room) {
+            if let Some(client) = state.get_client(user_address).await {
+                if state.room_limit_reached(&client) {
+                    return Err(AppError::BadRequest(format!(
+                        "Room limit reached: you can be in at most {} rooms",
+                        state.config.max_rooms_per_user
+                    )));
+                }
+            }
+        }
+        
+        let rooms = state.rooms.read().await;
+        match rooms.get(room) {
+            // 未开启隐式建房且房间不存在
+            None => return Err(AppError::InvalidRequest(format!("Room not found: {}", room))),
+            Some(r) if r.is_full(state.config.default_room_max_users) => {
+                return Err(AppError::RoomFull(room.to_string()));
+            }
+            Some(_) => {}
+        }
+    }
+    
+    Ok(())
+}
+
+/**
+ * 处理离开房间
+ */
+async fn handle_leave_room(
+    state: &Arc<AppState>,
+    user_address: &str,
+    room: &str,
+) -> Result<()> {
+    if state.get_client(user_address).await.is_none() {
+        return Err(AppError::AuthenticationFailed("Client not found".to_string()));
+    }
+    
+    // 不在房间中的用户离开时不广播，避免出现虚假的离开通知
+    if !state.leave_room(user_address, room).await {
+        return Ok(());
+    }
+    
+    // 广播用户离开消息及在线用户列表
+    state.broadcast_user_left(room, user_address).await;
+    
+    Ok(())
+}
+
+/**
+ * 发送消息到WebSocket
+ */
+async fn send_message(
     sender: &mut futures_util::stream::SplitSink<WebSocket, Message>,
     message: &ServerMessage,
 ) -> Result<()> {
@@ -486,4 +1731,686 @@ async fn send_message(
         .map_err(|e| AppError::WebSocketError(e.to_string()))?;
     
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{RoomConfig, TokenGate, TokenGateType, UserAuth};
+    use axum::response::IntoResponse;
+    use crate::test_support::{serve_rpc_result, TestHarness};
+    
+    #[tokio::test]
+    async fn only_moderators_can_set_topic() {
+        let harness = TestHarness::start().await;
+        let state = harness.app_state();
+        let moderator = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        let member = "0xFFcf8FDEE72ac11b5c542428B35EEF5769C409f0";
+        
+        state.register_room(RoomConfig {
+            name: "trading".to_string(),
+            description: None,
+            token_gate: None,
+            max_users: None,
+            max_history: None,
+            slow_mode_secs: None,
+            created_at: chrono::Utc::now(),
+            created_by: moderator.to_string(),
+        }).await;
+        for address in [moderator, member] {
+            state.add_client(address.to_string(), None).await.unwrap();
+            assert!(state.join_room(address, "trading").await);
+        }
+        let mut receiver = state.get_client(member).await.unwrap().sender.subscribe();
+        
+        let denied = handle_set_topic(&state, member, "trading", "wen moon").await;
+        assert!(matches!(denied, Err(AppError::AuthorizationFailed(_))));
+        assert_eq!(state.get_room_topic("trading").await, None);
+        
+        handle_set_topic(&state, moderator, "trading", "  ETH/USDC flow  ").await.unwrap();
+        assert_eq!(state.get_room_topic("trading").await.as_deref(), Some("ETH/USDC flow"));
+        
+        match receiver.recv().await.unwrap() {
+            ServerMessage::TopicChanged { room, topic, by } => {
+                assert_eq!(room, "trading");
+                assert_eq!(topic.as_deref(), Some("ETH/USDC flow"));
+                assert_eq!(by, moderator);
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn invalid_upgrade_token_is_rejected() {
+        let harness = TestHarness::start().await;
+        let state = harness.app_state();
+        
+        let rejected = authenticate_upgrade(&state, &HeaderMap::new(), Some("not-a-jwt")).await;
+        match rejected {
+            Err(e) => assert_eq!(e.into_response().status(), axum::http::StatusCode::UNAUTHORIZED),
+            Ok(_) => panic!("invalid query token was accepted"),
+        }
+        
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer not-a-jwt".parse().unwrap());
+        assert!(matches!(
+            authenticate_upgrade(&state, &headers, None).await,
+            Err(AppError::AuthenticationFailed(_))
+        ));
+        
+        // 没有token时不拒绝，连接建立后使用消息内认证
+        assert!(authenticate_upgrade(&state, &HeaderMap::new(), None).await.unwrap().is_none());
+    }
+    
+    #[test]
+    fn attachment_urls_must_be_https_or_ipfs() {
+        assert!(validate_attachment_url("javascript:alert(1)").is_err());
+        assert!(validate_attachment_url("data:image/png;base64,iVBORw0KGgo=").is_err());
+        assert!(validate_attachment_url("http://example.com/cat.png").is_err());
+        assert!(validate_attachment_url("https://example.com/cat.png").is_ok());
+        assert!(validate_attachment_url("ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi").is_ok());
+    }
+    
+    #[test]
+    fn internal_addresses_are_not_public() {
+        for ip in ["127.0.0.1", "10.0.0.1", "192.168.1.1", "172.16.0.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "::1", "fe80::1", "fd00::1", "::ffff:127.0.0.1"] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{} should not be public", ip);
+        }
+        assert!(is_public_ip("93.184.216.34".parse().unwrap()));
+        assert!(is_public_ip("2606:2800:220:1::1".parse().unwrap()));
+    }
+    
+    #[tokio::test]
+    async fn attachment_fetch_refuses_internal_hosts() {
+        for url in ["https://127.0.0.1/cat.png", "https://169.254.169.254/latest/meta-data", "https://[::1]/cat.png"] {
+            let error = fetch_attachment_type(url).await.unwrap_err();
+            assert!(error.to_string().contains("not a public address"), "{}: {}", url, error);
+        }
+    }
+    
+    #[tokio::test]
+    async fn message_length_counts_graphemes_up_to_configured_limit() {
+        let mut harness = TestHarness::start().await;
+        harness.config.max_message_length = 1000;
+        let state = harness.app_state();
+        
+        // 1000个emoji（4000字节）不超过1000个字符的上限，多码点的家庭emoji同样算一个字符
+        validate_outgoing_text(&state, "0x0000000000000000000000000000000000000001", &"👍".repeat(1000)).await.unwrap();
+        validate_outgoing_text(&state, "0x0000000000000000000000000000000000000002", &"👨‍👩‍👧".repeat(1000)).await.unwrap();
+        assert!(matches!(
+            validate_outgoing_text(&state, "0x0000000000000000000000000000000000000003", &"👍".repeat(1001)).await,
+            Err(AppError::InvalidRequest(_))
+        ));
+        
+        // 上限从配置读取
+        harness.config.max_message_length = 5;
+        let state = harness.app_state();
+        validate_outgoing_text(&state, "0x0000000000000000000000000000000000000004", "hello").await.unwrap();
+        assert!(validate_outgoing_text(&state, "0x0000000000000000000000000000000000000005", "hello!").await.is_err());
+    }
+    
+    #[test]
+    fn only_known_subprotocols_are_supported() {
+        // 没有声明子协议的旧客户端仍可连接
+        assert!(protocol_supported(None));
+        assert!(protocol_supported(Some("chaintalk.v1")));
+        assert!(protocol_supported(Some("chaintalk.v2, chaintalk.v1")));
+        assert!(!protocol_supported(Some("chaintalk.v0")));
+        assert!(!protocol_supported(Some("")));
+    }
+    
+    #[test]
+    fn unknown_message_type_lists_valid_types() {
+        let error = unknown_message_type(r#"{"type":"Teleport","room":"general"}"#).expect("unknown type should be reported");
+        let message = error.to_string();
+        assert!(message.contains("Unknown message type: Teleport"), "{}", message);
+        assert!(message.contains("SendText") && message.contains("JoinRoom"), "{}", message);
+        
+        // 已知类型的字段错误和非JSON输入仍按解析错误处理
+        assert!(unknown_message_type(r#"{"type":"SendText"}"#).is_none());
+        assert!(unknown_message_type("not json").is_none());
+    }
+    
+    #[test]
+    fn mentions_of_addresses_and_ens_names_are_parsed() {
+        let text = "gm @vitalik.eth and @0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1, ping @vitalik.eth again";
+        assert_eq!(
+            parse_mentions(text),
+            vec!["0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1".to_string(), "vitalik.eth".to_string()]
+        );
+        
+        // 邮箱、过短的地址和非.eth名称不算提及
+        assert!(parse_mentions("mail me at bob@example.com or @0x1234 or @alice").is_empty());
+    }
+    
+    #[tokio::test]
+    async fn mention_notifies_only_mentioned_room_members() {
+        let harness = TestHarness::start().await;
+        let state = harness.app_state();
+        let room = state.config.default_room.clone();
+        let author = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        let mentioned = "0xFFcf8FDEE72ac11b5c542428B35EEF5769C409f0";
+        let bystander = "0x22d491Bde2303f2f43325b2108D26f1eAbA1e32b";
+        let outsider = "0xE11BA2b4D45Eaed5996Cd0823791E0C93114882d";
+        
+        for address in [author, mentioned, bystander, outsider] {
+            state.add_client(address.to_string(), None).await.unwrap();
+        }
+        for address in [author, mentioned, bystander] {
+            assert!(state.join_room(address, &room).await);
+        }
+        let mut receivers: Vec<_> = Vec::new();
+        for address in [mentioned, bystander, outsider] {
+            receivers.push(state.get_client(address).await.unwrap().sender.subscribe());
+        }
+        
+        let mentions = parse_mentions(&format!("hey @{} and @{}", mentioned, outsider));
+        state.notify_mentions(&mentions, &room, "message-1", "alice", author).await;
+        
+        match receivers[0].try_recv().unwrap() {
+            ServerMessage::Mention { room: mention_room, message_id, from } => {
+                assert_eq!(mention_room, room);
+                assert_eq!(message_id, "message-1");
+                assert_eq!(from, "alice");
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+        // 未被提及的成员和不在房间中的用户都收不到通知
+        assert!(receivers[1].try_recv().is_err());
+        assert!(receivers[2].try_recv().is_err());
+    }
+    
+    #[tokio::test]
+    async fn message_rejected_by_slow_mode_can_be_resent_after_cooldown() {
+        let mut harness = TestHarness::start().await;
+        harness.config.duplicate_message_window_ms = 60_000;
+        let state = harness.app_state();
+        let room = state.config.default_room.clone();
+        let address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        
+        state.add_client(address.to_string(), None).await.unwrap();
+        assert!(state.join_room(address, &room).await);
+        state.set_slow_mode(&room, 1).await.unwrap();
+        let (reply, _replies) = mpsc::unbounded_channel();
+        
+        handle_send_text(&state, address, &room, "gm", None, None, &reply).await.unwrap();
+        match handle_send_text(&state, address, &room, "wagmi", None, None, &reply).await {
+            Err(AppError::InvalidRequest(message)) => assert!(message.starts_with("Slow mode is enabled"), "{}", message),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        
+        // 被慢速模式拒绝的消息没有记入去重窗口
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        handle_send_text(&state, address, &room, "wagmi", None, None, &reply).await.unwrap();
+    }
+    
+    #[test]
+    fn idle_check_runs_at_half_the_timeout_within_bounds() {
+        assert_eq!(idle_check_period(Duration::from_secs(120)), Duration::from_secs(30));
+        assert_eq!(idle_check_period(Duration::from_secs(20)), Duration::from_secs(10));
+        assert_eq!(idle_check_period(Duration::from_secs(1)), Duration::from_secs(1));
+        // 超时为0表示关闭空闲检测，周期仍需为正数
+        assert_eq!(idle_check_period(Duration::ZERO), Duration::from_secs(1));
+    }
+    
+    #[tokio::test]
+    async fn leaving_a_room_without_membership_is_not_broadcast() {
+        let harness = TestHarness::start().await;
+        let state = harness.app_state();
+        let room = state.config.default_room.clone();
+        let address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        let witness = "0xFFcf8FDEE72ac11b5c542428B35EEF5769C409f0";
+        
+        for address in [address, witness] {
+            state.add_client(address.to_string(), None).await.unwrap();
+        }
+        assert!(state.join_room(witness, &room).await);
+        let mut receiver = state.get_client(witness).await.unwrap().sender.subscribe();
+        
+        handle_leave_room(&state, address, &room).await.unwrap();
+        assert!(receiver.try_recv().is_err());
+        
+        // 真正离开时才通知房间
+        assert!(state.join_room(address, &room).await);
+        handle_leave_room(&state, address, &room).await.unwrap();
+        assert!(matches!(receiver.try_recv().unwrap(), ServerMessage::UserLeft { .. }));
+    }
+    
+    #[tokio::test]
+    async fn duplicate_auth_on_authenticated_socket_keeps_connection() {
+        let harness = TestHarness::start().await;
+        let state = harness.app_state();
+        let address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        
+        state.add_client(address.to_string(), Some("alice.eth".to_string())).await.unwrap();
+        let mut receiver = state.get_client(address).await.unwrap().sender.subscribe();
+        let mut user_address = Some(address.to_string());
+        let mut authenticated = true;
+        let mut client_receiver = None;
+        let (reply, mut replies) = mpsc::unbounded_channel();
+        
+        // 凭证无效也不会重新校验，直接回送当前身份
+        let keep_open = handle_client_message(
+            r#"{"type":"TokenAuth","payload":{"token":"not-a-jwt"}}"#,
+            &state,
+            &mut user_address,
+            &mut authenticated,
+            &mut client_receiver,
+            &reply,
+            &mut None,
+        )
+        .await
+        .unwrap();
+        
+        assert!(keep_open);
+        assert!(authenticated);
+        assert_eq!(user_address.as_deref(), Some(address));
+        match replies.try_recv().unwrap() {
+            ServerMessage::AuthSuccess { user_address, ens_name, .. } => {
+                assert_eq!(user_address, address);
+                assert_eq!(ens_name.as_deref(), Some("alice.eth"));
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+        // 回复只发给当前连接，同一地址的其他连接收不到
+        assert!(receiver.try_recv().is_err());
+    }
+    
+    #[tokio::test]
+    async fn logout_revokes_session_and_closes_every_connection() {
+        let harness = TestHarness::start().await;
+        let state = harness.app_state();
+        let room = state.config.default_room.clone();
+        let address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        let witness = "0xFFcf8FDEE72ac11b5c542428B35EEF5769C409f0";
+        
+        for address in [address, witness] {
+            state.add_client(address.to_string(), None).await.unwrap();
+            assert!(state.join_room(address, &room).await);
+        }
+        // 同一地址的第二个连接
+        state.add_client(address.to_string(), None).await.unwrap();
+        let mut sibling = state.get_client(address).await.unwrap().sender.subscribe();
+        let mut receiver = state.get_client(witness).await.unwrap().sender.subscribe();
+        
+        // 连接通过JWT认证，登出时没有再提供token
+        let token = state.auth_service.generate_jwt(&UserAuth {
+            address: address.to_string(),
+            ens_name: None,
+            token_holdings: Default::default(),
+            nft_holdings: Vec::new(),
+        }).unwrap();
+        let mut session_claims = Some(state.auth_service.verify_jwt(&token).await.unwrap());
+        let mut user_address = Some(address.to_string());
+        let mut authenticated = true;
+        let mut client_receiver = None;
+        let (reply, _replies) = mpsc::unbounded_channel();
+        
+        let keep_open = handle_client_message(
+            r#"{"type":"Logout","payload":{}}"#,
+            &state,
+            &mut user_address,
+            &mut authenticated,
+            &mut client_receiver,
+            &reply,
+            &mut session_claims,
+        )
+        .await
+        .unwrap();
+        
+        assert!(!keep_open);
+        assert!(!authenticated);
+        assert!(user_address.is_none());
+        assert!(session_claims.is_none());
+        assert!(state.auth_service.verify_jwt(&token).await.is_err());
+        
+        // 所有连接都被移除，其他连接的通道随之关闭
+        assert!(state.get_client(address).await.is_none());
+        assert!(matches!(sibling.try_recv(), Err(broadcast::error::TryRecvError::Closed)));
+        match receiver.try_recv().unwrap() {
+            ServerMessage::UserLeft { user, room: left_room, .. } => {
+                assert_eq!(user, address);
+                assert_eq!(left_room, room);
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn replies_go_only_to_the_requesting_connection() {
+        let harness = TestHarness::start().await;
+        let state = harness.app_state();
+        let room = state.config.default_room.clone();
+        let address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        
+        // 同一地址的两个连接
+        for _ in 0..2 {
+            state.add_client(address.to_string(), None).await.unwrap();
+        }
+        assert!(state.join_room(address, &room).await);
+        let mut shared = state.get_client(address).await.unwrap().sender.subscribe();
+        let (reply, mut replies) = mpsc::unbounded_channel();
+        let mut user_address = Some(address.to_string());
+        let mut authenticated = true;
+        let mut client_receiver = None;
+        
+        for request in [r#"{"type":"Ping"}"#, r#"{"type":"FetchHistory","payload":{"room":"general"}}"#] {
+            let request = request.replace("general", &room);
+            assert!(handle_client_message(&request, &state, &mut user_address, &mut authenticated, &mut client_receiver, &reply, &mut None)
+                .await
+                .unwrap());
+        }
+        
+        assert!(matches!(replies.try_recv().unwrap(), ServerMessage::Pong));
+        assert!(matches!(replies.try_recv().unwrap(), ServerMessage::History { .. }));
+        assert!(shared.try_recv().is_err());
+    }
+    
+    #[tokio::test]
+    async fn join_broadcasts_exactly_one_online_users_list() {
+        let harness = TestHarness::start().await;
+        let state = harness.app_state();
+        let witness = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        let newcomer = "0xFFcf8FDEE72ac11b5c542428B35EEF5769C409f0";
+        
+        state.add_client(witness.to_string(), None).await.unwrap();
+        state.add_client(newcomer.to_string(), Some("newcomer.eth".to_string())).await.unwrap();
+        assert!(state.join_room(witness, "general").await);
+        let mut receiver = state.get_client(witness).await.unwrap().sender.subscribe();
+        
+        handle_join_room(&state, newcomer, "general").await.unwrap();
+        
+        let mut online_lists = Vec::new();
+        while let Ok(message) = receiver.try_recv() {
+            if let ServerMessage::OnlineUsers { users, .. } = message {
+                online_lists.push(users);
+            }
+        }
+        assert_eq!(online_lists.len(), 1);
+        let users = &online_lists[0];
+        assert_eq!(users.len(), 2);
+        assert!(users.iter().any(|user| user.address == newcomer && user.ens_name.as_deref() == Some("newcomer.eth")));
+        
+        // 重复加入不再广播
+        handle_join_room(&state, newcomer, "general").await.unwrap();
+        assert!(receiver.try_recv().is_err());
+    }
+    
+    #[tokio::test]
+    async fn only_moderators_and_admins_can_kick_or_mute() {
+        let mut harness = TestHarness::start().await;
+        let admin = "0x22d491Bde2303f2f43325b2108D26f1eAbA1e32b";
+        harness.config.admin_addresses = vec![admin.to_lowercase()];
+        let state = harness.app_state();
+        let moderator = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        let member = "0xFFcf8FDEE72ac11b5c542428B35EEF5769C409f0";
+        let target = "0xE11BA2b4D45Eaed5996Cd0823791E0C93114882d";
+        
+        state.register_room(RoomConfig {
+            name: "trading".to_string(),
+            description: None,
+            token_gate: None,
+            max_users: None,
+            max_history: None,
+            slow_mode_secs: None,
+            created_at: chrono::Utc::now(),
+            created_by: moderator.to_string(),
+        }).await;
+        for address in [moderator, member, target, admin] {
+            state.add_client(address.to_string(), None).await.unwrap();
+            assert!(state.join_room(address, "trading").await);
+        }
+        let mut receiver = state.get_client(target).await.unwrap().sender.subscribe();
+        
+        assert!(matches!(
+            handle_kick(&state, member, "trading", target).await,
+            Err(AppError::AuthorizationFailed(_))
+        ));
+        assert!(matches!(
+            handle_mute(&state, member, "trading", target, 60).await,
+            Err(AppError::AuthorizationFailed(_))
+        ));
+        assert!(!state.is_muted("trading", target).await.unwrap());
+        assert!(state.is_room_member(target, "trading").await);
+        
+        // 管理员不是房间创建者也可以禁言
+        handle_mute(&state, admin, "trading", target, 60).await.unwrap();
+        assert!(state.is_muted("trading", target).await.unwrap());
+        
+        handle_kick(&state, moderator, "trading", target).await.unwrap();
+        assert!(!state.is_room_member(target, "trading").await);
+        let kicked = std::iter::from_fn(|| receiver.try_recv().ok())
+            .any(|message| matches!(message, ServerMessage::Kicked { ref by, .. } if by == moderator));
+        assert!(kicked);
+    }
+    
+    #[tokio::test]
+    async fn token_gated_join_allows_holders_and_denies_others() {
+        let mut harness = TestHarness::start().await;
+        // 节点对balanceOf返回500
+        let balance = ethers::abi::encode(&[ethers::abi::Token::Uint(ethers::types::U256::from(500))]);
+        harness.config.ethereum_http_url = serve_rpc_result(balance.into()).await;
+        let state = harness.app_state();
+        let address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        
+        for (room, minimum_balance) in [("holders", "100"), ("whales", "1000")] {
+            state.register_room(RoomConfig {
+                name: room.to_string(),
+                description: None,
+                token_gate: Some(TokenGate {
+                    gate_type: TokenGateType::ERC20,
+                    contract_address: "0x6B175474E89094C44Da98b954EedeAC495271d0F".to_string(),
+                    minimum_balance: Some(minimum_balance.to_string()),
+                    token_ids: None,
+                    chain_id: MAINNET_CHAIN_ID,
+                }),
+                max_users: None,
+                max_history: None,
+                slow_mode_secs: None,
+                created_at: chrono::Utc::now(),
+                created_by: address.to_string(),
+            }).await;
+        }
+        state.add_client(address.to_string(), None).await.unwrap();
+        
+        handle_join_room(&state, address, "holders").await.unwrap();
+        assert!(state.is_room_member(address, "holders").await);
+        // 通过的检查结果被缓存
+        let mut conn = harness.redis_pool.get().await.unwrap();
+        let cached: bool = conn.exists(format!("gate:holders:{}", address.to_lowercase())).await.unwrap();
+        assert!(cached);
+        
+        assert!(matches!(
+            handle_join_room(&state, address, "whales").await,
+            Err(AppError::TokenGateFailed(_))
+        ));
+        assert!(!state.is_room_member(address, "whales").await);
+    }
+    
+    #[tokio::test]
+    async fn ack_carries_the_broadcast_message_id() {
+        let harness = TestHarness::start().await;
+        let state = harness.app_state();
+        let room = state.config.default_room.clone();
+        let address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        
+        state.add_client(address.to_string(), None).await.unwrap();
+        assert!(state.join_room(address, &room).await);
+        let mut receiver = state.get_client(address).await.unwrap().sender.subscribe();
+        let (reply, mut replies) = mpsc::unbounded_channel();
+        
+        let request = serde_json::json!({
+            "type": "SendText",
+            "payload": { "room": room, "text": "gm", "client_msg_id": "local-1" },
+        });
+        assert!(handle_client_message(&request.to_string(), &state, &mut Some(address.to_string()), &mut true, &mut None, &reply, &mut None)
+            .await
+            .unwrap());
+        
+        let ack = tokio::time::timeout(Duration::from_secs(5), replies.recv()).await.unwrap().unwrap();
+        let broadcast_id = match receiver.recv().await.unwrap() {
+            ServerMessage::NewText { id, .. } => id,
+            other => panic!("unexpected message: {:?}", other),
+        };
+        match ack {
+            ServerMessage::Ack { client_msg_id, server_id, .. } => {
+                assert_eq!(client_msg_id, "local-1");
+                assert_eq!(server_id, broadcast_id);
+            }
+            other => panic!("unexpected reply: {:?}", other),
+        }
+    }
+    
+    #[test]
+    fn authentication_errors_become_auth_failed_frames() {
+        for error in [
+            AppError::AuthenticationFailed("expired".to_string()),
+            AppError::InvalidSignature,
+            AppError::InvalidNonce,
+        ] {
+            let frame = serde_json::to_value(error_message(&error)).unwrap();
+            assert_eq!(frame["type"], "AuthFailed");
+        }
+        
+        let frame = serde_json::to_value(error_message(&AppError::InvalidRequest("bad room".to_string()))).unwrap();
+        assert_eq!(frame["type"], "Error");
+    }
+    
+    #[tokio::test]
+    async fn edits_update_history_and_reach_the_room() {
+        let harness = TestHarness::start().await;
+        let state = harness.app_state();
+        let room = state.config.default_room.clone();
+        let author = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        let witness = "0xFFcf8FDEE72ac11b5c542428B35EEF5769C409f0";
+        
+        for address in [author, witness] {
+            state.add_client(address.to_string(), None).await.unwrap();
+            assert!(state.join_room(address, &room).await);
+        }
+        let message = ServerMessage::new_text(author.to_string(), author.to_string(), "gm".to_string(), room.clone(), None);
+        let message_id = message.chat_message_id().unwrap().to_string();
+        state.broadcast_chat_message(&room, author, message).await;
+        let mut receiver = state.get_client(witness).await.unwrap().sender.subscribe();
+        
+        assert!(matches!(
+            handle_edit_message(&state, witness, &room, &message_id, "gn").await,
+            Err(AppError::AuthorizationFailed(_))
+        ));
+        handle_edit_message(&state, author, &room, &message_id, "gm frens").await.unwrap();
+        
+        match receiver.try_recv().unwrap() {
+            ServerMessage::MessageEdited { message_id: edited_id, new_text, .. } => {
+                assert_eq!(edited_id, message_id);
+                assert_eq!(new_text, "gm frens");
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+        assert!(receiver.try_recv().is_err());
+        
+        match state.room_history(&room).await {
+            Some(ServerMessage::History { messages, .. }) => match messages.last() {
+                Some(ServerMessage::NewText { text, edited_at, .. }) => {
+                    assert_eq!(text, "gm frens");
+                    assert!(edited_at.is_some());
+                }
+                other => panic!("unexpected history entry: {:?}", other),
+            },
+            other => panic!("unexpected history: {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn authenticated_users_land_in_the_configured_default_room() {
+        let mut harness = TestHarness::start().await;
+        harness.config.default_room = "lobby".to_string();
+        let state = harness.app_state();
+        let address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        let token = state.auth_service.generate_jwt(&UserAuth {
+            address: address.to_string(),
+            ens_name: None,
+            token_holdings: Default::default(),
+            nft_holdings: Vec::new(),
+        }).unwrap();
+        let (reply, mut replies) = mpsc::unbounded_channel();
+        let mut user_address = None;
+        let mut authenticated = false;
+        
+        let request = serde_json::json!({ "type": "TokenAuth", "payload": { "token": token } });
+        assert!(handle_client_message(&request.to_string(), &state, &mut user_address, &mut authenticated, &mut None, &reply, &mut None)
+            .await
+            .unwrap());
+        
+        assert!(authenticated);
+        let client = state.get_client(address).await.unwrap();
+        assert_eq!(client.current_rooms.into_iter().collect::<Vec<_>>(), vec!["lobby".to_string()]);
+        assert!(matches!(replies.try_recv().unwrap(), ServerMessage::AuthSuccess { .. }));
+        assert!(matches!(replies.try_recv().unwrap(), ServerMessage::History { room, .. } if room == "lobby"));
+    }
+    
+    #[tokio::test]
+    async fn new_text_carries_display_name_and_author_address() {
+        let harness = TestHarness::start().await;
+        let state = harness.app_state();
+        let room = state.config.default_room.clone();
+        let named = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        let unnamed = "0xFFcf8FDEE72ac11b5c542428B35EEF5769C409f0";
+        
+        state.add_client(named.to_string(), Some("alice.eth".to_string())).await.unwrap();
+        state.add_client(unnamed.to_string(), None).await.unwrap();
+        for address in [named, unnamed] {
+            assert!(state.join_room(address, &room).await);
+        }
+        let mut receiver = state.get_client(named).await.unwrap().sender.subscribe();
+        let (reply, _replies) = mpsc::unbounded_channel();
+        
+        for (address, expected_from) in [
+            (named, "alice.eth".to_string()),
+            (unnamed, state.config.display_name(unnamed, None)),
+        ] {
+            let request = serde_json::json!({ "type": "SendText", "payload": { "room": room, "text": format!("gm from {}", address) } });
+            handle_client_message(&request.to_string(), &state, &mut Some(address.to_string()), &mut true, &mut None, &reply, &mut None)
+                .await
+                .unwrap();
+            
+            let message = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+            match message {
+                ServerMessage::NewText { from, from_address, .. } => {
+                    assert_eq!(from, expected_from);
+                    assert_eq!(from_address, address);
+                }
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+    }
+    
+    #[tokio::test]
+    async fn replies_to_missing_messages_are_sent_without_reference() {
+        let harness = TestHarness::start().await;
+        let state = harness.app_state();
+        let room = state.config.default_room.clone();
+        let address = "0x90F8bf6A479f320ead074411a4B0e7944Ea8c9C1";
+        
+        state.add_client(address.to_string(), None).await.unwrap();
+        assert!(state.join_room(address, &room).await);
+        let original = ServerMessage::new_text(address.to_string(), address.to_string(), "gm".to_string(), room.clone(), None);
+        let original_id = original.chat_message_id().unwrap().to_string();
+        state.broadcast_chat_message(&room, address, original).await;
+        let mut receiver = state.get_client(address).await.unwrap().sender.subscribe();
+        let (reply, _replies) = mpsc::unbounded_channel();
+        
+        for (reply_to, expected) in [("missing-id", None), (original_id.as_str(), Some(original_id.clone()))] {
+            let request = serde_json::json!({
+                "type": "SendText",
+                "payload": { "room": room, "text": format!("re: {}", reply_to), "reply_to": reply_to },
+            });
+            handle_client_message(&request.to_string(), &state, &mut Some(address.to_string()), &mut true, &mut None, &reply, &mut None)
+                .await
+                .unwrap();
+            
+            match tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap() {
+                ServerMessage::NewText { reply_to, .. } => assert_eq!(reply_to, expected),
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+    }
+}